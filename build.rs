@@ -0,0 +1,18 @@
+use std::process::Command;
+
+// Embed build information, to be reported by "/_api/version".
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=QUANWEB_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=QUANWEB_BUILD_TIME={}", chrono::Utc::now().to_rfc3339());
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=build.rs");
+}
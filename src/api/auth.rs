@@ -1,31 +1,48 @@
 
 use axum::http::StatusCode;
-use axum::{debug_handler, Json, response::Result as AxumResult};
+use axum::{debug_handler, Extension, Json, response::{IntoResponse, Response, Result as AxumResult}};
 use axum::extract::State;
 use axum_extra::extract::WithRejection;
+use axum_login::axum_sessions::SessionHandle;
 use axum_login::RequireAuthorizationLayer;
+use chrono::Utc;
 use djangohashers::check_password;
 use garde::Validate;
-use serde_json::Value;
+use serde_json::{json, Value};
+use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 use edgedb_tokio::Client;
 
-use crate::auth::structs::LoginReqData;
-use crate::models::{User, Role};
+use crate::auth::lockout::{LockoutStatus, LoginLockout};
+use crate::auth::passwords::{hash_password, needs_rehash, ResetTokens};
+use crate::auth::structs::{
+    LoginReqData, PasswordResetConfirmData, PasswordResetRequestData, PendingLogin, TwoFactorLoginData,
+};
+use crate::auth::totp;
+use crate::mail::{Mail, MailSender};
+use crate::models::{TwoFactorState, User, Role};
+use crate::monitoring::record_login;
 use crate::stores;
-use crate::types::ApiErrorShape;
-use super::errors::ApiError;
+use crate::types::{ApiErrorShape, AppState};
+use super::errors::{field_error, ApiError};
 use crate::auth::Auth;
 
 #[allow(dead_code)]
 pub type RequireAuth = RequireAuthorizationLayer<Uuid, User, Role>;
 
-#[debug_handler]
+const PENDING_LOGIN_KEY: &str = "pending_2fa_login";
+// Time to type the code after the password is accepted.
+const PENDING_LOGIN_SECONDS: i64 = 300;
+
+// If the user has 2FA enabled, the password only starts the login, which is finished by `login_two_factor`.
+#[debug_handler(state = AppState)]
 pub async fn login(
     mut auth: Auth,
     State(db): State<Client>,
+    State(lockout): State<LoginLockout>,
+    Extension(session): Extension<SessionHandle>,
     WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
-) -> AxumResult<Json<User>> {
+) -> AxumResult<Response> {
     let valid_data: LoginReqData = serde_json::from_value(value).map_err(ApiError::JsonExtractionError)?;
     valid_data.validate(&()).map_err(ApiError::ValidationError)?;
     tracing::info!("Validated request data: {:?}", valid_data);
@@ -34,25 +51,255 @@ pub async fn login(
         .map_err(ApiError::EdgeDBQueryError)?
         .ok_or_else(|| {
             tracing::info!("User not found");
+            record_login(false);
             let resp: ApiErrorShape = "User not found".to_string().into();
             (StatusCode::UNAUTHORIZED, Json(resp))
         })?;
-    let passwd_check = check_password(&valid_data.password.expose_secret(), &user.password)
+    // Don't even check the password of a locked account, to stop guessing.
+    let status = lockout.status(user.id).await;
+    if status.locked {
+        record_login(false);
+        return Err(locked_error(&status).into());
+    }
+    let passwd_check = check_password(valid_data.password.expose_secret(), &user.password)
         .map_err(|e| {
             tracing::error!("Error checking password: {:?}", e);
+            record_login(false);
             ApiError::LoginError("Wrong password".into())
         })?;
     tracing::info!("Password check: {:?}", passwd_check);
-    passwd_check.then_some(()).ok_or(ApiError::LoginError("Wrong password".into()))?;
+    if !passwd_check {
+        record_login(false);
+        let status = lockout.record_failure(user.id).await;
+        if status.locked {
+            return Err(locked_error(&status).into());
+        }
+        return Err(ApiError::LoginError("Wrong password".into()).into());
+    }
+    // Checked after the password, to not reveal account state to whoever guesses emails.
+    if !user.is_active {
+        tracing::info!("User {} is inactive", user.username);
+        record_login(false);
+        return Err(ApiError::InactiveAccount.into());
+    }
+    let user = upgrade_password_hash(user, valid_data.password.expose_secret(), &db).await;
+    let two_factor = stores::user::get_two_factor(user.id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .unwrap_or_default();
+    if two_factor.enabled {
+        // The failure counter is kept until the second step passes, so it still limits code guessing.
+        let pending = PendingLogin {
+            user_id: user.id,
+            expires_at: Utc::now().timestamp() + PENDING_LOGIN_SECONDS,
+        };
+        session.write().await.insert(PENDING_LOGIN_KEY, pending).map_err(|e| {
+            tracing::error!("Cannot save pending login: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        tracing::info!("User {} needs second factor to login", user.username);
+        let resp = json!({"two_factor_required": true});
+        return Ok((StatusCode::ACCEPTED, Json(resp)).into_response());
+    }
+    if status.failed_attempts > 0 {
+        lockout.reset(user.id).await.unwrap_or_else(|e| tracing::error!("Cannot reset login failures: {e}"));
+    }
+    complete_login(&mut auth, user).await
+}
+
+#[debug_handler(state = AppState)]
+pub async fn login_two_factor(
+    mut auth: Auth,
+    State(db): State<Client>,
+    State(lockout): State<LoginLockout>,
+    Extension(session): Extension<SessionHandle>,
+    WithRejection(Json(data), _): WithRejection<Json<TwoFactorLoginData>, ApiError>,
+) -> AxumResult<Response> {
+    let pending = session.read().await.get::<PendingLogin>(PENDING_LOGIN_KEY);
+    let Some(pending) = pending.filter(|p| p.expires_at > Utc::now().timestamp()) else {
+        return Err(ApiError::LoginError("No pending login, please enter your password again".into()).into());
+    };
+    let status = lockout.status(pending.user_id).await;
+    if status.locked {
+        session.write().await.remove(PENDING_LOGIN_KEY);
+        record_login(false);
+        return Err(locked_error(&status).into());
+    }
+    let user = stores::user::get_user(pending.user_id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .filter(|u| u.is_active)
+        .ok_or(ApiError::InactiveAccount)?;
+    let two_factor = stores::user::get_two_factor(user.id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .unwrap_or_default();
+    if !check_second_factor(user.id, &two_factor, &data, &db).await? {
+        record_login(false);
+        let status = lockout.record_failure(user.id).await;
+        if status.locked {
+            session.write().await.remove(PENDING_LOGIN_KEY);
+            return Err(locked_error(&status).into());
+        }
+        return Err(ApiError::LoginError("Invalid authentication code".into()).into());
+    }
+    session.write().await.remove(PENDING_LOGIN_KEY);
+    if status.failed_attempts > 0 {
+        lockout.reset(user.id).await.unwrap_or_else(|e| tracing::error!("Cannot reset login failures: {e}"));
+    }
+    complete_login(&mut auth, user).await
+}
+
+// Both the TOTP code and recovery code can only be used once.
+async fn check_second_factor(user_id: Uuid, state: &TwoFactorState, data: &TwoFactorLoginData, db: &Client) -> Result<bool, ApiError> {
+    if !state.enabled {
+        return Ok(false);
+    }
+    match (&data.code, &data.recovery_code) {
+        (Some(code), _) => {
+            let secret = state.secret.as_deref().unwrap_or_default();
+            let now = Utc::now().timestamp() as u64;
+            let Some(step) = totp::verify_code(secret, code, now, state.last_step) else {
+                return Ok(false);
+            };
+            Ok(stores::user::mark_totp_step_used(user_id, step as i64, db).await?)
+        }
+        (None, Some(recovery_code)) => {
+            let hash = totp::hash_recovery_code(recovery_code.expose_secret());
+            let used = stores::user::use_recovery_code(user_id, &hash, db).await?;
+            if used {
+                tracing::info!("User {user_id} logged in with recovery code, {} left", state.recovery_codes.len().saturating_sub(1));
+            }
+            Ok(used)
+        }
+        (None, None) => Err(ApiError::NotEnoughData),
+    }
+}
+
+// Hashes from the old Django site, or with outdated Argon2 parameters, are replaced now that we know the password.
+async fn upgrade_password_hash(user: User, password: &str, db: &Client) -> User {
+    if !needs_rehash(&user.password) {
+        return user;
+    }
+    let password_hash = hash_password(password);
+    match stores::user::set_password_by_id(user.id, &password_hash, db).await {
+        Ok(Some(updated)) => {
+            tracing::info!("Upgraded password hash of user {}", updated.username);
+            updated
+        }
+        Ok(None) => user,
+        Err(e) => {
+            tracing::error!("Cannot upgrade password hash of user {}: {e}", user.username);
+            user
+        }
+    }
+}
+
+async fn complete_login(auth: &mut Auth, user: User) -> AxumResult<Response> {
     tracing::info!("Logging in user: {:?}", user);
     auth.login(&user).await.map_err(|e| {
         tracing::error!("Error logging in user: {}", e);
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
-    Ok(Json(user))
+    record_login(true);
+    Ok(Json(user).into_response())
 }
 
+fn locked_error(status: &LockoutStatus) -> ApiError {
+    let retry_after = status.retry_after.unwrap_or_default();
+    ApiError::TooManyRequests {
+        message: format!(
+            "Too many failed login attempts, try again in {} minute(s)",
+            retry_after.div_ceil(60).max(1)
+        ),
+        retry_after,
+    }
+}
 
 pub async fn logout(mut auth: Auth) {
     auth.logout().await;
 }
+
+// The answer is the same whether the email is registered or not, and the mail is sent in background,
+// so that neither the response nor its timing tells.
+#[debug_handler(state = AppState)]
+pub async fn request_password_reset(
+    State(db): State<Client>,
+    State(mailer): State<Option<MailSender>>,
+    State(reset_tokens): State<Option<ResetTokens>>,
+    State(tasks): State<TaskTracker>,
+    WithRejection(Json(data), _): WithRejection<Json<PasswordResetRequestData>, ApiError>,
+) -> AxumResult<StatusCode> {
+    let (Some(mailer), Some(reset_tokens)) = (mailer, reset_tokens) else {
+        return Err(reset_disabled().into());
+    };
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    tasks.spawn(async move {
+        send_reset_mail(&data.email, &db, &mailer, &reset_tokens).await;
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn reset_disabled() -> ApiError {
+    ApiError::Unavailable("Password reset is not enabled on this site".into())
+}
+
+async fn send_reset_mail(email: &str, db: &Client, mailer: &MailSender, reset_tokens: &ResetTokens) {
+    let user = match stores::user::get_user_by_email(email, db).await {
+        Ok(Some(user)) if user.is_active => user,
+        Ok(_) => {
+            tracing::info!("No active user with email {email} to reset password");
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Cannot look up user to reset password: {e}");
+            return;
+        }
+    };
+    let token = reset_tokens.issue(user.id, &user.password);
+    let body = format!(
+        "Hi {},\n\nSomeone, hopefully you, asked to reset your password. Open this link to choose a new one:\n\n{}\n\n\
+        The link expires in {} minutes. If you didn't ask for it, you can ignore this mail.\n",
+        user.username,
+        reset_tokens.reset_url(&token),
+        reset_tokens.lifetime_minutes(),
+    );
+    let mail = Mail {
+        to: user.email,
+        subject: "Reset your password".into(),
+        body,
+    };
+    if let Err(e) = mailer.send(&mail).await {
+        tracing::error!("Cannot send password reset mail: {e}");
+    }
+}
+
+#[debug_handler(state = AppState)]
+pub async fn confirm_password_reset(
+    State(db): State<Client>,
+    State(reset_tokens): State<Option<ResetTokens>>,
+    State(lockout): State<LoginLockout>,
+    WithRejection(Json(data), _): WithRejection<Json<PasswordResetConfirmData>, ApiError>,
+) -> AxumResult<StatusCode> {
+    let reset_tokens = reset_tokens.ok_or_else(reset_disabled)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    let invalid = || field_error("token", "Invalid or expired reset link");
+    let user_id = ResetTokens::user_id(&data.token).ok_or_else(invalid)?;
+    let user = stores::user::get_user(user_id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .filter(|u| u.is_active)
+        .ok_or_else(invalid)?;
+    if !reset_tokens.verify(&data.token, &user.password) {
+        return Err(invalid().into());
+    }
+    let password_hash = hash_password(data.new_password.expose_secret());
+    stores::user::set_password_by_id(user.id, &password_hash, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    // Whoever has the mail box can now log in anyway.
+    lockout.reset(user.id).await.unwrap_or_else(|e| tracing::error!("Cannot reset login failures: {e}"));
+    tracing::info!("User {} reset password", user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
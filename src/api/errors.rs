@@ -2,7 +2,10 @@ use std::collections::HashMap;
 
 use axum::extract::rejection::{JsonRejection, PathRejection};
 use axum::http::StatusCode;
+use axum::http::header::RETRY_AFTER;
 use axum::{response::IntoResponse, Json};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use thiserror::Error;
 use edgedb_errors::display::display_error_verbose;
 use edgedb_errors::kinds as EdErrKind;
@@ -24,10 +27,22 @@ pub enum ApiError {
     ObjectNotFound(String),
     #[error("Please login")]
     Unauthorized,
+    #[error("You don't have permission to do this")]
+    Forbidden,
+    #[error("Cross-site request is refused: {0}")]
+    CsrfRejected(String),
     #[error("Error logging in")]
     LoginError(String),
+    #[error("This account is disabled")]
+    InactiveAccount,
+    #[error("{message}")]
+    TooManyRequests { message: String, retry_after: u64 },
     #[error("Not enough data")]
     NotEnoughData,
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unavailable(String),
     #[error(transparent)]
     ValidationError(#[from] garde::Errors),
     #[error("Other error: {0}")]
@@ -57,6 +72,15 @@ impl IntoResponse for ApiError {
                 tracing::error!("EdgeDB error: {}", display_error_verbose(e));
                 if e.is::<EdErrKind::ConstraintViolationError>() {
                     let detail = e.details().unwrap_or_default();
+                    let message = e.initial_message().unwrap_or_default();
+                    // Like validation errors, so that the form can show it next to the field.
+                    if let Some(field) = constraint_field(message, detail) {
+                        let resp = ApiErrorShape {
+                            fields: Some(HashMap::from([(field, constraint_message(message))])),
+                            ..detail.to_string().into()
+                        };
+                        return (StatusCode::UNPROCESSABLE_ENTITY, Json(resp)).into_response();
+                    }
                     (StatusCode::UNPROCESSABLE_ENTITY, detail.to_string())
                 } else {
                     (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
@@ -64,8 +88,33 @@ impl IntoResponse for ApiError {
             }
             Self::ObjectNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
             Self::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
+            Self::Forbidden => (StatusCode::FORBIDDEN, self.to_string()),
+            Self::CsrfRejected(_) => {
+                let resp = ApiErrorShape {
+                    code: Some("csrf_rejected".into()),
+                    ..self.to_string().into()
+                };
+                return (StatusCode::FORBIDDEN, Json(resp)).into_response();
+            }
             Self::LoginError(e) => (StatusCode::UNAUTHORIZED, e.to_string()),
+            Self::InactiveAccount => {
+                let resp = ApiErrorShape {
+                    code: Some("inactive_account".into()),
+                    ..self.to_string().into()
+                };
+                return (StatusCode::FORBIDDEN, Json(resp)).into_response();
+            }
+            Self::TooManyRequests { message, retry_after } => {
+                let resp = ApiErrorShape {
+                    code: Some("too_many_requests".into()),
+                    ..message.into()
+                };
+                let headers = [(RETRY_AFTER, retry_after.to_string())];
+                return (StatusCode::TOO_MANY_REQUESTS, headers, Json(resp)).into_response();
+            }
             Self::NotEnoughData => (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()),
+            Self::Conflict(message) => (StatusCode::CONFLICT, message),
+            Self::Unavailable(message) => (StatusCode::SERVICE_UNAVAILABLE, message),
             Self::ValidationError(e) => {
                 let resp: ApiErrorShape = flatten_garde_errors(e).into();
                 return (StatusCode::UNPROCESSABLE_ENTITY, Json(resp)).into_response();
@@ -77,6 +126,25 @@ impl IntoResponse for ApiError {
     }
 }
 
+// EdgeDB tells the property in details, like "property 'email' of object type 'default::User' violates
+// exclusivity constraint", or only in message, like "email violates exclusivity constraint".
+pub fn constraint_field(message: &str, detail: &str) -> Option<String> {
+    static PROPERTY: Lazy<Regex> = Lazy::new(|| Regex::new(r"property '(\w+)'").unwrap());
+    static SUBJECT: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(\w+) (violates|must|is)\b").unwrap());
+    PROPERTY
+        .captures(detail)
+        .or_else(|| SUBJECT.captures(message))
+        .map(|c| c[1].to_string())
+}
+
+fn constraint_message(message: &str) -> String {
+    if message.contains("exclusivity") {
+        "Already taken".to_string()
+    } else {
+        message.to_string()
+    }
+}
+
 pub fn flatten_garde_errors(errors: garde::Errors) -> HashMap<String, String> {
     errors
         .flatten()
@@ -84,3 +152,9 @@ pub fn flatten_garde_errors(errors: garde::Errors) -> HashMap<String, String> {
         .map(|(k, v)| (k.trim_start_matches("value.").into(), v.message.to_string()))
         .collect()
 }
+
+// Error of one field, shaped like validation errors.
+pub fn field_error(field: &str, message: &str) -> (StatusCode, Json<ApiErrorShape>) {
+    let resp: ApiErrorShape = HashMap::from([(field.to_string(), message.to_string())]).into();
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(resp))
+}
@@ -1,5 +1,6 @@
 pub mod structs;
 pub mod posts;
+pub mod users;
 pub mod views;
 pub mod auth;
 pub mod paging;
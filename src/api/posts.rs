@@ -6,18 +6,24 @@ use axum::{http::StatusCode, response::Result as AxumResult, Json};
 use axum_extra::extract::{Query, WithRejection};
 use edgedb_tokio::Client as EdgeClient;
 use garde::Validate;
+use serde::Deserialize;
 use serde_json::{Map as JMap, Value};
+use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
-use crate::auth::Auth;
+use crate::auth::CurrentUser;
 use super::errors::ApiError;
 use super::paging::gen_pagination_links;
 use super::structs::{BlogPostCreateData, BlogPostPatchData, ObjectListResponse, Paging};
 use crate::consts::DEFAULT_PAGE_SIZE;
-use crate::models::{DetailedBlogPost, MinimalObject, MediumBlogPost};
+use crate::models::{DetailedBlogPost, DocFormat, MinimalObject, MediumBlogPost, Permission};
+use crate::rerender::{self, RerenderProgress, RerenderTracker};
 use crate::stores;
+use crate::utils::markdown::{render_post_blocking, RenderedPost};
 
+// Drafts are only listed for users who can view them.
 pub async fn list_posts(
+    user: Option<CurrentUser>,
     paging: Query<Paging>,
     OriginalUri(original_uri): OriginalUri,
     State(db): State<EdgeClient>,
@@ -25,14 +31,18 @@ pub async fn list_posts(
     tracing::info!("Paging: {:?}", paging);
     let page = max(1, paging.0.page.unwrap_or(1));
     let per_page = max(0, paging.0.per_page.unwrap_or(DEFAULT_PAGE_SIZE)) as u16;
-    let offset: i64 = ((page - 1) * per_page).try_into().unwrap_or(0);
+    let offset: i64 = ((page - 1) * per_page).into();
     let limit = per_page as i64;
-    let posts = stores::blog::get_blogposts(Some(offset), Some(limit), &db)
-        .await
-        .map_err(ApiError::EdgeDBQueryError)?;
-    let count = stores::blog::get_all_posts_count(&db)
+    let published_only = !can_view_drafts(user.as_ref());
+    let posts = stores::blog::get_blogposts(Some(offset), Some(limit), published_only, &db)
         .await
         .map_err(ApiError::EdgeDBQueryError)?;
+    let count = if published_only {
+        stores::blog::count_all_published_posts(&db).await
+    } else {
+        stores::blog::get_all_posts_count(&db).await
+    }
+    .map_err(ApiError::EdgeDBQueryError)?;
     let total_pages =
         NonZeroU16::new((count as f64 / per_page as f64).ceil() as u16).unwrap_or(NonZeroU16::MIN);
     let links = gen_pagination_links(&paging.0, count, original_uri);
@@ -46,23 +56,49 @@ pub async fn list_posts(
 }
 
 pub async fn get_post(
+    user: Option<CurrentUser>,
     WithRejection(Path(post_id), _): WithRejection<Path<Uuid>, ApiError>,
     State(db): State<EdgeClient>,
 ) -> AxumResult<Json<DetailedBlogPost>> {
-    let post = stores::blog::get_post(post_id, &db)
+    let published_only = !can_view_drafts(user.as_ref());
+    let post = stores::blog::get_post(post_id, published_only, &db)
         .await
         .map_err(ApiError::EdgeDBQueryError)?
         .ok_or(ApiError::ObjectNotFound("BlogPost".into()))?;
     Ok(Json(post))
 }
 
+fn can_view_drafts(user: Option<&CurrentUser>) -> bool {
+    user.is_some_and(|u| u.can(Permission::ViewDrafts))
+}
+
+// Editors can change any post, authors only their own.
+async fn check_post_editable(user: &CurrentUser, post_id: Uuid, db: &EdgeClient) -> Result<(), ApiError> {
+    let owner = stores::blog::get_post_owner(post_id, db)
+        .await?
+        .ok_or(ApiError::ObjectNotFound("BlogPost".into()))?;
+    if !user.can_edit_post(owner.author_id) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(())
+}
+
+// Submitted body is rendered as Markdown, off the async runtime, because diagram programs may be run.
+async fn render_body(body: Option<String>) -> Result<Option<RenderedPost>, ApiError> {
+    let Some(body) = body else {
+        return Ok(None);
+    };
+    render_post_blocking(body, DocFormat::Md)
+        .await
+        .map_err(|e| ApiError::Other(format!("Failed to render post: {e}")))
+}
+
 pub async fn delete_post(
     Path(post_id): Path<Uuid>,
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
 ) -> AxumResult<StatusCode> {
-    tracing::info!("Current user: {:?}", auth.current_user);
-    auth.current_user.ok_or(StatusCode::FORBIDDEN)?;
+    check_post_editable(&user, post_id, &db).await?;
     let q = "DELETE BlogPost FILTER .id = <uuid>$0";
     tracing::debug!("To query: {}", q);
     let _deleted_post: MinimalObject = db
@@ -75,17 +111,17 @@ pub async fn delete_post(
 
 pub async fn update_post_partial(
     WithRejection(Path(post_id), _): WithRejection<Path<Uuid>, ApiError>,
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
     WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
 ) -> AxumResult<Json<DetailedBlogPost>> {
-    auth.current_user.ok_or(StatusCode::FORBIDDEN)?;
+    check_post_editable(&user, post_id, &db).await?;
     // Collect list of submitted fields
     let jdata: JMap<String, Value> =
         serde_json::from_value(value.clone()).map_err(ApiError::JsonExtractionError)?;
     // User submit no field to update
     if jdata.is_empty() {
-        let post = stores::blog::get_post(post_id, &db)
+        let post = stores::blog::get_post(post_id, false, &db)
             .await
             .map_err(ApiError::EdgeDBQueryError)?;
         let post = post.ok_or(ApiError::ObjectNotFound("BlogPost".into()))?;
@@ -96,7 +132,8 @@ pub async fn update_post_partial(
         serde_json::from_value(value).map_err(ApiError::JsonExtractionError)?;
     let submitted_fields: Vec<&String> = jdata.keys().collect();
     let set_clause = patch_data.gen_set_clause(&submitted_fields);
-    let args = patch_data.make_edgedb_object(post_id, &submitted_fields);
+    let rendered = render_body(patch_data.body.clone()).await?;
+    let args = patch_data.make_edgedb_object(post_id, &submitted_fields, rendered);
     let q = format!(
         "SELECT (
             UPDATE BlogPost
@@ -120,6 +157,8 @@ pub async fn update_post_partial(
             html,
             seo_description,
             og_image,
+            toc := .toc ?? <json>[],
+            show_toc := .show_toc ?? true,
         }}"
     );
     tracing::debug!("To query: {}", q);
@@ -133,11 +172,11 @@ pub async fn update_post_partial(
 }
 
 pub async fn create_post(
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
     WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
 ) -> AxumResult<(StatusCode, Json<DetailedBlogPost>)> {
-    auth.current_user.ok_or(StatusCode::FORBIDDEN)?;
+    let user = user.require(Permission::CreatePost)?;
     // Collect list of submitted fields
     let jdata: JMap<String, Value> =
         serde_json::from_value(value.clone()).map_err(ApiError::JsonExtractionError)?;
@@ -146,13 +185,15 @@ pub async fn create_post(
         .then_some(())
         .ok_or(ApiError::NotEnoughData)?;
     // Check that data has valid fields
-    let post_data: BlogPostCreateData =
+    let mut post_data: BlogPostCreateData =
         serde_json::from_value(value).map_err(ApiError::JsonExtractionError)?;
+    post_data.author = Some(user.id);
     post_data.validate(&()).map_err(ApiError::ValidationError)?;
     tracing::debug!("Post data: {:?}", post_data);
     let submitted_fields: Vec<&String> = jdata.keys().collect();
     let set_clause = post_data.gen_set_clause(&submitted_fields);
-    let args = post_data.make_edgedb_object(&submitted_fields);
+    let rendered = render_body(post_data.body.clone()).await?;
+    let args = post_data.make_edgedb_object(&submitted_fields, rendered);
     let q = format!(
         "
     SELECT (
@@ -175,6 +216,8 @@ pub async fn create_post(
         html,
         seo_description,
         og_image,
+        toc := .toc ?? <json>[],
+        show_toc := .show_toc ?? true,
     }}"
     );
     tracing::debug!("To query: {}", q);
@@ -186,3 +229,38 @@ pub async fn create_post(
         .ok_or(ApiError::Other("Failed to create BlogPost".into()))?;
     Ok((StatusCode::CREATED, Json(created_post)))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct RerenderParams {
+    // Re-render all posts, not just the outdated ones.
+    #[serde(default)]
+    pub all: bool,
+}
+
+pub async fn start_rerender(
+    user: CurrentUser,
+    Query(params): Query<RerenderParams>,
+    State(db): State<EdgeClient>,
+    State(tasks): State<TaskTracker>,
+    State(tracker): State<RerenderTracker>,
+) -> AxumResult<(StatusCode, Json<RerenderProgress>)> {
+    user.require(Permission::RerenderPosts)?;
+    if !tracker.try_start() {
+        return Err(ApiError::Conflict("A re-render job is already running".into()).into());
+    }
+    let job_tracker = tracker.clone();
+    tasks.spawn(async move {
+        let progress = rerender::rerender_posts(&db, params.all, |p| job_tracker.update(p)).await;
+        job_tracker.update(&progress);
+    });
+    Ok((StatusCode::ACCEPTED, Json(tracker.get().unwrap_or_default())))
+}
+
+pub async fn get_rerender_progress(
+    user: CurrentUser,
+    State(tracker): State<RerenderTracker>,
+) -> AxumResult<Json<RerenderProgress>> {
+    user.require(Permission::RerenderPosts)?;
+    let progress = tracker.get().ok_or(ApiError::ObjectNotFound("Re-render job".into()))?;
+    Ok(Json(progress))
+}
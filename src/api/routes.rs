@@ -1,23 +1,45 @@
-use axum::routing::{get, post, Router};
+use axum::middleware;
+use axum::routing::Router;
 
 use super::auth;
+use super::users;
 use super::views;
+use crate::middlewares::http_metrics::track_http_metrics;
 use crate::types::AppState;
+use crate::utils::routing::{delete, get, post, RouteTable};
 
-pub fn get_router() -> Router<AppState> {
+pub fn route_table() -> RouteTable<AppState> {
     let single_post_router = get(views::get_post)
         .patch(views::update_post_partial)
         .delete(views::delete_post);
+    let single_user_router = get(users::get_user_info)
+        .patch(users::update_user_partial)
+        .delete(users::delete_user);
     let single_category_router = get(views::get_category)
         .patch(views::update_category_partial)
         .delete(views::delete_category);
 
-    Router::new()
+    RouteTable::new()
         .route("/", get(views::root))
+        .route("/version", get(views::version))
         .route("/login", post(auth::login))
+        .route("/login/2fa", post(auth::login_two_factor))
+        .route("/password-reset", post(auth::request_password_reset))
+        .route("/password-reset/confirm", post(auth::confirm_password_reset))
         .route("/logout", post(auth::logout))
         .route("/users/me", get(views::show_me))
+        .route("/users/me/password", post(users::change_password))
+        .route("/users/me/tokens", get(users::list_tokens).post(users::create_token))
+        .route("/users/me/tokens/:token_id", delete(users::revoke_token))
+        .route("/users/me/2fa", get(users::get_two_factor))
+        .route("/users/me/2fa/setup", post(users::setup_two_factor))
+        .route("/users/me/2fa/enable", post(users::enable_two_factor))
+        .route("/users/me/2fa/disable", post(users::disable_two_factor))
+        .route("/users/", get(users::list_users).post(users::create_user))
+        .route("/users/:user_id", single_user_router)
+        .route("/users/:user_id/lockout", get(users::get_lockout).delete(users::unlock))
         .route("/posts/", get(views::list_posts).post(views::create_post))
+        .route("/posts/rerender", get(views::get_rerender_progress).post(views::start_rerender))
         .route("/posts/:post_id", single_post_router)
         .route(
             "/categories/",
@@ -25,3 +47,9 @@ pub fn get_router() -> Router<AppState> {
         )
         .route("/categories/:category_id", single_category_router)
 }
+
+pub fn get_router() -> Router<AppState> {
+    route_table()
+        .into_router()
+        .route_layer(middleware::from_fn(track_http_metrics))
+}
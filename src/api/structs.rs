@@ -4,13 +4,18 @@ use edgedb_protocol::common::Cardinality as Cd;
 use edgedb_protocol::value::Value as EValue;
 use garde::Validate;
 use indexmap::indexmap;
+use redact::Secret;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map as JMap, Value as JValue};
 use uuid::Uuid;
 
 use super::macros::append_set_statement;
-use crate::models::DocFormat;
+use crate::models::{AccessToken, DocFormat, Permission, Role};
+use crate::utils::validation::{
+    has_some_chars, optional_email, optional_person_name, optional_username, valid_new_password, valid_token_lifetime,
+};
 use crate::types::conversions::{edge_object_from_pairs, edge_object_from_simple_pairs};
-use crate::utils::markdown::{make_excerpt, markdown_to_html};
+use crate::utils::markdown::{RenderedPost, RENDERER_VERSION};
 
 #[derive(Deserialize, Debug)]
 pub struct Paging {
@@ -53,10 +58,11 @@ pub struct BlogPostPatchData {
     pub locale: Option<String>,
     pub categories: Option<Vec<Uuid>>,
     pub og_image: Option<String>,
+    pub show_toc: Option<bool>,
 }
 
 impl BlogPostPatchData {
-    pub fn gen_set_clause<'a>(&self, submitted_fields: &Vec<&String>) -> String {
+    pub fn gen_set_clause(&self, submitted_fields: &Vec<&String>) -> String {
         let mut lines = Vec::<&str>::new();
         append_set_statement!("title", "optional str", lines, submitted_fields);
         append_set_statement!("slug", "optional str", lines, submitted_fields);
@@ -67,9 +73,12 @@ impl BlogPostPatchData {
             lines.push("body := <optional str>$body");
             lines.push("html := <optional str>$html");
             lines.push("excerpt := <optional str>$excerpt");
+            lines.push("toc := to_json(<optional str>$toc)");
+            lines.push("renderer_version := <int16>$renderer_version");
         }
         append_set_statement!("locale", "optional str", lines, submitted_fields);
         append_set_statement!("og_image", "optional str", lines, submitted_fields);
+        append_set_statement!("show_toc", "optional bool", lines, submitted_fields);
         if submitted_fields.iter().any(|&f| f == "categories") && self.categories.is_some() {
             let line = "categories := (
                 SELECT BlogCategory FILTER .id IN array_unpack(<array<uuid>>$categories)
@@ -79,7 +88,13 @@ impl BlogPostPatchData {
         lines.join(&format!(",\n{}", " ".repeat(8)))
     }
 
-    pub fn make_edgedb_object<'a>(&self, post_id: Uuid, submitted_fields: &Vec<&String>) -> EValue {
+    // "rendered" is from the submitted body, done beforehand because it is slow, see `render_post_blocking`.
+    pub fn make_edgedb_object(
+        &self,
+        post_id: Uuid,
+        submitted_fields: &Vec<&String>,
+        rendered: Option<RenderedPost>,
+    ) -> EValue {
         let mut pairs = indexmap! {
             "id" => (Some(EValue::Uuid(post_id)), Cd::One),
         };
@@ -112,14 +127,21 @@ impl BlogPostPatchData {
         }
         if submitted_fields.iter().any(|&f| f == "body") {
             let body = self.body.clone();
-            let html = body.as_ref().map(|b| markdown_to_html(b));
-            let excerpt = body.as_ref().map(|b| make_excerpt(b));
+            let (html, excerpt, toc) = match rendered {
+                Some(RenderedPost { html, excerpt, toc }) => (Some(html), Some(excerpt), serde_json::to_string(&toc).ok()),
+                None => (None, None, None),
+            };
             pairs.insert("body", (body.map(EValue::Str), Cd::AtMostOne));
             pairs.insert("html", (html.map(EValue::Str), Cd::AtMostOne));
             pairs.insert(
                 "excerpt",
                 (excerpt.map(EValue::Str), Cd::AtMostOne),
             );
+            pairs.insert("toc", (toc.map(EValue::Str), Cd::AtMostOne));
+            pairs.insert(
+                "renderer_version",
+                (Some(EValue::Int16(RENDERER_VERSION)), Cd::One),
+            );
         }
         if submitted_fields.iter().any(|&f| f == "locale") {
             pairs.insert(
@@ -133,6 +155,12 @@ impl BlogPostPatchData {
                 (self.og_image.clone().map(EValue::Str), Cd::AtMostOne),
             );
         }
+        if submitted_fields.iter().any(|&f| f == "show_toc") {
+            pairs.insert(
+                "show_toc",
+                (self.show_toc.map(EValue::Bool), Cd::AtMostOne),
+            );
+        }
         if let Some(categories) = &self.categories {
             let categories: Vec<EValue> = categories.iter().map(|&i| EValue::Uuid(i)).collect();
             pairs.insert(
@@ -162,10 +190,16 @@ pub struct BlogPostCreateData {
     pub categories: Option<Vec<Uuid>>,
     #[garde(skip)]
     pub og_image: Option<String>,
+    #[garde(skip)]
+    pub show_toc: Option<bool>,
+    // Set from the logged-in user, not from submitted data.
+    #[serde(skip)]
+    #[garde(skip)]
+    pub author: Option<Uuid>,
 }
 
 impl BlogPostCreateData {
-    pub fn gen_set_clause<'a>(&self, submitted_fields: &Vec<&String>) -> String {
+    pub fn gen_set_clause(&self, submitted_fields: &Vec<&String>) -> String {
         let mut lines = vec!["title := <str>$title", "slug := <str>$slug"];
         append_set_statement!("is_published", "optional bool", lines, submitted_fields);
         if submitted_fields.iter().any(|&f| f == "body") {
@@ -173,21 +207,28 @@ impl BlogPostCreateData {
             lines.push("body := <optional str>$body");
             lines.push("html := <optional str>$html");
             lines.push("excerpt := <optional str>$excerpt");
+            lines.push("toc := to_json(<optional str>$toc)");
+            lines.push("renderer_version := <int16>$renderer_version");
         }
         append_set_statement!("format", "optional DocFormat", lines, submitted_fields);
         append_set_statement!("locale", "optional str", lines, submitted_fields);
         append_set_statement!("og_image", "optional str", lines, submitted_fields);
+        append_set_statement!("show_toc", "optional bool", lines, submitted_fields);
         if self.categories.is_some() {
             let line = "categories := (
                 SELECT BlogCategory FILTER .id IN array_unpack(<array<uuid>>$categories)
             )";
             lines.push(line);
         }
+        if self.author.is_some() {
+            lines.push("author := (SELECT User FILTER .id = <uuid>$author)");
+        }
         let sep = format!(",\n{}", " ".repeat(12));
         lines.join(&sep)
     }
 
-    pub fn make_edgedb_object<'a>(&self, submitted_fields: &Vec<&String>) -> EValue {
+    // "rendered" is from the submitted body, done beforehand because it is slow, see `render_post_blocking`.
+    pub fn make_edgedb_object(&self, submitted_fields: &Vec<&String>, rendered: Option<RenderedPost>) -> EValue {
         let mut pairs = indexmap! {
             "title" => (Some(EValue::Str(self.title.clone())), Cd::One),
             "slug" => (Some(EValue::Str(self.slug.clone())), Cd::One),
@@ -200,14 +241,21 @@ impl BlogPostCreateData {
         }
         if submitted_fields.iter().any(|&f| f == "body") {
             let body = self.body.clone();
-            let html = body.as_ref().map(|v| markdown_to_html(v));
-            let excerpt = body.as_ref().map(|v| make_excerpt(v));
+            let (html, excerpt, toc) = match rendered {
+                Some(RenderedPost { html, excerpt, toc }) => (Some(html), Some(excerpt), serde_json::to_string(&toc).ok()),
+                None => (None, None, None),
+            };
             pairs.insert("body", (body.map(EValue::Str), Cd::AtMostOne));
             pairs.insert("html", (html.map(EValue::Str), Cd::AtMostOne));
             pairs.insert(
                 "excerpt",
                 (excerpt.map(EValue::Str), Cd::AtMostOne),
             );
+            pairs.insert("toc", (toc.map(EValue::Str), Cd::AtMostOne));
+            pairs.insert(
+                "renderer_version",
+                (Some(EValue::Int16(RENDERER_VERSION)), Cd::One),
+            );
         }
         if submitted_fields.iter().any(|&f| f == "format") {
             pairs.insert(
@@ -230,6 +278,12 @@ impl BlogPostCreateData {
                 (self.og_image.clone().map(EValue::Str), Cd::AtMostOne),
             );
         }
+        if submitted_fields.iter().any(|&f| f == "show_toc") {
+            pairs.insert(
+                "show_toc",
+                (self.show_toc.map(EValue::Bool), Cd::AtMostOne),
+            );
+        }
         if let Some(categories) = &self.categories {
             let categories: Vec<EValue> = categories.iter().map(|&i| EValue::Uuid(i)).collect();
             pairs.insert(
@@ -237,6 +291,9 @@ impl BlogPostCreateData {
                 (Some(EValue::Array(categories)), Cd::One),
             );
         }
+        if let Some(author) = self.author {
+            pairs.insert("author", (Some(EValue::Uuid(author)), Cd::One));
+        }
         edge_object_from_pairs(pairs)
     }
 }
@@ -249,7 +306,7 @@ pub struct BlogCategoryPatchData {
 
 #[allow(dead_code)]
 impl BlogCategoryPatchData {
-    pub fn gen_set_clause<'a>(&self, submitted_fields: &Vec<&String>) -> String {
+    pub fn gen_set_clause(&self, submitted_fields: &Vec<&String>) -> String {
         let mut lines = Vec::<&str>::new();
         append_set_statement!("title", "optional str", lines, submitted_fields);
         append_set_statement!("slug", "optional str", lines, submitted_fields);
@@ -257,7 +314,7 @@ impl BlogCategoryPatchData {
         lines.join(&sep)
     }
 
-    pub fn make_edgedb_object<'a>(&self, id: Uuid, submitted_fields: &Vec<&String>) -> EValue {
+    pub fn make_edgedb_object(&self, id: Uuid, submitted_fields: &Vec<&String>) -> EValue {
         let mut pairs = indexmap!(
             "id" => (Some(EValue::Uuid(id)), Cd::One),
         );
@@ -287,13 +344,13 @@ pub struct BlogCategoryCreateData {
 
 #[allow(dead_code)]
 impl BlogCategoryCreateData {
-    pub fn gen_set_clause<'a>(&self) -> String {
-        let lines = vec!["title := <str>$title", "slug := <str>$slug"];
+    pub fn gen_set_clause(&self) -> String {
+        let lines = ["title := <str>$title", "slug := <str>$slug"];
         let sep = format!(",\n{}", " ".repeat(12));
         lines.join(&sep)
     }
 
-    pub fn make_edgedb_object<'a>(&self) -> EValue {
+    pub fn make_edgedb_object(&self) -> EValue {
         let pairs = indexmap! {
             "title" => Some(EValue::from(self.title.clone())),
             "slug" => Some(EValue::from(self.slug.clone())),
@@ -301,3 +358,203 @@ impl BlogCategoryCreateData {
         edge_object_from_simple_pairs(pairs)
     }
 }
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AccessTokenCreateData {
+    #[garde(length(min = 1, max = 100))]
+    pub name: String,
+    #[garde(length(min = 1))]
+    pub scopes: Vec<Permission>,
+    // Never expires if not given.
+    #[garde(custom(valid_token_lifetime))]
+    pub expires_in_days: Option<u16>,
+}
+
+// The token itself is only shown in this response.
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+    #[serde(flatten)]
+    pub info: AccessToken,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct TwoFactorCodeData {
+    #[garde(length(min = 6, max = 10))]
+    pub code: String,
+}
+
+// Shown once, when 2FA is enabled.
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PasswordConfirmData {
+    #[garde(custom(has_some_chars))]
+    pub password: Secret<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PasswordChangeData {
+    #[garde(custom(has_some_chars))]
+    pub current_password: Secret<String>,
+    #[garde(custom(valid_new_password))]
+    pub new_password: Secret<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UserCreateData {
+    #[garde(length(min = 1, max = 150))]
+    pub username: String,
+    #[garde(email, length(max = 200))]
+    pub email: String,
+    #[garde(custom(valid_new_password))]
+    pub password: Secret<String>,
+    #[garde(custom(optional_person_name))]
+    pub first_name: Option<String>,
+    #[garde(custom(optional_person_name))]
+    pub last_name: Option<String>,
+    #[garde(skip)]
+    pub is_active: Option<bool>,
+    #[garde(skip)]
+    pub is_superuser: Option<bool>,
+    #[garde(skip)]
+    pub role: Option<Role>,
+}
+
+impl UserCreateData {
+    pub fn gen_set_clause(&self) -> String {
+        let mut lines = vec![
+            "username := <str>$username",
+            "email := <str>$email",
+            "password := <str>$password",
+            "first_name := <optional str>$first_name",
+            "last_name := <optional str>$last_name",
+        ];
+        if self.is_active.is_some() {
+            lines.push("is_active := <bool>$is_active");
+        }
+        if self.is_superuser.is_some() {
+            lines.push("is_superuser := <bool>$is_superuser");
+        }
+        if self.role.is_some() {
+            lines.push("role := <UserRole>$role");
+        }
+        let sep = format!(",\n{}", " ".repeat(12));
+        lines.join(&sep)
+    }
+
+    // The password is hashed by the caller.
+    pub fn make_edgedb_object(&self, password_hash: String) -> EValue {
+        let mut pairs = indexmap! {
+            "username" => (Some(EValue::Str(self.username.trim().to_string())), Cd::One),
+            "email" => (Some(EValue::Str(self.email.trim().to_string())), Cd::One),
+            "password" => (Some(EValue::Str(password_hash)), Cd::One),
+            "first_name" => (self.first_name.clone().map(EValue::Str), Cd::AtMostOne),
+            "last_name" => (self.last_name.clone().map(EValue::Str), Cd::AtMostOne),
+        };
+        if let Some(is_active) = self.is_active {
+            pairs.insert("is_active", (Some(EValue::Bool(is_active)), Cd::One));
+        }
+        if let Some(is_superuser) = self.is_superuser {
+            pairs.insert("is_superuser", (Some(EValue::Bool(is_superuser)), Cd::One));
+        }
+        if let Some(role) = self.role.clone() {
+            pairs.insert("role", (Some(EValue::from(role)), Cd::One));
+        }
+        edge_object_from_pairs(pairs)
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UserPatchData {
+    #[garde(custom(optional_username))]
+    pub username: Option<String>,
+    #[garde(custom(optional_email))]
+    pub email: Option<String>,
+    #[garde(custom(optional_person_name))]
+    pub first_name: Option<String>,
+    #[garde(custom(optional_person_name))]
+    pub last_name: Option<String>,
+    #[garde(skip)]
+    pub is_active: Option<bool>,
+    #[garde(skip)]
+    pub is_superuser: Option<bool>,
+    #[garde(skip)]
+    pub role: Option<Role>,
+}
+
+impl UserPatchData {
+    // Properties which users are loaded with, so they must not be cleared.
+    pub const NON_NULL_FIELDS: [&'static str; 4] = ["username", "email", "is_active", "is_superuser"];
+
+    pub fn find_null_field(submitted: &JMap<String, JValue>) -> Option<&'static str> {
+        Self::NON_NULL_FIELDS
+            .into_iter()
+            .find(|&f| submitted.get(f).is_some_and(JValue::is_null))
+    }
+
+    pub fn gen_set_clause(&self, submitted_fields: &Vec<&String>) -> String {
+        let mut lines = Vec::<&str>::new();
+        // Required fields cannot be set to null.
+        if self.username.is_some() {
+            lines.push("username := <str>$username");
+        }
+        if self.email.is_some() {
+            lines.push("email := <str>$email");
+        }
+        append_set_statement!("first_name", "optional str", lines, submitted_fields);
+        append_set_statement!("last_name", "optional str", lines, submitted_fields);
+        if self.is_active.is_some() {
+            lines.push("is_active := <bool>$is_active");
+        }
+        if self.is_superuser.is_some() {
+            lines.push("is_superuser := <bool>$is_superuser");
+        }
+        append_set_statement!("role", "optional UserRole", lines, submitted_fields);
+        let sep = format!(",\n{}", " ".repeat(12));
+        lines.join(&sep)
+    }
+
+    pub fn make_edgedb_object(&self, id: Uuid, submitted_fields: &Vec<&String>) -> EValue {
+        let mut pairs = indexmap! {
+            "id" => (Some(EValue::Uuid(id)), Cd::One),
+        };
+        if let Some(username) = &self.username {
+            pairs.insert("username", (Some(EValue::Str(username.trim().to_string())), Cd::One));
+        }
+        if let Some(email) = &self.email {
+            pairs.insert("email", (Some(EValue::Str(email.trim().to_string())), Cd::One));
+        }
+        if submitted_fields.iter().any(|&f| f == "first_name") {
+            pairs.insert("first_name", (self.first_name.clone().map(EValue::Str), Cd::AtMostOne));
+        }
+        if submitted_fields.iter().any(|&f| f == "last_name") {
+            pairs.insert("last_name", (self.last_name.clone().map(EValue::Str), Cd::AtMostOne));
+        }
+        if let Some(is_active) = self.is_active {
+            pairs.insert("is_active", (Some(EValue::Bool(is_active)), Cd::One));
+        }
+        if let Some(is_superuser) = self.is_superuser {
+            pairs.insert("is_superuser", (Some(EValue::Bool(is_superuser)), Cd::One));
+        }
+        if submitted_fields.iter().any(|&f| f == "role") {
+            pairs.insert("role", (self.role.clone().map(EValue::from), Cd::AtMostOne));
+        }
+        edge_object_from_pairs(pairs)
+    }
+}
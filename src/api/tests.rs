@@ -1,7 +1,17 @@
 use http::Uri;
 
+use garde::Validate;
+
+use super::errors::{constraint_field, flatten_garde_errors};
 use super::paging::gen_pagination_links;
-use super::structs::Paging;
+use super::structs::{Paging, UserPatchData};
+use crate::auth::lockout::{LockoutStatus, LoginLockout};
+use crate::auth::tokens::{generate_token, hash_token, parse_scopes, TOKEN_PREFIX};
+use crate::auth::CurrentUser;
+use crate::conf::LockoutSettings;
+use crate::db::CounterBackend;
+use crate::models::{Permission, Role, User};
+use uuid::Uuid;
 
 #[test]
 fn gen_next_url_when_per_page_is_missing() {
@@ -16,3 +26,117 @@ fn gen_next_url_when_per_page_is_missing() {
     );
     assert!(links.next == Some("/api/categories?page=2".to_string()));
 }
+
+#[test]
+fn authors_only_edit_their_own_posts() {
+    let as_current = |user: User| CurrentUser {
+        user,
+        token_scopes: None,
+    };
+    let author = as_current(User {
+        id: Uuid::new_v4(),
+        role: Role::Author,
+        ..Default::default()
+    });
+    let editor = as_current(User {
+        role: Role::Editor,
+        ..Default::default()
+    });
+    let superuser = User {
+        is_superuser: true,
+        role: Role::Viewer,
+        ..Default::default()
+    };
+    assert!(author.can_edit_post(Some(author.user.id)));
+    assert!(!author.can_edit_post(Some(Uuid::new_v4())));
+    assert!(!author.can_edit_post(None));
+    assert!(!author.can(Permission::ManageCategories));
+    assert!(editor.can_edit_post(None));
+    assert!(!editor.can(Permission::ManageUsers));
+    assert_eq!(superuser.effective_role(), Role::Admin);
+    assert!(superuser.can(Permission::RerenderPosts));
+    assert!(!User { role: Role::Viewer, ..Default::default() }.can(Permission::CreatePost));
+}
+
+#[test]
+fn access_token_is_limited_by_scopes_and_role() {
+    let new_token = generate_token().unwrap();
+    assert!(new_token.token.starts_with(TOKEN_PREFIX));
+    assert!(new_token.token.starts_with(&new_token.prefix));
+    assert_eq!(new_token.hash, hash_token(&new_token.token));
+    assert_ne!(new_token.hash, hash_token(&generate_token().unwrap().token));
+    let scopes = parse_scopes(&["create_post".into(), "edit_any_post".into(), "no_such_scope".into()]);
+    assert_eq!(scopes, vec![Permission::CreatePost, Permission::EditAnyPost]);
+    let author = User {
+        id: Uuid::new_v4(),
+        role: Role::Author,
+        ..Default::default()
+    };
+    let by_token = CurrentUser {
+        user: author.clone(),
+        token_scopes: Some(scopes),
+    };
+    assert!(by_token.can(Permission::CreatePost));
+    // Not in the scopes.
+    assert!(!by_token.can_edit_post(Some(author.id)));
+    // In the scopes, but not allowed to authors.
+    assert!(!by_token.can_edit_post(None));
+}
+
+#[tokio::test]
+async fn login_lockout_after_max_attempts() {
+    let settings = LockoutSettings {
+        max_attempts: 3,
+        window: 60,
+    };
+    let lockout = LoginLockout::new(CounterBackend::new(None, "test:"), settings);
+    let user_id = Uuid::new_v4();
+    assert_eq!(lockout.status(user_id).await, LockoutStatus::default());
+    assert!(!lockout.record_failure(user_id).await.locked);
+    assert!(!lockout.record_failure(user_id).await.locked);
+    let status = lockout.record_failure(user_id).await;
+    assert!(status.locked);
+    assert_eq!(status.failed_attempts, 3);
+    assert!(status.retry_after.is_some_and(|s| s > 0 && s <= 60));
+    assert!(lockout.status(user_id).await.locked);
+    assert!(!lockout.status(Uuid::new_v4()).await.locked);
+    lockout.reset(user_id).await.unwrap();
+    assert!(!lockout.status(user_id).await.locked);
+}
+
+#[test]
+fn constraint_violation_is_mapped_to_field() {
+    let detail = "property 'email' of object type 'default::User' violates exclusivity constraint";
+    assert_eq!(constraint_field("email violates exclusivity constraint", detail).as_deref(), Some("email"));
+    let message = "first_name must be no longer than 40 characters.";
+    assert_eq!(constraint_field(message, "").as_deref(), Some("first_name"));
+    assert_eq!(constraint_field("deletion of default::User is prohibited", ""), None);
+}
+
+#[test]
+fn user_patch_is_validated_against_schema_limits() {
+    let data: UserPatchData = serde_json::from_value(serde_json::json!({
+        "email": "not-an-email",
+        "first_name": "x".repeat(41),
+        "last_name": null,
+    }))
+    .unwrap();
+    let errors = flatten_garde_errors(data.validate(&()).unwrap_err());
+    assert!(errors.contains_key("email"));
+    assert!(errors.contains_key("first_name"));
+    assert!(!errors.contains_key("last_name"));
+}
+
+#[test]
+fn user_patch_cannot_clear_required_fields() {
+    let submitted = serde_json::json!({"last_name": null, "is_active": null});
+    let jdata = submitted.as_object().unwrap();
+    assert_eq!(UserPatchData::find_null_field(jdata), Some("is_active"));
+    let submitted = serde_json::json!({"last_name": null, "is_superuser": false});
+    assert_eq!(UserPatchData::find_null_field(submitted.as_object().unwrap()), None);
+    let data: UserPatchData = serde_json::from_value(submitted.clone()).unwrap();
+    let fields: Vec<&String> = submitted.as_object().unwrap().keys().collect();
+    let clause = data.gen_set_clause(&fields);
+    assert!(clause.contains("is_superuser := <bool>$is_superuser"));
+    assert!(!clause.contains("is_active"));
+}
@@ -0,0 +1,383 @@
+use std::cmp::max;
+use std::num::NonZeroU16;
+
+use axum::extract::{OriginalUri, Path, State};
+use axum::{http::StatusCode, response::Result as AxumResult, Json};
+use axum_extra::extract::{Query, WithRejection};
+use chrono::{Duration, Utc};
+use djangohashers::check_password;
+use edgedb_protocol::model::Datetime as EDatetime;
+use edgedb_tokio::Client as EdgeClient;
+use garde::Validate;
+use serde_json::{Map as JMap, Value};
+use uuid::Uuid;
+
+use super::errors::{field_error, ApiError};
+use super::paging::gen_pagination_links;
+use super::structs::{
+    AccessTokenCreateData, CreatedAccessToken, ObjectListResponse, Paging, PasswordChangeData, PasswordConfirmData,
+    RecoveryCodes, TwoFactorCodeData, TwoFactorSetup, TwoFactorStatus, UserCreateData, UserPatchData,
+};
+use crate::auth::lockout::{LockoutStatus, LoginLockout};
+use crate::auth::passwords::hash_password;
+use crate::auth::tokens::generate_token;
+use crate::auth::totp;
+use crate::auth::{Auth, CurrentUser};
+use crate::consts::DEFAULT_PAGE_SIZE;
+use crate::models::{AccessToken, Permission, Role, User, UserInfo};
+use crate::stores;
+use crate::stores::user::USER_INFO_FIELDS;
+
+async fn get_user_or_404(user_id: Uuid, db: &EdgeClient) -> Result<User, ApiError> {
+    stores::user::get_user(user_id, db)
+        .await?
+        .ok_or(ApiError::ObjectNotFound("User".into()))
+}
+
+pub async fn get_lockout(
+    WithRejection(Path(user_id), _): WithRejection<Path<Uuid>, ApiError>,
+    user: CurrentUser,
+    State(db): State<EdgeClient>,
+    State(lockout): State<LoginLockout>,
+) -> AxumResult<Json<LockoutStatus>> {
+    user.require(Permission::ManageUsers)?;
+    let target = get_user_or_404(user_id, &db).await?;
+    Ok(Json(lockout.status(target.id).await))
+}
+
+pub async fn unlock(
+    WithRejection(Path(user_id), _): WithRejection<Path<Uuid>, ApiError>,
+    user: CurrentUser,
+    State(db): State<EdgeClient>,
+    State(lockout): State<LoginLockout>,
+) -> AxumResult<StatusCode> {
+    user.require(Permission::ManageUsers)?;
+    let target = get_user_or_404(user_id, &db).await?;
+    lockout
+        .reset(target.id)
+        .await
+        .map_err(|e| ApiError::Other(format!("Cannot unlock user: {e}")))?;
+    tracing::info!("User {} is unlocked by {}", target.username, user.user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Tokens and 2FA are managed with a logged-in session only, a leaked token must not be able to make more tokens.
+fn require_session(current: &CurrentUser) -> Result<(), ApiError> {
+    match current.token_scopes {
+        Some(_) => Err(ApiError::Forbidden),
+        None => Ok(()),
+    }
+}
+
+pub async fn list_tokens(current: CurrentUser, State(db): State<EdgeClient>) -> AxumResult<Json<Vec<AccessToken>>> {
+    require_session(&current)?;
+    let tokens = stores::token::list_tokens(current.user.id, &db).await.map_err(ApiError::EdgeDBQueryError)?;
+    Ok(Json(tokens))
+}
+
+pub async fn create_token(
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(data), _): WithRejection<Json<AccessTokenCreateData>, ApiError>,
+) -> AxumResult<(StatusCode, Json<CreatedAccessToken>)> {
+    require_session(&current)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    // A token cannot do more than its owner.
+    let denied: Vec<&str> = data.scopes.iter().filter(|&&p| !current.can(p)).map(|&p| p.into()).collect();
+    if !denied.is_empty() {
+        let message = format!("Not allowed to your role: {}", denied.join(", "));
+        return Err(field_error("scopes", &message).into());
+    }
+    let new_token = generate_token().map_err(|_e| ApiError::Other("Cannot generate token".into()))?;
+    let expires_at: Option<EDatetime> = data
+        .expires_in_days
+        .and_then(|days| (Utc::now() + Duration::days(days.into())).try_into().ok());
+    let mut scopes: Vec<String> = data.scopes.iter().map(|&p| <&str>::from(p).to_string()).collect();
+    scopes.sort_unstable();
+    scopes.dedup();
+    let info = stores::token::create_token(
+        current.user.id,
+        data.name.trim(),
+        &new_token.hash,
+        &new_token.prefix,
+        scopes,
+        expires_at,
+        &db,
+    )
+    .await
+    .map_err(ApiError::EdgeDBQueryError)?;
+    tracing::info!("User {} created access token {}", current.user.username, info.prefix);
+    let token = CreatedAccessToken {
+        info,
+        token: new_token.token,
+    };
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+pub async fn revoke_token(
+    WithRejection(Path(token_id), _): WithRejection<Path<Uuid>, ApiError>,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+) -> AxumResult<StatusCode> {
+    require_session(&current)?;
+    stores::token::delete_token(current.user.id, token_id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("AccessToken".into()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_two_factor(current: CurrentUser, State(db): State<EdgeClient>) -> AxumResult<Json<TwoFactorStatus>> {
+    require_session(&current)?;
+    let state = stores::user::get_two_factor(current.user.id, &db).await.map_err(ApiError::EdgeDBQueryError)?.unwrap_or_default();
+    let status = TwoFactorStatus {
+        enabled: state.enabled,
+        recovery_codes_left: state.recovery_codes.len(),
+    };
+    Ok(Json(status))
+}
+
+// Generate a new secret. Calling it again before enabling replaces the secret.
+pub async fn setup_two_factor(current: CurrentUser, State(db): State<EdgeClient>) -> AxumResult<Json<TwoFactorSetup>> {
+    require_session(&current)?;
+    let secret = totp::generate_secret().map_err(|_e| ApiError::Other("Cannot generate secret".into()))?;
+    stores::user::set_pending_totp_secret(current.user.id, &secret, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or_else(|| ApiError::Conflict("Two-factor authentication is already enabled".into()))?;
+    let setup = TwoFactorSetup {
+        otpauth_uri: totp::otpauth_uri(&secret, &current.user.email),
+        secret,
+    };
+    Ok(Json(setup))
+}
+
+// Confirm that the authenticator app works, by a code from it.
+pub async fn enable_two_factor(
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(data), _): WithRejection<Json<TwoFactorCodeData>, ApiError>,
+) -> AxumResult<Json<RecoveryCodes>> {
+    require_session(&current)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    let state = stores::user::get_two_factor(current.user.id, &db).await.map_err(ApiError::EdgeDBQueryError)?.unwrap_or_default();
+    if state.enabled {
+        return Err(ApiError::Conflict("Two-factor authentication is already enabled".into()).into());
+    }
+    let secret = state.secret.ok_or(ApiError::Conflict("Please set up two-factor authentication first".into()))?;
+    let step = totp::verify_code(&secret, &data.code, Utc::now().timestamp() as u64, None)
+        .ok_or_else(|| field_error("code", "Invalid authentication code"))?;
+    let recovery_codes = totp::generate_recovery_codes().map_err(|_e| ApiError::Other("Cannot generate recovery codes".into()))?;
+    let hashes = recovery_codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    stores::user::enable_two_factor(current.user.id, hashes, step as i64, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    tracing::info!("User {} enabled two-factor authentication", current.user.username);
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable_two_factor(
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(data), _): WithRejection<Json<PasswordConfirmData>, ApiError>,
+) -> AxumResult<StatusCode> {
+    require_session(&current)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    let password_ok = check_password(data.password.expose_secret(), &current.user.password).unwrap_or_else(|e| {
+        tracing::error!("Error checking password: {:?}", e);
+        false
+    });
+    if !password_ok {
+        return Err(field_error("password", "Wrong password").into());
+    }
+    stores::user::disable_two_factor(current.user.id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    tracing::info!("User {} disabled two-factor authentication", current.user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Other sessions of the user are logged out, because axum-login binds sessions to the password hash.
+pub async fn change_password(
+    mut auth: Auth,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(data), _): WithRejection<Json<PasswordChangeData>, ApiError>,
+) -> AxumResult<StatusCode> {
+    require_session(&current)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    let password_ok = check_password(data.current_password.expose_secret(), &current.user.password).unwrap_or_else(|e| {
+        tracing::error!("Error checking password: {:?}", e);
+        false
+    });
+    if !password_ok {
+        return Err(field_error("current_password", "Wrong password").into());
+    }
+    let password_hash = hash_password(data.new_password.expose_secret());
+    let user = stores::user::set_password_by_id(current.user.id, &password_hash, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    // Log in again, to keep the current session.
+    auth.login(&user).await.map_err(|e| {
+        tracing::error!("Error logging in user: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!("User {} changed password", user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Admins manage users, but only superusers can grant superuser or admin role, or touch other superusers and admins.
+// Otherwise an admin could take over another admin account by its email, or make more admins.
+fn require_superuser_for(current: &CurrentUser, involves_privileged: bool) -> Result<(), ApiError> {
+    if involves_privileged && !current.user.is_superuser {
+        tracing::info!("User {} is denied changing superuser or admin", current.user.username);
+        return Err(ApiError::Forbidden);
+    }
+    Ok(())
+}
+
+pub async fn list_users(
+    paging: Query<Paging>,
+    OriginalUri(original_uri): OriginalUri,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+) -> AxumResult<Json<ObjectListResponse<UserInfo>>> {
+    current.require(Permission::ManageUsers)?;
+    let page = max(1, paging.0.page.unwrap_or(1));
+    let per_page = max(0, paging.0.per_page.unwrap_or(DEFAULT_PAGE_SIZE)) as u16;
+    let offset: i64 = ((page - 1) * per_page).into();
+    let limit = per_page as i64;
+    let users = stores::user::list_users(Some(offset), Some(limit), &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?;
+    let count = stores::user::get_all_users_count(&db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?;
+    let total_pages =
+        NonZeroU16::new((count as f64 / per_page as f64).ceil() as u16).unwrap_or(NonZeroU16::MIN);
+    let links = gen_pagination_links(&paging.0, count, original_uri);
+    let resp = ObjectListResponse {
+        objects: users,
+        count,
+        total_pages,
+        links,
+    };
+    Ok(Json(resp))
+}
+
+pub async fn get_user_info(
+    WithRejection(Path(user_id), _): WithRejection<Path<Uuid>, ApiError>,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+) -> AxumResult<Json<UserInfo>> {
+    current.require(Permission::ManageUsers)?;
+    let user = stores::user::get_user_info(user_id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    Ok(Json(user))
+}
+
+pub async fn create_user(
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(data), _): WithRejection<Json<UserCreateData>, ApiError>,
+) -> AxumResult<(StatusCode, Json<UserInfo>)> {
+    current.require(Permission::ManageUsers)?;
+    data.validate(&()).map_err(ApiError::ValidationError)?;
+    require_superuser_for(&current, data.is_superuser.unwrap_or_default() || data.role == Some(Role::Admin))?;
+    let set_clause = data.gen_set_clause();
+    let args = data.make_edgedb_object(hash_password(data.password.expose_secret()));
+    let q = format!(
+        "SELECT (
+            INSERT User {{
+                {set_clause}
+            }}
+        ) {{{USER_INFO_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    let user: UserInfo = db
+        .query_single(&q, &args)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::Other("Failed to create User".into()))?;
+    tracing::info!("User {} is created by {}", user.username, current.user.username);
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+pub async fn update_user_partial(
+    WithRejection(Path(user_id), _): WithRejection<Path<Uuid>, ApiError>,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+    WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
+) -> AxumResult<Json<UserInfo>> {
+    current.require(Permission::ManageUsers)?;
+    // Collect list of submitted fields
+    let jdata: JMap<String, Value> =
+        serde_json::from_value(value.clone()).map_err(ApiError::JsonExtractionError)?;
+    let target = stores::user::get_user_info(user_id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    if jdata.is_empty() {
+        return Ok(Json(target));
+    }
+    if let Some(field) = UserPatchData::find_null_field(&jdata) {
+        return Err(field_error(field, "Must not be null").into());
+    }
+    let patch_data: UserPatchData = serde_json::from_value(value).map_err(ApiError::JsonExtractionError)?;
+    patch_data.validate(&()).map_err(ApiError::ValidationError)?;
+    let is_other_admin = target.id != current.user.id && (target.is_superuser || target.role == Role::Admin);
+    let promotes_to_admin = patch_data.role == Some(Role::Admin) && target.role != Role::Admin;
+    require_superuser_for(&current, is_other_admin || promotes_to_admin || patch_data.is_superuser.is_some())?;
+    // Don't let admins lock themselves out by accident.
+    if target.id == current.user.id && patch_data.is_active == Some(false) {
+        return Err(ApiError::Conflict("You cannot deactivate your own account".into()).into());
+    }
+    let submitted_fields: Vec<&String> = jdata.keys().collect();
+    let set_clause = patch_data.gen_set_clause(&submitted_fields);
+    if set_clause.is_empty() {
+        return Ok(Json(target));
+    }
+    let args = patch_data.make_edgedb_object(user_id, &submitted_fields);
+    let q = format!(
+        "SELECT (
+            UPDATE User
+            FILTER .id = <uuid>$id
+            SET {{
+                {set_clause}
+            }}
+        ) {{{USER_INFO_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    tracing::debug!("With args: {:#?}", args);
+    let user: UserInfo = db
+        .query_single(&q, &args)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    tracing::info!("User {} is updated by {}", user.username, current.user.username);
+    Ok(Json(user))
+}
+
+pub async fn delete_user(
+    WithRejection(Path(user_id), _): WithRejection<Path<Uuid>, ApiError>,
+    current: CurrentUser,
+    State(db): State<EdgeClient>,
+) -> AxumResult<StatusCode> {
+    current.require(Permission::ManageUsers)?;
+    if user_id == current.user.id {
+        return Err(ApiError::Conflict("You cannot delete your own account".into()).into());
+    }
+    let target = get_user_or_404(user_id, &db).await?;
+    require_superuser_for(&current, target.is_superuser || target.role == Role::Admin)?;
+    stores::user::delete_user(target.id, &db)
+        .await
+        .map_err(ApiError::EdgeDBQueryError)?
+        .ok_or(ApiError::ObjectNotFound("User".into()))?;
+    tracing::info!("User {} is deleted by {}", target.username, current.user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -6,28 +6,53 @@ use axum::{http::StatusCode, response::Result as AxumResult, Json};
 use axum_extra::extract::{Query, WithRejection};
 use edgedb_tokio::Client as EdgeClient;
 use garde::Validate;
+use serde::Serialize;
 use serde_json::{Map as JMap, Value};
 use uuid::Uuid;
 
 use super::errors::ApiError;
 use super::paging::gen_pagination_links;
-pub use super::posts::{create_post, delete_post, get_post, list_posts, update_post_partial};
+pub use super::posts::{
+    create_post, delete_post, get_post, get_rerender_progress, list_posts, start_rerender, update_post_partial,
+};
 use super::structs::{BlogCategoryCreateData, BlogCategoryPatchData, ObjectListResponse, Paging};
-use crate::auth::Auth;
+use crate::auth::CurrentUser;
 use crate::consts::DEFAULT_PAGE_SIZE;
-use crate::models::{BlogCategory, MinimalObject, User};
+use crate::health::VersionInfo;
+use crate::models::{BlogCategory, MinimalObject, Permission, Role, User};
 use crate::stores;
 
 pub async fn root() -> &'static str {
     "API root"
 }
 
-pub async fn show_me(auth: Auth) -> AxumResult<Json<User>> {
-    tracing::info!("Current user: {:?}", auth.current_user);
-    let user = auth
-        .current_user
-        .ok_or(ApiError::Unauthorized)?;
-    Ok(Json(user))
+pub async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo::CURRENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CurrentUserInfo {
+    #[serde(flatten)]
+    pub user: User,
+    // Role which is actually applied, "admin" for superusers.
+    pub effective_role: Role,
+    pub permissions: Vec<Permission>,
+}
+
+pub async fn show_me(current: CurrentUser) -> AxumResult<Json<CurrentUserInfo>> {
+    let effective_role = current.user.effective_role();
+    // With access token, only what the token is allowed to do.
+    let permissions = effective_role
+        .permissions()
+        .iter()
+        .copied()
+        .filter(|&p| current.can(p))
+        .collect();
+    Ok(Json(CurrentUserInfo {
+        user: current.user,
+        effective_role,
+        permissions,
+    }))
 }
 
 pub async fn list_categories(
@@ -37,7 +62,7 @@ pub async fn list_categories(
 ) -> AxumResult<Json<ObjectListResponse<BlogCategory>>> {
     let page = max(1, paging.0.page.unwrap_or(1));
     let per_page = max(0, paging.0.per_page.unwrap_or(DEFAULT_PAGE_SIZE)) as u16;
-    let offset: i64 = ((page - 1) * per_page).try_into().unwrap_or(0);
+    let offset: i64 = ((page - 1) * per_page).into();
     let limit = per_page as i64;
     let categories = stores::blog::get_blog_categories(Some(offset), Some(limit), &db)
         .await
@@ -73,11 +98,10 @@ pub async fn get_category(
 
 pub async fn delete_category(
     Path(category_id): Path<Uuid>,
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
 ) -> AxumResult<StatusCode> {
-    tracing::info!("Current user: {:?}", auth.current_user);
-    auth.current_user.ok_or(StatusCode::FORBIDDEN)?;
+    user.require(Permission::ManageCategories)?;
     let q = "DELETE BlogCategory FILTER .id = <uuid>$0";
     tracing::debug!("To query: {}", q);
     let _deleted_cat: MinimalObject = db
@@ -90,14 +114,11 @@ pub async fn delete_category(
 
 pub async fn update_category_partial(
     WithRejection(Path(category_id), _): WithRejection<Path<Uuid>, ApiError>,
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
     WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
 ) -> AxumResult<Json<BlogCategory>> {
-    auth.current_user.ok_or_else(|| {
-        tracing::debug!("Not logged in!");
-        StatusCode::FORBIDDEN
-    })?;
+    user.require(Permission::ManageCategories)?;
     // Collect list of submitted fields
     let jdata: JMap<String, Value> =
         serde_json::from_value(value.clone()).map_err(ApiError::JsonExtractionError)?;
@@ -139,11 +160,11 @@ pub async fn update_category_partial(
 }
 
 pub async fn create_category(
-    auth: Auth,
+    user: CurrentUser,
     State(db): State<EdgeClient>,
     WithRejection(Json(value), _): WithRejection<Json<Value>, ApiError>,
 ) -> AxumResult<(StatusCode, Json<BlogCategory>)> {
-    auth.current_user.ok_or(StatusCode::FORBIDDEN)?;
+    user.require(Permission::ManageCategories)?;
     // Collect list of submitted fields
     let jdata: JMap<String, Value> =
         serde_json::from_value(value.clone()).map_err(ApiError::JsonExtractionError)?;
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::conf::LockoutSettings;
+use crate::db::CounterBackend;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct LockoutStatus {
+    // Wrong passwords in the current window.
+    pub failed_attempts: u64,
+    pub locked: bool,
+    // Seconds until the window ends, when the counter is reset.
+    pub retry_after: Option<u64>,
+}
+
+// Locks an account after too many wrong passwords. If the counter backend fails,
+// we log it and let the login go on, rather than locking everyone out.
+#[derive(Debug, Clone)]
+pub struct LoginLockout {
+    counters: CounterBackend,
+    settings: LockoutSettings,
+}
+
+impl LoginLockout {
+    pub fn new(counters: CounterBackend, settings: LockoutSettings) -> Self {
+        Self { counters, settings }
+    }
+
+    fn counter_key(user_id: Uuid) -> String {
+        format!("login_failures:{user_id}")
+    }
+
+    fn make_status(&self, counter: Option<(u64, Duration)>) -> LockoutStatus {
+        let Some((failed_attempts, ttl)) = counter else {
+            return LockoutStatus::default();
+        };
+        let max_attempts = self.settings.max_attempts as u64;
+        LockoutStatus {
+            failed_attempts,
+            locked: max_attempts > 0 && failed_attempts >= max_attempts,
+            retry_after: Some(ttl.as_secs_f64().ceil() as u64),
+        }
+    }
+
+    pub async fn status(&self, user_id: Uuid) -> LockoutStatus {
+        match self.counters.get(&Self::counter_key(user_id)).await {
+            Ok(counter) => self.make_status(counter),
+            Err(e) => {
+                tracing::error!("Cannot read login failures of {user_id}: {e}");
+                LockoutStatus::default()
+            }
+        }
+    }
+
+    pub async fn record_failure(&self, user_id: Uuid) -> LockoutStatus {
+        let window = Duration::from_secs(self.settings.window);
+        match self.counters.increment(&Self::counter_key(user_id), window).await {
+            Ok(counter) => {
+                let status = self.make_status(Some(counter));
+                if status.locked {
+                    tracing::warn!("User {user_id} is locked out after {} failed logins", status.failed_attempts);
+                }
+                status
+            }
+            Err(e) => {
+                tracing::error!("Cannot count login failure of {user_id}: {e}");
+                LockoutStatus::default()
+            }
+        }
+    }
+
+    // Called on successful login, or by admin to unlock the account.
+    pub async fn reset(&self, user_id: Uuid) -> Result<(), fred::error::RedisError> {
+        self.counters.reset(&Self::counter_key(user_id)).await
+    }
+}
@@ -1,10 +1,64 @@
+pub mod lockout;
+pub mod passwords;
+pub mod rotation;
 pub mod store;
 pub mod structs;
+#[cfg(test)]
+pub mod tests;
+pub mod tokens;
+pub mod totp;
 
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use http::request::Parts;
 use uuid::Uuid;
 use axum_login::extractors::AuthContext;
 
-use crate::models::{User, Role};
+use crate::api::errors::ApiError;
+use crate::models::{Permission, User, Role};
 use store::EdgeDbStore;
+use tokens::TokenScopes;
 
 pub type Auth = AuthContext<Uuid, User, EdgeDbStore<User>, Role>;
+
+// The logged-in user, for API handlers which need to check permissions.
+// Rejects with 401 if nobody is logged in.
+pub struct CurrentUser {
+    pub user: User,
+    // Set when authenticated by access token, which can only do what its scopes allow.
+    pub token_scopes: Option<Vec<Permission>>,
+}
+
+impl CurrentUser {
+    pub fn can(&self, permission: Permission) -> bool {
+        let in_scope = self.token_scopes.as_ref().is_none_or(|s| s.contains(&permission));
+        in_scope && self.user.can(permission)
+    }
+
+    // Authors can only change the posts they wrote.
+    pub fn can_edit_post(&self, author_id: Option<Uuid>) -> bool {
+        let is_own = author_id == Some(self.user.id);
+        self.can(Permission::EditAnyPost) || (is_own && self.can(Permission::EditOwnPost))
+    }
+
+    pub fn require(&self, permission: Permission) -> Result<&User, ApiError> {
+        if self.can(permission) {
+            Ok(&self.user)
+        } else {
+            tracing::info!("User {} ({}) is denied {permission:?}", self.user.username, self.user.effective_role());
+            Err(ApiError::Forbidden)
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Ok(auth) = Auth::from_request_parts(parts, state).await;
+        let user = auth.current_user.ok_or(ApiError::Unauthorized)?;
+        let token_scopes = parts.extensions.get::<TokenScopes>().map(|s| s.0.clone());
+        Ok(Self { user, token_scopes })
+    }
+}
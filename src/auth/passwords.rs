@@ -0,0 +1,81 @@
+use chrono::Utc;
+use djangohashers::{make_password_with_algorithm, Algorithm};
+use ring::hmac;
+use uuid::Uuid;
+
+use crate::conf::PasswordResetSettings;
+
+// What `make_password_with_algorithm` produces for Argon2 with the current djangohashers.
+// Hashes not starting with it come from the old Django site, or older Argon2 parameters.
+const CURRENT_HASH_PREFIX: &str = "argon2$argon2id$v=19$m=102400,t=2,p=8$";
+// Keeps reset signatures apart from anything else signed with the secret key.
+const RESET_CONTEXT: &str = "password-reset";
+
+pub fn hash_password(password: &str) -> String {
+    make_password_with_algorithm(password, Algorithm::Argon2)
+}
+
+pub fn needs_rehash(encoded: &str) -> bool {
+    !encoded.starts_with(CURRENT_HASH_PREFIX)
+}
+
+// Stateless password reset tokens: "{user_id}.{expires_at}.{signature}".
+// The signature covers the current password hash, so a token stops working once the password is changed.
+#[derive(Debug, Clone)]
+pub struct ResetTokens {
+    key: hmac::Key,
+    lifetime: i64,
+    url_template: String,
+}
+
+impl ResetTokens {
+    // None when there is no page to link to, then password reset is disabled.
+    pub fn new(secret: &[u8], settings: &PasswordResetSettings) -> Option<Self> {
+        Some(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            lifetime: settings.token_lifetime as i64,
+            url_template: settings.url.clone()?,
+        })
+    }
+
+    fn message(user_id: Uuid, expires_at: i64, password_hash: &str) -> String {
+        format!("{RESET_CONTEXT}:{}:{expires_at}:{password_hash}", user_id.simple())
+    }
+
+    pub fn issue(&self, user_id: Uuid, password_hash: &str) -> String {
+        let expires_at = Utc::now().timestamp() + self.lifetime;
+        let tag = hmac::sign(&self.key, Self::message(user_id, expires_at, password_hash).as_bytes());
+        let signature = base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{expires_at}.{signature}", user_id.simple())
+    }
+
+    pub fn lifetime_minutes(&self) -> i64 {
+        (self.lifetime + 59) / 60
+    }
+
+    pub fn reset_url(&self, token: &str) -> String {
+        self.url_template.replace("{token}", token)
+    }
+
+    // Read the user ID without checking, to look up the password hash for `verify`.
+    pub fn user_id(token: &str) -> Option<Uuid> {
+        token.split('.').next()?.parse().ok()
+    }
+
+    pub fn verify(&self, token: &str, password_hash: &str) -> bool {
+        let parts: Vec<&str> = token.split('.').collect();
+        let [user_id, expires_at, signature] = parts[..] else {
+            return false;
+        };
+        let (Ok(user_id), Ok(expires_at)) = (user_id.parse::<Uuid>(), expires_at.parse::<i64>()) else {
+            return false;
+        };
+        if expires_at <= Utc::now().timestamp() {
+            return false;
+        }
+        let Ok(signature) = base64::decode_config(signature, base64::URL_SAFE_NO_PAD) else {
+            return false;
+        };
+        hmac::verify(&self.key, Self::message(user_id, expires_at, password_hash).as_bytes(), &signature).is_ok()
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::header::COOKIE;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_login::axum_sessions::SessionHandle;
+use axum_login::{secrecy::ExposeSecret, AuthUser, UserStore};
+use cookie::{Cookie, CookieJar, Key};
+use ring::{digest, hmac};
+use uuid::Uuid;
+
+use super::store::EdgeDbStore;
+use crate::models::{Role, User};
+
+// Cookie name and session keys are the ones used by axum-sessions and axum-login.
+pub const SESSION_COOKIE_NAME: &str = "sid";
+const SESSION_USER_ID_KEY: &str = "_user_id";
+const SESSION_AUTH_ID_KEY: &str = "_auth_id";
+// Marks the session as already verified against the current secret key.
+const SESSION_KEY_ID_KEY: &str = "_secret_key_id";
+
+// After the secret key is rotated, sessions signed with old keys are re-signed with the
+// current key on their next request, so that users are not logged out.
+#[derive(Clone)]
+pub struct SecretRotation(Arc<SecretRotationInner>);
+
+struct SecretRotationInner {
+    current: Vec<u8>,
+    old: Vec<Vec<u8>>,
+    key_id: String,
+    user_store: EdgeDbStore<User>,
+}
+
+impl SecretRotation {
+    pub fn new(current: Vec<u8>, old: Vec<Vec<u8>>, user_store: EdgeDbStore<User>) -> Self {
+        let fingerprint = digest::digest(&digest::SHA256, &current);
+        let key_id = fingerprint.as_ref()[..8].iter().map(|b| format!("{b:02x}")).collect();
+        Self(Arc::new(SecretRotationInner {
+            current,
+            old,
+            key_id,
+            user_store,
+        }))
+    }
+
+    fn is_active(&self) -> bool {
+        !self.0.old.is_empty()
+    }
+
+    // If the session cookie is signed by an old key, return the cookie re-signed by current key.
+    fn resign_cookie(&self, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie.clone());
+        if jar.signed(&Key::from(&self.0.current)).get(SESSION_COOKIE_NAME).is_some() {
+            return None;
+        }
+        let value = self
+            .0
+            .old
+            .iter()
+            .find_map(|old| jar.signed(&Key::from(old)).get(SESSION_COOKIE_NAME))?
+            .value()
+            .to_string();
+        let mut signed_jar = CookieJar::new();
+        signed_jar
+            .signed_mut(&Key::from(&self.0.current))
+            .add(Cookie::new(SESSION_COOKIE_NAME, value));
+        signed_jar.get(SESSION_COOKIE_NAME).cloned()
+    }
+
+    // axum-login stores HMAC of the user's password hash in session, which is bound to the key, too.
+    async fn upgrade_auth_id(&self, handle: &SessionHandle) {
+        let session = handle.read().await;
+        let Some(user_id) = session.get::<Uuid>(SESSION_USER_ID_KEY) else {
+            return;
+        };
+        if session.get::<String>(SESSION_KEY_ID_KEY).as_ref() == Some(&self.0.key_id) {
+            return;
+        }
+        let auth_id = session
+            .get::<String>(SESSION_AUTH_ID_KEY)
+            .and_then(|a| base64::decode(a).ok())
+            .unwrap_or_default();
+        drop(session);
+        let user = match UserStore::<Uuid, Role>::load_user(&self.0.user_store, &user_id).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Failed to load user {user_id} to upgrade session: {e}");
+                return;
+            }
+        };
+        let password_hash = AuthUser::<Uuid, Role>::get_password_hash(&user);
+        let password_hash = password_hash.expose_secret();
+        let signed_by = std::iter::once(&self.0.current)
+            .chain(&self.0.old)
+            .find(|k| hmac::verify(&hmac::Key::new(hmac::HMAC_SHA512, k), password_hash, &auth_id).is_ok());
+        // Not signed by any known key, leave it for axum-login to log out.
+        let Some(signed_by) = signed_by else {
+            return;
+        };
+        let mut session = handle.write().await;
+        if signed_by != &self.0.current {
+            let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, &self.0.current), password_hash);
+            if session.insert(SESSION_AUTH_ID_KEY, base64::encode(tag.as_ref())).is_ok() {
+                tracing::info!("Re-signed session of user {user_id} with current key");
+            }
+        }
+        session.insert(SESSION_KEY_ID_KEY, &self.0.key_id).ok();
+    }
+}
+
+// To be placed outside of session layer.
+pub async fn resign_session_cookie<B>(
+    State(rotation): State<SecretRotation>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !rotation.is_active() {
+        return next.run(request).await;
+    }
+    let cookies: Vec<Cookie<'static>> = request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| Cookie::parse_encoded(c.trim().to_string()).ok())
+        .collect();
+    let resigned = cookies
+        .iter()
+        .filter(|c| c.name() == SESSION_COOKIE_NAME)
+        .find_map(|c| rotation.resign_cookie(c));
+    if let Some(resigned) = resigned {
+        let header: Vec<String> = cookies
+            .iter()
+            .filter(|c| c.name() != SESSION_COOKIE_NAME)
+            .chain(std::iter::once(&resigned))
+            .map(|c| c.encoded().to_string())
+            .collect();
+        if let Ok(value) = HeaderValue::from_str(&header.join("; ")) {
+            request.headers_mut().insert(COOKIE, value);
+        }
+    }
+    next.run(request).await
+}
+
+// To be placed between session layer and auth layer.
+pub async fn upgrade_session_auth<B>(
+    State(rotation): State<SecretRotation>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if rotation.is_active() {
+        if let Some(handle) = request.extensions().get::<SessionHandle>() {
+            rotation.upgrade_auth_id(handle).await;
+        }
+    }
+    next.run(request).await
+}
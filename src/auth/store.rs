@@ -33,7 +33,9 @@ where
     type User = IUser;
     async fn load_user(&self, user_id: &UserId) -> Result<Option<Self::User>, Error> {
         tracing::info!("To load user with ID {:?}", user_id);
-        let q = "SELECT User {id, username, email, password, is_active, is_superuser} FILTER .id = <uuid>$0";
+        // Deactivated users are logged out on their next request.
+        let q = "SELECT User {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author}
+            FILTER .id = <uuid>$0 AND .is_active ?? true";
         let user: Option<IUser> = self.client.query_single(q, &(user_id,)).await?;
         Ok(user)
     }
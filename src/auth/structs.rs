@@ -1,8 +1,9 @@
 use garde::Validate;
 use redact::Secret;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::utils::validation::has_some_chars;
+use crate::utils::validation::{has_some_chars, valid_new_password};
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct LoginReqData {
@@ -11,3 +12,32 @@ pub struct LoginReqData {
     #[garde(custom(has_some_chars))]
     pub password: Secret<String>,
 }
+
+// Second login step. Either code from authenticator app, or one of the recovery codes.
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginData {
+    pub code: Option<String>,
+    pub recovery_code: Option<Secret<String>>,
+}
+
+// Kept in session between the password and the 2FA code steps.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub user_id: Uuid,
+    // Unix timestamp
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PasswordResetRequestData {
+    #[garde(email)]
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct PasswordResetConfirmData {
+    #[garde(length(min = 1, max = 200))]
+    pub token: String,
+    #[garde(custom(valid_new_password))]
+    pub new_password: Secret<String>,
+}
@@ -0,0 +1,75 @@
+use uuid::Uuid;
+
+use crate::conf::PasswordResetSettings;
+use super::passwords::{hash_password, needs_rehash, ResetTokens};
+use super::totp::{base32_decode, base32_encode, code_at, generate_recovery_codes, hash_recovery_code, verify_code};
+
+// Test vectors from RFC 4648 and RFC 6238.
+const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+#[test]
+fn base32_roundtrip() {
+    assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+    assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+    assert_eq!(base32_decode(&base32_encode(RFC_SECRET)).unwrap(), RFC_SECRET);
+    assert!(base32_decode("MZXW1").is_none());
+}
+
+#[test]
+fn totp_codes_match_rfc() {
+    assert_eq!(code_at(RFC_SECRET, 59 / 30), "287082");
+    assert_eq!(code_at(RFC_SECRET, 1111111109 / 30), "081804");
+    let secret = base32_encode(RFC_SECRET);
+    assert_eq!(verify_code(&secret, "287 082", 59, None), Some(1));
+    // One step of clock drift is allowed
+    assert_eq!(verify_code(&secret, "287082", 89, None), Some(1));
+    assert_eq!(verify_code(&secret, "287082", 150, None), None);
+    // Used code cannot be used again
+    assert_eq!(verify_code(&secret, "287082", 59, Some(1)), None);
+}
+
+#[test]
+fn recovery_codes_are_normalized() {
+    let codes = generate_recovery_codes().unwrap();
+    assert_eq!(codes.len(), 10);
+    let code = &codes[0];
+    assert_eq!(hash_recovery_code(code), hash_recovery_code(&code.replace('-', " ").to_uppercase()));
+    assert_ne!(hash_recovery_code(code), hash_recovery_code(&codes[1]));
+}
+
+#[test]
+fn legacy_hashes_need_rehash() {
+    assert!(needs_rehash("pbkdf2_sha256$36000$salt$hash"));
+    assert!(needs_rehash("argon2$argon2i$v=19$m=512,t=2,p=2$salt$hash"));
+    assert!(!needs_rehash("argon2$argon2id$v=19$m=102400,t=2,p=8$salt$hash"));
+}
+
+// Fails when djangohashers changes its Argon2 parameters, then CURRENT_HASH_PREFIX must follow.
+#[test]
+fn fresh_hashes_need_no_rehash() {
+    assert!(!needs_rehash(&hash_password("x")));
+}
+
+#[test]
+fn reset_token_is_bound_to_password_hash() {
+    let settings = PasswordResetSettings {
+        url: Some("https://example.com/reset?token={token}".into()),
+        ..Default::default()
+    };
+    let tokens = ResetTokens::new(&[7; 64], &settings).unwrap();
+    let user_id = Uuid::from_u128(42);
+    let token = tokens.issue(user_id, "old-hash");
+    assert_eq!(ResetTokens::user_id(&token), Some(user_id));
+    assert!(tokens.verify(&token, "old-hash"));
+    // Changing password makes the token useless
+    assert!(!tokens.verify(&token, "new-hash"));
+    // Expiry cannot be extended
+    let parts: Vec<&str> = token.split('.').collect();
+    let forged = format!("{}.{}.{}", parts[0], i64::MAX, parts[2]);
+    assert!(!tokens.verify(&forged, "old-hash"));
+    // Other keys don't accept it
+    let other = ResetTokens::new(&[8; 64], &settings).unwrap();
+    assert!(!other.verify(&token, "old-hash"));
+    assert!(!tokens.verify("garbage", "old-hash"));
+}
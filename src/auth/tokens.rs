@@ -0,0 +1,81 @@
+use std::str::FromStr;
+
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use edgedb_tokio::Client as EdgeClient;
+use http::header::AUTHORIZATION;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::Auth;
+use crate::api::errors::ApiError;
+use crate::models::{Permission, User};
+use crate::stores;
+
+// Makes tokens recognizable, for example by secret scanners.
+pub const TOKEN_PREFIX: &str = "qwt_";
+const TOKEN_BYTES: usize = 32;
+// Length of the token beginning which is stored in clear, to tell tokens apart.
+const SHOWN_PREFIX_LENGTH: usize = 12;
+
+// What a request authenticated by access token is allowed to do, on top of the owner's role.
+#[derive(Debug, Clone)]
+pub struct TokenScopes(pub Vec<Permission>);
+
+pub struct NewToken {
+    pub token: String,
+    pub hash: String,
+    pub prefix: String,
+}
+
+pub fn generate_token() -> Result<NewToken, ring::error::Unspecified> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    SystemRandom::new().fill(&mut bytes)?;
+    let token = format!("{TOKEN_PREFIX}{}", base64::encode_config(bytes, base64::URL_SAFE_NO_PAD));
+    Ok(NewToken {
+        hash: hash_token(&token),
+        prefix: token[..SHOWN_PREFIX_LENGTH].to_string(),
+        token,
+    })
+}
+
+// Tokens are random enough that a fast hash, without salt, is fine.
+pub fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    hash.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Scopes unknown to this version (removed permissions) are ignored.
+pub fn parse_scopes(scopes: &[String]) -> Vec<Permission> {
+    scopes.iter().filter_map(|s| Permission::from_str(s).ok()).collect()
+}
+
+fn bearer_token<B>(request: &Request<B>) -> Option<&str> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+// Must be inside the auth layer. A request with valid "Authorization: Bearer" token is treated
+// as if the token owner is logged in, so handlers using `Auth` don't have to care.
+pub async fn authenticate_bearer<B>(State(db): State<EdgeClient>, mut request: Request<B>, next: Next<B>) -> Response {
+    let Some(token) = bearer_token(&request) else {
+        return next.run(request).await;
+    };
+    let owner = match stores::token::use_token(&hash_token(token), &db).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return ApiError::LoginError("Invalid or expired access token".into()).into_response(),
+        Err(e) => return ApiError::EdgeDBQueryError(e).into_response(),
+    };
+    tracing::debug!("Authenticated {} by access token", owner.user.username);
+    let scopes = TokenScopes(parse_scopes(&owner.scopes));
+    let extensions = request.extensions_mut();
+    if let Some(auth) = extensions.get_mut::<Auth>() {
+        auth.current_user = Some(owner.user.clone());
+    }
+    extensions.insert::<Option<User>>(Some(owner.user));
+    extensions.insert(scopes);
+    next.run(request).await
+}
@@ -0,0 +1,117 @@
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::tokens::hash_token;
+use crate::consts::TOTP_ISSUER;
+
+// RFC 6238 defaults, which are the only ones that every authenticator app supports.
+pub const STEP_SECONDS: u64 = 30;
+pub const DIGITS: usize = 6;
+const SECRET_BYTES: usize = 20;
+// Codes of one step before and after are accepted, for clock drift.
+const ALLOWED_SKEW: u64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 base32, without padding, as used in otpauth URIs.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    output
+}
+
+// Lenient about case, spaces and padding, because people may type the secret in.
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let c = c.to_ascii_uppercase() as u8;
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+pub fn generate_secret() -> Result<String, ring::error::Unspecified> {
+    let mut bytes = [0u8; SECRET_BYTES];
+    SystemRandom::new().fill(&mut bytes)?;
+    Ok(base32_encode(&bytes))
+}
+
+// For authenticator apps to scan as QR code.
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let issuer = urlencoding::encode(TOTP_ISSUER);
+    let account = urlencoding::encode(account);
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}")
+}
+
+// HOTP value (RFC 4226) for the given counter, which is the time step in TOTP.
+pub fn code_at(key: &[u8], step: u64) -> String {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key), &step.to_be_bytes());
+    let digest = tag.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+// Return the time step which the code matches. Steps up to `last_step` are refused,
+// so that a code seen by someone else cannot be replayed.
+pub fn verify_code(secret: &str, code: &str, unix_time: u64, last_step: Option<i64>) -> Option<u64> {
+    let key = base32_decode(secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS {
+        return None;
+    }
+    let current = unix_time / STEP_SECONDS;
+    (current.saturating_sub(ALLOWED_SKEW)..=current + ALLOWED_SKEW)
+        .filter(|&step| last_step.is_none_or(|last| step as i64 > last))
+        .find(|&step| verify_slices_are_equal(code_at(&key, step).as_bytes(), code.as_bytes()).is_ok())
+}
+
+// Codes are shown as "xxxxx-xxxxx", in lowercase base32 to avoid confusable characters.
+pub fn generate_recovery_codes() -> Result<Vec<String>, ring::error::Unspecified> {
+    let rng = SystemRandom::new();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; RECOVERY_CODE_LENGTH];
+            rng.fill(&mut bytes)?;
+            let chars: String = bytes
+                .iter()
+                .map(|b| BASE32_ALPHABET[(b & 0x1f) as usize].to_ascii_lowercase() as char)
+                .collect();
+            let (head, tail) = chars.split_at(RECOVERY_CODE_LENGTH / 2);
+            Ok(format!("{head}-{tail}"))
+        })
+        .collect()
+}
+
+// Recovery codes are stored hashed, like access tokens. Case and separators don't matter.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
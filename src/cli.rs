@@ -1,8 +1,85 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::models::Role;
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about)]
 pub struct AppOptions {
-    #[arg(short, action = clap::ArgAction::Count)]
+    #[arg(short, action = clap::ArgAction::Count, global = true)]
     pub verbose: u8,
+    /// Default to "serve" if not given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Run the web server
+    Serve,
+    /// Create a user, with password read from terminal or stdin
+    Createuser(CreateUserArgs),
+    /// Change password of a user, with password read from terminal or stdin
+    Changepassword(ChangePasswordArgs),
+    /// Regenerate HTML and excerpt of blog posts rendered by an older renderer
+    Rerender(RerenderArgs),
+    /// Validate config, template folder, database and Redis connectivity
+    Check,
+    /// Print the route table
+    Routes,
+    /// Generate secret key and store it in a file readable only by the owner
+    Gensecret(GenSecretArgs),
+    /// Print the stylesheet for highlighted code blocks, in a bundled syntect theme
+    Highlightcss(HighlightCssArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CreateUserArgs {
+    pub username: String,
+    pub email: String,
+    #[arg(long)]
+    pub superuser: bool,
+    /// One of "viewer", "author", "editor", "admin". Superusers are always admins.
+    #[arg(long, default_value = "author")]
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ChangePasswordArgs {
+    /// Username or email
+    pub user: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RerenderArgs {
+    /// Re-render all posts, not just the outdated ones
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GenSecretArgs {
+    /// File to write to. Default to "secret_file" setting.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Replace current key, but keep it as an old key, still accepted for existing sessions
+    #[arg(long, conflicts_with = "force")]
+    pub rotate: bool,
+    /// Number of old keys to keep when rotating
+    #[arg(long, default_value_t = 2)]
+    pub keep: usize,
+    /// Overwrite existing file, discarding all keys in it
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct HighlightCssArgs {
+    /// Theme name, like "base16-ocean.dark", or its slug, like "base16-ocean-dark"
+    #[arg(required_unless_present = "list")]
+    pub theme: Option<String>,
+    /// List the bundled themes
+    #[arg(long, conflicts_with = "theme")]
+    pub list: bool,
 }
@@ -0,0 +1,66 @@
+use miette::miette;
+
+use crate::conf::AppConfig;
+use crate::health::{self, DependencyStatus, HealthStatus};
+use crate::{config_jinja, db};
+
+fn report(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("[ OK ] {name}: {detail}");
+            true
+        }
+        Err(e) => {
+            println!("[FAIL] {name}: {e}");
+            false
+        }
+    }
+}
+
+fn dependency_result(status: DependencyStatus) -> Result<String, String> {
+    match status.status {
+        HealthStatus::Ok => Ok(format!("responded in {} ms", status.latency_ms)),
+        HealthStatus::Unavailable => Err(status.error.unwrap_or_default()),
+    }
+}
+
+// Run all checks, even if some fail, so that operators see every problem at once.
+pub async fn run_checks() -> miette::Result<()> {
+    let config = match AppConfig::load() {
+        Ok(config) => {
+            report("config", Ok("valid".into()));
+            config
+        }
+        Err(e) => {
+            report("config", Err("invalid".into()));
+            // Other checks need a valid config.
+            return Err(e);
+        }
+    };
+    let mut passed = vec![report(
+        "templates",
+        config_jinja().map(|_j| "readable".into()).map_err(|e| e.to_string()),
+    )];
+    let edgedb = match db::get_edgedb_client(&config).await {
+        Ok(client) => dependency_result(health::check_edgedb(&client).await),
+        Err(e) => Err(e.to_string()),
+    };
+    passed.push(report("edgedb", edgedb));
+    let redis = match &config.redis.url {
+        Some(url) => match db::get_redis_pool(url.expose_secret(), &config.redis).await {
+            Ok(pool) => {
+                let result = dependency_result(health::check_redis(&pool).await);
+                pool.quit_pool().await;
+                result
+            }
+            Err(e) => Err(e.to_string()),
+        },
+        None => Ok("not configured, sessions are kept in memory".into()),
+    };
+    passed.push(report("redis", redis));
+    let failed = passed.iter().filter(|&&p| !p).count();
+    if failed > 0 {
+        return Err(miette!("{failed} check(s) failed"));
+    }
+    Ok(())
+}
@@ -0,0 +1,4 @@
+pub mod check;
+pub mod rerender;
+pub mod routes;
+pub mod user;
@@ -0,0 +1,31 @@
+use miette::miette;
+
+use crate::cli::RerenderArgs;
+use crate::conf::AppConfig;
+use crate::db;
+use crate::rerender::{self, JobStatus};
+
+pub async fn rerender_posts(args: &RerenderArgs, config: &AppConfig) -> miette::Result<()> {
+    let client = db::get_edgedb_client(config).await?;
+    let progress = rerender::rerender_posts(&client, args.all, |p| {
+        println!("Processed {}/{} posts", p.processed, p.total);
+    })
+    .await;
+    for failure in &progress.failures {
+        eprintln!("Failed to update post \"{}\" ({}): {}", failure.title, failure.id, failure.error);
+    }
+    println!(
+        "Re-rendered {} posts with renderer version {}, skipped {}, failed {}",
+        progress.updated,
+        progress.renderer_version,
+        progress.skipped,
+        progress.failures.len()
+    );
+    if progress.status == JobStatus::Failed {
+        return Err(miette!("Job stopped: {}", progress.error.unwrap_or_default()));
+    }
+    if !progress.failures.is_empty() {
+        return Err(miette!("Failed to re-render {} posts", progress.failures.len()));
+    }
+    Ok(())
+}
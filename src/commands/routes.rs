@@ -0,0 +1,19 @@
+use crate::utils::routing::RouteInfo;
+use crate::{api, front, health, monitoring};
+
+// Mirror how routers are merged and nested in main.
+pub fn print_routes() {
+    let tables: [(&str, Vec<RouteInfo>); 4] = [
+        ("", front::routes::route_table().routes().to_vec()),
+        ("/_api", api::routes::route_table().routes().to_vec()),
+        ("/_health", health::route_table().routes().to_vec()),
+        ("", monitoring::route_table().routes().to_vec()),
+    ];
+    for (prefix, routes) in tables {
+        for route in routes {
+            let methods: Vec<&str> = route.methods.iter().map(|m| m.as_str()).collect();
+            println!("{:<20} {prefix}{}", methods.join(","), route.path);
+        }
+    }
+    println!("{:<20} (any other path, served by front::views::fallback_view)", "*");
+}
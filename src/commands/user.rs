@@ -0,0 +1,70 @@
+use std::io::{self, BufRead, IsTerminal};
+
+use miette::{miette, IntoDiagnostic};
+
+use crate::auth::passwords::hash_password;
+use crate::cli::{ChangePasswordArgs, CreateUserArgs};
+use crate::conf::AppConfig;
+use crate::db;
+use crate::stores;
+
+fn read_line(stdin: &io::Stdin) -> miette::Result<String> {
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line).into_diagnostic()?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Prompt twice on terminal. Otherwise, read one line from stdin, to be used in scripts.
+fn read_password() -> miette::Result<String> {
+    let stdin = io::stdin();
+    let password = if stdin.is_terminal() {
+        let password = rpassword::prompt_password("Password: ").into_diagnostic()?;
+        if rpassword::prompt_password("Password (again): ").into_diagnostic()? != password {
+            return Err(miette!("Passwords don't match"));
+        }
+        password
+    } else {
+        read_line(&stdin)?
+    };
+    if password.is_empty() {
+        return Err(miette!("Password must not be empty"));
+    }
+    Ok(password)
+}
+
+pub async fn create_user(args: &CreateUserArgs, config: &AppConfig) -> miette::Result<()> {
+    if !args.email.contains('@') {
+        return Err(miette!("Invalid email: {}", args.email));
+    }
+    let password = read_password()?;
+    let password_hash = hash_password(&password);
+    let client = db::get_edgedb_client(config).await?;
+    let user = stores::user::create_user(&args.username, &args.email, &password_hash, args.superuser, args.role.clone(), &client)
+        .await
+        .map_err(|e| miette!("Failed to create user: {e}"))?;
+    println!("Created user {} ({})", user.username, user.id);
+    Ok(())
+}
+
+pub async fn change_password(args: &ChangePasswordArgs, config: &AppConfig) -> miette::Result<()> {
+    let client = db::get_edgedb_client(config).await?;
+    let users = stores::user::get_users_by_login(&args.user, &client)
+        .await
+        .map_err(|e| miette!("Failed to look up user: {e}"))?;
+    let user_id = match users.as_slice() {
+        [user] => user.id,
+        [] => return Err(miette!("No user with username or email {}", args.user)),
+        _ => {
+            let names: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+            return Err(miette!("{} is the username or email of more than one user: {}", args.user, names.join(", ")));
+        }
+    };
+    let password = read_password()?;
+    let password_hash = hash_password(&password);
+    let user = stores::user::set_password_by_id(user_id, &password_hash, &client)
+        .await
+        .map_err(|e| miette!("Failed to change password: {e}"))?
+        .ok_or_else(|| miette!("User {} was deleted meanwhile", args.user))?;
+    println!("Changed password of user {}", user.username);
+    Ok(())
+}
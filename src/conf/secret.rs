@@ -0,0 +1,93 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use miette::{miette, IntoDiagnostic};
+
+use super::gen_secret;
+
+// Secret keys persisted in a file, one per line. The first one is the current key,
+// the rest are old keys, which are still accepted when verifying existing sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretKeys {
+    pub current: String,
+    pub old: Vec<String>,
+}
+
+impl SecretKeys {
+    pub fn parse(content: &str) -> Option<Self> {
+        let mut keys = content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(String::from);
+        let current = keys.next()?;
+        Some(Self {
+            current,
+            old: keys.collect(),
+        })
+    }
+
+    fn to_file_content(&self) -> String {
+        let mut content = String::from("# Generated by \"quanweb gensecret\". First line is the current key.\n");
+        for key in std::iter::once(&self.current).chain(&self.old) {
+            content.push_str(key);
+            content.push('\n');
+        }
+        content
+    }
+}
+
+// Returns None if the file doesn't exist.
+pub fn read_secret_file(path: &Path) -> Result<Option<SecretKeys>, String> {
+    let metadata = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Cannot read {}: {e}", path.display())),
+    };
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!("{} must not be accessible by group or others, run: chmod 600 {}", path.display(), path.display()));
+    }
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+    SecretKeys::parse(&content)
+        .map(Some)
+        .ok_or_else(|| format!("{} has no key", path.display()))
+}
+
+// Write to a temporary file first, so that the server never sees a half-written file.
+fn write_secret_file(path: &Path, keys: &SecretKeys) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(keys.to_file_content().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+// Entry of "gensecret" command. With "rotate", the current key is kept as an old key,
+// and only "keep" old keys are retained.
+pub fn generate_secret_file(path: &Path, rotate: bool, keep: usize, force: bool) -> miette::Result<()> {
+    let existing = read_secret_file(path).map_err(|e| miette!("{e}"))?;
+    let keys = match existing {
+        Some(_) if !(rotate || force) => {
+            return Err(miette!(
+                "{} already exists. Use --rotate to replace the key while still accepting the old one, or --force to overwrite",
+                path.display()
+            ));
+        }
+        Some(SecretKeys { current, mut old }) if rotate => {
+            old.insert(0, current);
+            old.truncate(keep);
+            SecretKeys { current: gen_secret(), old }
+        }
+        _ => SecretKeys { current: gen_secret(), old: Vec::new() },
+    };
+    write_secret_file(path, &keys).into_diagnostic()?;
+    println!("Wrote new secret key to {} ({} old key(s) kept)", path.display(), keys.old.len());
+    Ok(())
+}
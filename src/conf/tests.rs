@@ -0,0 +1,68 @@
+use config::{Config, File};
+
+use super::secret::SecretKeys;
+use super::{AppConfig, CookieSameSite, MailBackendKind, DEFAULT_PORT, KEY_SECRET};
+
+const SECRET: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+#[test]
+fn app_config_applies_defaults() {
+    let config = Config::builder()
+        .set_override(KEY_SECRET, SECRET)
+        .and_then(|b| b.set_override("edgedb_instance", "QuanWeb"))
+        .and_then(|b| b.build())
+        .unwrap();
+    let app_config = AppConfig::from_config(&config).unwrap();
+    assert_eq!(app_config.port, DEFAULT_PORT);
+    assert_eq!(app_config.cookie.same_site, CookieSameSite::Strict);
+    assert!(app_config.unix_socket.is_none());
+    assert!(app_config.redis.url.is_none());
+    assert_eq!(app_config.mail.backend, MailBackendKind::Smtp);
+}
+
+// The shipped settings must be valid with only the secrets added, so that a fresh checkout runs.
+#[test]
+fn base_settings_are_valid() {
+    let config = Config::builder()
+        .add_source(File::with_name("base_settings.toml"))
+        .set_override(KEY_SECRET, SECRET)
+        .and_then(|b| b.set_override("edgedb_instance", "QuanWeb"))
+        .and_then(|b| b.build())
+        .unwrap();
+    let app_config = AppConfig::from_config(&config).unwrap();
+    assert!(app_config.mail.smtp.host.is_none());
+    assert!(app_config.password_reset.url.is_none());
+}
+
+#[test]
+fn app_config_reports_every_issue() {
+    let config = Config::builder()
+        .set_override(KEY_SECRET, "short")
+        .and_then(|b| b.set_override("port", "not-a-number"))
+        .and_then(|b| b.set_override("unix_socket_mode", "999"))
+        .and_then(|b| b.set_override("redis.url", "http://localhost"))
+        .and_then(|b| b.set_override("mail.smtp.username", "me"))
+        .and_then(|b| b.build())
+        .unwrap();
+    let diagnostic = AppConfig::from_config(&config).unwrap_err();
+    let keys: Vec<&str> = diagnostic.issues.iter().map(|i| i.key.as_str()).collect();
+    assert_eq!(
+        keys,
+        [
+            "secret_key",
+            "edgedb_instance",
+            "redis.url",
+            "port",
+            "unix_socket_mode",
+            "mail.smtp.password"
+        ]
+    );
+}
+
+#[test]
+fn secret_keys_parsing_skips_comments() {
+    let keys = SecretKeys::parse("# comment\nnew-key\n\nold-key-1\nold-key-2\n").unwrap();
+    assert_eq!(keys.current, "new-key");
+    assert_eq!(keys.old, ["old-key-1", "old-key-2"]);
+    assert!(SecretKeys::parse("# only comment\n").is_none());
+}
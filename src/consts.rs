@@ -2,3 +2,5 @@
 pub const DB_NAME: &str = "quanweb";
 pub const DEFAULT_PAGE_SIZE: u8 = 10;
 pub const STATIC_URL: &str = "/static";
+pub const HIGHLIGHT_CSS_URL: &str = "/highlight";
+pub const TOTP_ISSUER: &str = "QuanWeb";
@@ -1,35 +1,193 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use async_fred_session::RedisSessionStore;
-use config::Config;
-use edgedb_errors::kinds::ConfigurationError;
-use edgedb_errors::ErrorKind;
+use async_trait::async_trait;
+use axum_sessions::async_session::{MemoryStore, Result as SessionResult, Session, SessionStore};
 use edgedb_tokio::TlsSecurity;
-use fred::{error::RedisError, pool::RedisPool, types::RedisConfig};
+use fred::error::{RedisError, RedisErrorKind};
+use fred::interfaces::KeysInterface;
+use fred::{pool::RedisPool, types::{RedisConfig, ReconnectPolicy}};
 
-use crate::conf::KEY_EDGEDB_INSTANCE;
+use crate::conf::{AppConfig, ReconnectSettings, ReconnectStrategy, RedisSettings};
 use crate::consts::DB_NAME;
 
+const REDIS_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub async fn get_edgedb_client(
-    app_config: &Config,
+    app_config: &AppConfig,
 ) -> Result<edgedb_tokio::Client, edgedb_tokio::Error> {
     let mut builder = edgedb_tokio::Builder::new();
-    // Our EdgeDB is only available on localhost, so no need for TLS.
-    builder.tls_security(TlsSecurity::Insecure);
-    let instance_name = app_config.get_string(KEY_EDGEDB_INSTANCE).map_err(|e| {
-        tracing::error!("Missing EdgeDB instance name in config");
-        ConfigurationError::with_message(e.to_string())
-    })?;
-    tracing::info!("To connect to EdgeDB instance {}", instance_name);
-    builder.instance(&instance_name)?;
+    match (&app_config.edgedb_dsn, &app_config.edgedb_instance) {
+        (Some(dsn), _) => {
+            tracing::info!("To connect to EdgeDB via DSN");
+            builder.dsn(dsn.expose_secret())?;
+        }
+        (None, Some(instance_name)) => {
+            // Our local EdgeDB instance is only available on localhost, so no need for TLS.
+            builder.tls_security(TlsSecurity::Insecure);
+            tracing::info!("To connect to EdgeDB instance {}", instance_name);
+            builder.instance(instance_name)?;
+        }
+        // AppConfig validation guarantees one of them is set.
+        (None, None) => unreachable!("EdgeDB instance or DSN must be configured"),
+    }
     let config = builder.build_env().await?.with_database(DB_NAME)?;
     Ok(edgedb_tokio::Client::new(&config))
 }
 
-pub async fn get_redis_store() -> Result<RedisSessionStore, RedisError> {
-    let config = RedisConfig::default();
-    let pool = RedisPool::new(config, None, None, 2)?;
+fn get_reconnect_policy(settings: &ReconnectSettings) -> Option<ReconnectPolicy> {
+    let ReconnectSettings { max_attempts, min_delay_ms, max_delay_ms, .. } = *settings;
+    match settings.strategy {
+        ReconnectStrategy::Exponential => Some(ReconnectPolicy::new_exponential(max_attempts, min_delay_ms, max_delay_ms, 2)),
+        ReconnectStrategy::Linear => Some(ReconnectPolicy::new_linear(max_attempts, max_delay_ms, min_delay_ms)),
+        ReconnectStrategy::Constant => Some(ReconnectPolicy::new_constant(max_attempts, min_delay_ms)),
+        ReconnectStrategy::Never => None,
+    }
+}
+
+pub async fn get_redis_pool(url: &str, settings: &RedisSettings) -> Result<RedisPool, RedisError> {
+    let config = RedisConfig::from_url(url)?;
+    let policy = get_reconnect_policy(&settings.reconnect);
+    let pool = RedisPool::new(config, None, policy, settings.pool_size)?;
     pool.connect();
-    pool.wait_for_connect().await?;
+    // With a reconnect policy, the first connection is retried too, so we need a deadline.
+    tokio::time::timeout(REDIS_CONNECT_TIMEOUT, pool.wait_for_connect())
+        .await
+        .map_err(|_e| RedisError::new(RedisErrorKind::Timeout, "Timed out connecting to Redis"))??;
     tracing::debug!("Connected to Redis");
-    let store = RedisSessionStore::from_pool(pool, Some(format!("{}_axum:", DB_NAME)));
-    Ok(store)
+    Ok(pool)
+}
+
+// Sessions are kept in Redis if it is configured, otherwise in memory.
+#[derive(Debug, Clone)]
+pub enum SessionBackend {
+    Redis(RedisSessionStore),
+    Memory(MemoryStore),
+}
+
+impl SessionBackend {
+    pub fn new(pool: Option<RedisPool>, key_prefix: &str) -> Self {
+        match pool {
+            Some(pool) => Self::Redis(RedisSessionStore::from_pool(pool, Some(key_prefix.into()))),
+            None => Self::Memory(MemoryStore::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn load_session(&self, cookie_value: String) -> SessionResult<Option<Session>> {
+        match self {
+            Self::Redis(s) => s.load_session(cookie_value).await,
+            Self::Memory(s) => s.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> SessionResult<Option<String>> {
+        match self {
+            Self::Redis(s) => s.store_session(session).await,
+            Self::Memory(s) => s.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> SessionResult {
+        match self {
+            Self::Redis(s) => s.destroy_session(session).await,
+            Self::Memory(s) => s.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> SessionResult {
+        match self {
+            Self::Redis(s) => s.clear_store().await,
+            Self::Memory(s) => s.clear_store().await,
+        }
+    }
+}
+
+// Memory counters are pruned of expired entries when there are more than this.
+const MEMORY_COUNTERS_PRUNE_THRESHOLD: usize = 10_000;
+
+// Counters which are reset when their time window ends, for login lockout.
+// Kept in Redis if it is configured, so that they are shared by all app instances, otherwise in memory.
+#[derive(Debug, Clone)]
+pub enum CounterBackend {
+    Redis { pool: RedisPool, key_prefix: String },
+    Memory(Arc<Mutex<HashMap<String, (u64, Instant)>>>),
+}
+
+impl CounterBackend {
+    pub fn new(pool: Option<RedisPool>, key_prefix: &str) -> Self {
+        match pool {
+            Some(pool) => Self::Redis {
+                pool,
+                key_prefix: format!("{key_prefix}counter:"),
+            },
+            None => Self::Memory(Default::default()),
+        }
+    }
+
+    // Increase the counter, starting a new window if there is none.
+    // Return the count and the time left until the window ends.
+    pub async fn increment(&self, key: &str, window: Duration) -> Result<(u64, Duration), RedisError> {
+        match self {
+            Self::Redis { pool, key_prefix } => {
+                let key = format!("{key_prefix}{key}");
+                let count: u64 = pool.incr(&key).await?;
+                let mut ttl: i64 = pool.pttl(&key).await?;
+                // Also covers the key left without expiry if we failed between INCR and PEXPIRE before.
+                if ttl < 0 {
+                    let seconds = window.as_secs().max(1);
+                    pool.expire::<(), _>(&key, seconds as i64).await?;
+                    ttl = (seconds * 1000) as i64;
+                }
+                Ok((count, Duration::from_millis(ttl as u64)))
+            }
+            Self::Memory(counters) => {
+                let now = Instant::now();
+                let mut counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+                if counters.len() > MEMORY_COUNTERS_PRUNE_THRESHOLD {
+                    counters.retain(|_k, (_c, end)| *end > now);
+                }
+                let entry = counters.entry(key.into()).or_insert((0, now + window));
+                if entry.1 <= now {
+                    *entry = (0, now + window);
+                }
+                entry.0 += 1;
+                Ok((entry.0, entry.1 - now))
+            }
+        }
+    }
+
+    // Return None if the counter doesn't exist or its window has ended.
+    pub async fn get(&self, key: &str) -> Result<Option<(u64, Duration)>, RedisError> {
+        match self {
+            Self::Redis { pool, key_prefix } => {
+                let key = format!("{key_prefix}{key}");
+                let count: Option<u64> = pool.get(&key).await?;
+                let ttl: i64 = pool.pttl(&key).await?;
+                Ok(count.map(|c| (c, Duration::from_millis(ttl.max(0) as u64))))
+            }
+            Self::Memory(counters) => {
+                let now = Instant::now();
+                let counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+                Ok(counters
+                    .get(key)
+                    .filter(|(_c, end)| *end > now)
+                    .map(|(c, end)| (*c, *end - now)))
+            }
+        }
+    }
+
+    pub async fn reset(&self, key: &str) -> Result<(), RedisError> {
+        match self {
+            Self::Redis { pool, key_prefix } => pool.del::<u64, _>(format!("{key_prefix}{key}")).await.map(|_n| ()),
+            Self::Memory(counters) => {
+                counters.lock().unwrap_or_else(|e| e.into_inner()).remove(key);
+                Ok(())
+            }
+        }
+    }
 }
@@ -1,13 +1,16 @@
-use axum::{routing::get, Router};
+use axum::{middleware, Router};
 
 use crate::types::AppState;
-use crate::consts::STATIC_URL;
+use crate::consts::{HIGHLIGHT_CSS_URL, STATIC_URL};
+use crate::middlewares::http_metrics::track_http_metrics;
+use crate::utils::routing::{get, RouteTable};
 use super::views;
 
-pub fn get_router() -> Router<AppState> {
-    Router::new()
+pub fn route_table() -> RouteTable<AppState> {
+    RouteTable::new()
     .route("/", get(views::home))
     .route(&format!("{STATIC_URL}/*file"), get(views::static_handler))
+    .route(&format!("{HIGHLIGHT_CSS_URL}/:file"), get(views::highlight_css))
     .route("/post/:year/:month/:slug", get(views::blog::show_post))
     .route("/category/_uncategorized/", get(views::blog::list_uncategorized_posts))
     .route("/category/:category/", get(views::blog::list_posts))
@@ -16,3 +19,9 @@ pub fn get_router() -> Router<AppState> {
     .route("/talk/", get(views::old_urls::default_for_old_views))
     .route("/book/", get(views::old_urls::default_for_old_views))
 }
+
+pub fn get_router() -> Router<AppState> {
+    route_table()
+        .into_router()
+        .route_layer(middleware::from_fn(track_http_metrics))
+}
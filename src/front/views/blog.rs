@@ -12,6 +12,7 @@ use super::render_with;
 use crate::auth::Auth;
 use crate::consts::DEFAULT_PAGE_SIZE;
 use crate::errors::PageError;
+use crate::models::Permission;
 use crate::stores;
 use crate::stores::blog::{get_detailed_post_by_slug, get_next_post, get_previous_post};
 use crate::types::{AppState, Paginator};
@@ -19,26 +20,27 @@ use crate::types::{AppState, Paginator};
 pub async fn show_post(
     auth: Auth,
     Path((_y, _m, slug)): Path<(u16, u16, String)>,
-    _params: Query<PostPageParams>,
+    Query(params): Query<PostPageParams>,
     State(state): State<AppState>,
 ) -> AxumResult<Html<String>> {
-    let AppState { db, jinja } = state;
+    let AppState { db, jinja, .. } = state;
     let post = get_detailed_post_by_slug(slug, &db)
         .await
         .map_err(PageError::EdgeDBQueryError)?
         .ok_or((StatusCode::NOT_FOUND, "No post at this URL"))?;
     let user = auth.current_user;
     let no_tracking = !post.is_published.unwrap_or(false) || user.is_some();
-    let prev_post = get_previous_post(post.created_at, None, &db)
+    let prev_post = get_previous_post(post.created_at, params.cat.clone(), &db)
         .await
         .map_err(PageError::EdgeDBQueryError)?;
     tracing::debug!("Previous post: {:?}", prev_post);
-    let next_post = get_next_post(post.created_at, None, &db)
+    let next_post = get_next_post(post.created_at, params.cat.clone(), &db)
         .await
         .map_err(PageError::EdgeDBQueryError)?;
     tracing::debug!("Next post: {:?}", next_post);
     tracing::info!("Post: {:?}", post);
-    let context = context!(post => post, prev_post => prev_post, next_post => next_post, no_tracking => no_tracking);
+    // Keep prev/next links within the category which the reader came from.
+    let context = context!(post => post, prev_post => prev_post, next_post => next_post, cat => params.cat, no_tracking => no_tracking);
     let content = render_with("blog/post.jinja", context, jinja)?;
     Ok(Html(content))
 }
@@ -50,7 +52,7 @@ pub async fn list_posts(
     Query(paging): Query<LaxPaging>,
     State(state): State<AppState>,
 ) -> AxumResult<Html<String>> {
-    let AppState { db, jinja } = state;
+    let AppState { db, jinja, .. } = state;
     let current_page = paging
         .page
         .and_then(|p| NonZeroU16::new(p.parse().ok()?))
@@ -98,9 +100,11 @@ pub async fn preview_post(
     Path(id): Path<Uuid>,
     State(state): State<AppState>,
 ) -> AxumResult<Html<String>> {
-    let _user = auth.current_user.ok_or(PageError::PermissionDenied);
-    let AppState { db, jinja } = state;
-    let post = stores::blog::get_post(id, &db)
+    auth.current_user
+        .filter(|u| u.can(Permission::ViewDrafts))
+        .ok_or_else(|| PageError::PermissionDenied("Only logged-in users can preview posts".into()))?;
+    let AppState { db, jinja, .. } = state;
+    let post = stores::blog::get_post(id, false, &db)
         .await
         .map_err(PageError::EdgeDBQueryError)?
         .ok_or((StatusCode::NOT_FOUND, "No post at this URL"))?;
@@ -124,7 +128,7 @@ pub async fn list_uncategorized_posts(
     Query(paging): Query<LaxPaging>,
     State(state): State<AppState>,
 ) -> AxumResult<Html<String>> {
-    let AppState { db, jinja } = state;
+    let AppState { db, jinja, .. } = state;
     let current_page = paging
         .page
         .and_then(|p| NonZeroU16::new(p.parse().ok()?))
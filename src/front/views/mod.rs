@@ -2,11 +2,13 @@ pub mod blog;
 pub mod old_urls;
 
 use std::num::NonZeroU16;
+use std::time::Instant;
 
 use serde::ser::Serialize;
 use minijinja::Environment;
-use http::{StatusCode, Uri};
-use axum::extract::{Query, State, OriginalUri};
+use http::{HeaderMap, StatusCode, Uri};
+use http::header::{ACCEPT_ENCODING, CONTENT_TYPE};
+use axum::extract::{Path, Query, State, OriginalUri};
 use axum::response::{Html, IntoResponse, Result as AxumResult};
 use minijinja::context;
 use minijinja::value::Value as MJValue;
@@ -15,12 +17,16 @@ pub use crate::errors::PageError;
 use crate::auth::Auth;
 use crate::types::{AppState, Paginator, StaticFile};
 use super::structs::LaxPaging;
+use crate::monitoring;
 use crate::stores;
+use crate::utils::highlight;
 use crate::consts::{DEFAULT_PAGE_SIZE, STATIC_URL};
 
 pub fn render_with<S: Serialize>(template_name: &str, context: S, engine: Environment) -> Result<String, PageError> {
+    let started = Instant::now();
     let tpl = engine.get_template(template_name)?;
     let content = tpl.render(context)?;
+    monitoring::record_render(template_name, started.elapsed());
     Ok(content)
 }
 
@@ -36,7 +42,7 @@ pub async fn home(
     Query(paging): Query<LaxPaging>,
     State(state): State<AppState>,
 ) -> AxumResult<Html<String>> {
-    let AppState { db, jinja } = state;
+    let AppState { db, jinja, .. } = state;
     let current_page = paging
         .page
         .and_then(|p| NonZeroU16::new(p.parse().ok()?))
@@ -75,11 +81,18 @@ pub async fn home(
     Ok(Html(content))
 }
 
-pub async fn static_handler(uri: Uri) -> impl IntoResponse {
+pub async fn static_handler(uri: Uri, headers: HeaderMap) -> impl IntoResponse {
     // URI is like "/static/css/style.css", we need to strip to "css/style.css"
     let path = uri
         .path()
         .trim_start_matches(&format!("{STATIC_URL}/"))
         .to_string();
-    StaticFile(path)
+    StaticFile(path, headers.get(ACCEPT_ENCODING).cloned())
+}
+
+// Stylesheet for highlighted code blocks, like "/highlight/base16-ocean-dark.css".
+pub async fn highlight_css(Path(file): Path<String>) -> Result<impl IntoResponse, StatusCode> {
+    let theme = file.strip_suffix(".css").ok_or(StatusCode::NOT_FOUND)?;
+    let css = highlight::theme_css(theme).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(([(CONTENT_TYPE, "text/css; charset=utf-8")], css))
 }
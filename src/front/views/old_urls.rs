@@ -38,6 +38,6 @@ pub async fn redirect_old_blog_view(Path(rest): Path<String>, State(db): State<C
 }
 
 pub async fn default_for_old_views(State(jinja): State<Environment<'_>>) -> Result<Html<String>> {
-    let content = render_with("for_old_views.jinja", &(), jinja)?;
+    let content = render_with("for_old_views.jinja", (), jinja)?;
     Ok(Html(content))
 }
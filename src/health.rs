@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::Router;
+use axum::Json;
+use edgedb_tokio::Client as EdgeClient;
+use fred::interfaces::ClientLike;
+use fred::pool::RedisPool;
+use serde::Serialize;
+
+use crate::types::AppState;
+use crate::utils::routing::{get, RouteTable};
+
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DependencyStatus {
+    pub status: HealthStatus,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, DependencyStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_time: &'static str,
+}
+
+impl VersionInfo {
+    pub const CURRENT: Self = Self {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("QUANWEB_GIT_COMMIT"),
+        build_time: env!("QUANWEB_BUILD_TIME"),
+    };
+}
+
+async fn check_dependency<F, E>(probe: F) -> DependencyStatus
+where
+    F: Future<Output = Result<(), E>>,
+    E: ToString,
+{
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, probe).await;
+    let latency_ms = started.elapsed().as_millis();
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_elapsed) => Some(format!("No response after {:?}", CHECK_TIMEOUT)),
+    };
+    let status = if error.is_some() {
+        HealthStatus::Unavailable
+    } else {
+        HealthStatus::Ok
+    };
+    DependencyStatus {
+        status,
+        latency_ms,
+        error,
+    }
+}
+
+pub async fn check_edgedb(db: &EdgeClient) -> DependencyStatus {
+    check_dependency(async {
+        db.query_required_single::<i64, _>("SELECT 1", &()).await.map(|_v| ())
+    })
+    .await
+}
+
+pub async fn check_redis(pool: &RedisPool) -> DependencyStatus {
+    check_dependency(async { pool.ping::<String>().await.map(|_v| ()) }).await
+}
+
+pub async fn live() -> Json<HealthReport> {
+    Json(HealthReport {
+        status: HealthStatus::Ok,
+        checks: BTreeMap::new(),
+    })
+}
+
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let redis_check = async {
+        match &state.redis {
+            Some(pool) => Some(check_redis(pool).await),
+            None => None,
+        }
+    };
+    let (edgedb, redis) = tokio::join!(check_edgedb(&state.db), redis_check);
+    let mut checks = BTreeMap::from([("edgedb", edgedb)]);
+    // Without Redis, sessions are in memory and there is nothing to check.
+    if let Some(redis) = redis {
+        checks.insert("redis", redis);
+    }
+    let all_ok = checks.values().all(|c| c.status == HealthStatus::Ok);
+    let (code, status) = if all_ok {
+        (StatusCode::OK, HealthStatus::Ok)
+    } else {
+        tracing::warn!("Readiness check failed: {:?}", checks);
+        (StatusCode::SERVICE_UNAVAILABLE, HealthStatus::Unavailable)
+    };
+    (code, Json(HealthReport { status, checks }))
+}
+
+pub fn route_table() -> RouteTable<AppState> {
+    RouteTable::new()
+        .route("/live", get(live))
+        .route("/ready", get(ready))
+}
+
+pub fn get_router() -> Router<AppState> {
+    route_table().into_router()
+}
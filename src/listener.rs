@@ -0,0 +1,131 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::server::accept::Accept;
+use listenfd::ListenFd;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::conf::AppConfig;
+
+// The socket which the HTTP server accepts connections from.
+#[derive(Debug)]
+pub enum AppListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl AppListener {
+    // Precedence: socket passed by systemd (socket activation), then Unix domain socket path
+    // in config, then TCP address.
+    pub fn bind(config: &AppConfig) -> io::Result<Self> {
+        if let Some(listener) = Self::from_systemd()? {
+            return Ok(listener);
+        }
+        if let Some(path) = config.unix_socket.clone() {
+            return Self::bind_unix(path, config.unix_socket_mode);
+        }
+        let addr = SocketAddr::new(config.bind, config.port);
+        TcpListener::bind(addr).map(Self::Tcp)
+    }
+
+    // Ref: https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+    fn from_systemd() -> io::Result<Option<Self>> {
+        let mut listenfd = ListenFd::from_env();
+        if listenfd.len() == 0 {
+            return Ok(None);
+        }
+        if listenfd.len() > 1 {
+            tracing::warn!("Received {} sockets from systemd, only the first one is used", listenfd.len());
+        }
+        // If the socket is not TCP, it is left in place for the next attempt.
+        if let Ok(Some(listener)) = listenfd.take_tcp_listener(0) {
+            tracing::info!("Use TCP socket inherited from systemd");
+            return Ok(Some(Self::Tcp(listener)));
+        }
+        let listener = listenfd.take_unix_listener(0)?.map(|l| {
+            tracing::info!("Use Unix domain socket inherited from systemd");
+            l.set_nonblocking(true)?;
+            // The socket file is owned by systemd, we must not delete it.
+            UnixListener::from_std(l).map(|l| Self::Unix(l, None))
+        });
+        listener.transpose()
+    }
+
+    fn bind_unix(path: PathBuf, mode: Option<u32>) -> io::Result<Self> {
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(Self::Unix(listener, Some(path)))
+    }
+
+    // The socket file we created, which should be removed after the server stops.
+    pub fn created_socket_file(&self) -> Option<PathBuf> {
+        match self {
+            Self::Unix(_l, path) => path.clone(),
+            Self::Tcp(_l) => None,
+        }
+    }
+}
+
+pub fn remove_socket_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        tracing::warn!("Failed to remove socket file {}: {}", path.display(), e);
+    }
+}
+
+impl fmt::Display for AppListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(l) => match l.local_addr() {
+                Ok(addr) => write!(f, "http://{addr}"),
+                Err(_e) => write!(f, "TCP socket"),
+            },
+            Self::Unix(l, _) => match l.local_addr().ok().and_then(|a| a.as_pathname().map(PathBuf::from)) {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unnamed Unix domain socket"),
+            },
+        }
+    }
+}
+
+// A socket file left by previous run prevents us from binding, so we delete it.
+// Other kind of files are not touched.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            tracing::debug!("Remove stale socket file {}", path.display());
+            fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Adapter to let Hyper accept connections from Unix domain socket.
+pub struct UnixAccept(pub UnixListener);
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|r| Some(r.map(|(stream, _addr)| stream)))
+    }
+}
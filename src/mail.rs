@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use thiserror::Error;
+
+use crate::conf::{MailBackendKind, MailSettings, SmtpSecurity, SmtpSettings};
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    // Plain text
+    pub body: String,
+}
+
+impl Mail {
+    // Minimal RFC 5322 message, enough for mail clients to open the .eml file.
+    fn to_message(&self, from: &str) -> String {
+        let date = Utc::now().to_rfc2822();
+        format!(
+            "From: {from}\r\nTo: {}\r\nSubject: {}\r\nDate: {date}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            self.to,
+            self.subject,
+            self.body.replace('\n', "\r\n"),
+        )
+    }
+
+    fn to_lettre_message(&self, from: &Mailbox) -> Result<Message, MailError> {
+        let message = Message::builder()
+            .from(from.clone())
+            .to(self.to.parse()?)
+            .subject(&self.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())?;
+        Ok(message)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("Cannot write mail file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid mail address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("Cannot build mail: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+// Where mails go. New ways of delivery are added as variants.
+#[derive(Debug, Clone)]
+pub enum MailSender {
+    Smtp {
+        from: Mailbox,
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+    },
+    Log { from: String },
+    File { from: String, dir: PathBuf },
+}
+
+impl MailSender {
+    // None when the "smtp" backend has no host, then nothing can be mailed.
+    pub fn from_settings(settings: &MailSettings) -> Result<Option<Self>, MailError> {
+        let from = settings.from.clone();
+        let sender = match settings.backend {
+            MailBackendKind::Smtp => {
+                let Some(host) = &settings.smtp.host else {
+                    tracing::warn!("No mail.smtp.host, mails cannot be sent");
+                    return Ok(None);
+                };
+                Self::Smtp {
+                    from: from.parse()?,
+                    transport: build_smtp_transport(host, &settings.smtp)?,
+                }
+            }
+            MailBackendKind::Log => {
+                tracing::warn!("Mails are only written to the log, this is for development");
+                Self::Log { from }
+            }
+            MailBackendKind::File => Self::File {
+                from,
+                dir: settings.file_dir.clone(),
+            },
+        };
+        Ok(Some(sender))
+    }
+
+    pub async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        match self {
+            Self::Smtp { from, transport } => {
+                transport.send(mail.to_lettre_message(from)?).await?;
+                tracing::info!("Sent mail to {}", mail.to);
+            }
+            Self::Log { from } => {
+                tracing::info!("Mail to send:\n{}", mail.to_message(from));
+            }
+            Self::File { from, dir } => {
+                tokio::fs::create_dir_all(dir).await?;
+                let name = format!("{}.eml", Utc::now().format("%Y%m%d-%H%M%S-%f"));
+                let path = dir.join(name);
+                tokio::fs::write(&path, mail.to_message(from)).await?;
+                tracing::info!("Saved mail to {} as {}", mail.to, path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Port is the usual one of "security" (587, 465, 25) if not set.
+fn build_smtp_transport(host: &str, settings: &SmtpSettings) -> Result<AsyncSmtpTransport<Tokio1Executor>, MailError> {
+    let mut builder = match settings.security {
+        SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    };
+    builder = builder.timeout(Some(Duration::from_secs(settings.timeout)));
+    if let Some(port) = settings.port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.expose_secret().clone()));
+    }
+    Ok(builder.build())
+}
@@ -1,26 +1,37 @@
 mod api;
 mod auth;
 mod cli;
+mod commands;
 mod conf;
 mod consts;
 mod db;
 mod errors;
 mod front;
+mod health;
+mod listener;
+mod mail;
+mod middlewares;
 mod models;
+mod monitoring;
+mod rerender;
+mod shutdown;
 mod stores;
 mod types;
 mod utils;
 
 use std::env;
 use std::io;
-use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use faccess::PathExt;
+use axum::middleware;
 use axum::routing::Router;
 use axum_login::{axum_sessions::SessionLayer, AuthLayer};
 use clap::Parser;
 use miette::{miette, IntoDiagnostic};
-use minijinja::{path_loader, Environment};
+use minijinja::{context, path_loader, Environment};
+use tokio_util::task::TaskTracker;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{
     filter::{EnvFilter, LevelFilter},
@@ -28,19 +39,36 @@ use tracing_subscriber::{
     util::SubscriberInitExt,
 };
 
+use auth::rotation::{resign_session_cookie, upgrade_session_auth, SecretRotation, SESSION_COOKIE_NAME};
+use auth::lockout::LoginLockout;
 use auth::store::EdgeDbStore;
-use cli::AppOptions;
+use auth::passwords::ResetTokens;
+use auth::tokens::authenticate_bearer;
+use mail::MailSender;
+use monitoring::MeteredSessionStore;
+use cli::{AppOptions, Command, GenSecretArgs, HighlightCssArgs};
+use conf::{AppConfig, LogFormat, LoggingSettings};
+use db::{CounterBackend, SessionBackend};
+use listener::{AppListener, UnixAccept};
+use middlewares::request_id::{scope_request_id, X_REQUEST_ID};
+use middlewares::stats::{track_requests, RequestStats};
+use middlewares::csrf::{csrf_protect, CsrfGuard};
+use middlewares::security_headers::{set_security_headers, SecurityHeaders};
+use middlewares::rate_limit::{rate_limit, RateLimiter};
+use middlewares::trace::RequestSampler;
+use shutdown::DrainOutcome;
 use types::AppState;
 use utils::jinja_extra;
 
 const TEMPLATE_DIR: &str = "minijinja";
 
-fn config_jinja() -> Result<Environment<'static>, io::Error> {
+pub fn config_jinja() -> Result<Environment<'static>, io::Error> {
     let mut jinja = Environment::new();
     jinja.add_filter("debug_value", jinja_extra::debug_value);
     jinja.add_function("post_detail_url", jinja_extra::post_detail_url);
     jinja.add_function("gen_element_attr", jinja_extra::gen_element_attr);
     jinja.add_function("add_url_param", jinja_extra::add_url_param);
+    jinja.add_function("csp_nonce", jinja_extra::csp_nonce);
     jinja.add_filter("striptags", jinja_extra::striptags);
     #[cfg(debug_assertions)]
     jinja.add_global("running_locally", true);
@@ -52,13 +80,13 @@ fn config_jinja() -> Result<Environment<'static>, io::Error> {
     Ok(jinja)
 }
 
-fn config_logging(app_opt: AppOptions) {
+fn config_logging(verbose: u8, settings: &LoggingSettings) -> miette::Result<()> {
     // If run by "cargo run", we want to see debug logs.
     let run_by_cargo = env::var("CARGO").is_ok();
     let level = if run_by_cargo {
         LevelFilter::DEBUG
     } else {
-        match app_opt.verbose {
+        match verbose {
             0 => LevelFilter::WARN,
             1 => LevelFilter::INFO,
             2 => LevelFilter::DEBUG,
@@ -70,35 +98,161 @@ fn config_logging(app_opt: AppOptions) {
         .with_default_directive(LevelFilter::WARN.into())
         .parse(command_directives)
         .unwrap();
+    let (text_layer, json_layer) = match settings.format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json())),
+    };
+    #[cfg(feature = "otlp")]
+    let otlp_layer = settings
+        .otlp_endpoint
+        .as_deref()
+        .map(monitoring::otlp_layer)
+        .transpose()
+        .map_err(|e| miette!("Failed to set up OTLP exporter: {e}"))?;
+    #[cfg(not(feature = "otlp"))]
+    let otlp_layer: Option<tracing_subscriber::layer::Identity> = None;
     tracing_subscriber::registry()
         .with(filter)
-        .with(tracing_subscriber::fmt::layer())
+        .with(text_layer)
+        .with(json_layer)
+        .with(otlp_layer)
         .init();
+    #[cfg(not(feature = "otlp"))]
+    if settings.otlp_endpoint.is_some() {
+        tracing::warn!("otlp_endpoint is set, but the program is built without \"otlp\" feature");
+    }
+    Ok(())
+}
+
+// Only needs the secret file location, so the rest of config doesn't have to be valid yet.
+fn gen_secret(args: &GenSecretArgs) -> miette::Result<()> {
+    let path = match &args.output {
+        Some(path) => path.clone(),
+        None => conf::get_config()
+            .and_then(|c| c.get::<PathBuf>(conf::KEY_SECRET_FILE))
+            .unwrap_or_else(|_e| PathBuf::from(conf::DEFAULT_SECRET_FILE)),
+    };
+    conf::secret::generate_secret_file(&path, args.rotate, args.keep, args.force)
+}
+
+fn print_highlight_css(args: &HighlightCssArgs) -> miette::Result<()> {
+    if args.list {
+        for name in utils::highlight::theme_names() {
+            println!("{name:<24} {}", utils::highlight::stylesheet_url(name));
+        }
+        return Ok(());
+    }
+    let theme = args.theme.as_deref().unwrap_or_default();
+    let css = utils::highlight::theme_css(theme)
+        .ok_or_else(|| miette!(help = "Run with --list to see available themes", "Unknown theme {theme:?}"))?;
+    print!("{css}");
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> miette::Result<()> {
-    let app_opts = cli::AppOptions::parse();
-    config_logging(app_opts);
-    let redis_store = db::get_redis_store()
-        .await
-        .map_err(|_e| miette!("Error connecting to Redis"))?;
+    let AppOptions { verbose, command } = AppOptions::parse();
+    let command = command.unwrap_or(Command::Serve);
+    // These commands don't need a valid config.
+    match &command {
+        Command::Gensecret(args) => return gen_secret(args),
+        Command::Routes => {
+            commands::routes::print_routes();
+            return Ok(());
+        }
+        Command::Check => return commands::check::run_checks().await,
+        Command::Highlightcss(args) => return print_highlight_css(args),
+        _ => {}
+    }
+    // Fail early, before anything is started, if config is invalid.
+    let config = AppConfig::load()?;
+    config_logging(verbose, &config.logging)?;
+    utils::markdown::configure(&config);
+    match command {
+        Command::Createuser(args) => commands::user::create_user(&args, &config).await,
+        Command::Changepassword(args) => commands::user::change_password(&args, &config).await,
+        Command::Rerender(args) => commands::rerender::rerender_posts(&args, &config).await,
+        _ => serve(config).await,
+    }
+}
 
-    let config = conf::get_config().map_err(|e| miette!("Error loading config: {e}"))?;
-    let secret_bytes =
-        conf::get_secret_bytes(&config).map_err(|e| miette!("Error getting secret bytes: {e}"))?;
+async fn serve(config: AppConfig) -> miette::Result<()> {
+    if config.secret_is_ephemeral {
+        tracing::warn!("No persisted secret key, users will be logged out on restart. Run \"quanweb gensecret\" to create one");
+    }
+    for dir in [&config.cache_dir, &config.upload_dir] {
+        std::fs::create_dir_all(dir).map_err(|e| miette!("Failed to create {}: {e}", dir.display()))?;
+    }
+    let redis_pool = match &config.redis.url {
+        Some(url) => Some(
+            db::get_redis_pool(url.expose_secret(), &config.redis)
+                .await
+                .map_err(|e| miette!("Error connecting to Redis: {e}"))?,
+        ),
+        None => {
+            tracing::warn!("Redis is not configured, sessions are kept in memory and lost on restart");
+            None
+        }
+    };
+    let session_store = MeteredSessionStore(SessionBackend::new(redis_pool.clone(), &config.redis.key_prefix));
+    let metrics_handle = monitoring::install_recorder().into_diagnostic()?;
+
+    let secret_bytes = config.secret_bytes();
     let client = db::get_edgedb_client(&config).await?;
-    let jinja = config_jinja().into_diagnostic()?;
+    let mut jinja = config_jinja().into_diagnostic()?;
+    let highlight = &config.markdown.highlight;
+    jinja.add_global(
+        "highlight_css",
+        context! {
+            light => utils::highlight::stylesheet_url(&highlight.theme),
+            dark => highlight.dark_theme.as_deref().filter(|t| !t.is_empty()).map(utils::highlight::stylesheet_url),
+        },
+    );
+    let mailer = MailSender::from_settings(&config.mail).into_diagnostic()?;
+    let reset_tokens = ResetTokens::new(&secret_bytes, &config.password_reset);
+    if mailer.is_none() || reset_tokens.is_none() {
+        tracing::warn!("Password reset is disabled, it needs mail.smtp.host (or another mail backend) and password_reset.url");
+    }
     let app_state = AppState {
         db: client.clone(),
+        redis: redis_pool.clone(),
         jinja,
+        tasks: TaskTracker::new(),
+        rerender: Default::default(),
+        lockout: LoginLockout::new(
+            CounterBackend::new(redis_pool.clone(), &config.redis.key_prefix),
+            config.login_lockout.clone(),
+        ),
+        mailer,
+        reset_tokens,
     };
-    let session_layer = SessionLayer::new(redis_store, &secret_bytes).with_secure(false);
-    let user_store: EdgeDbStore<models::User> = EdgeDbStore::new(client);
-    let auth_layer = AuthLayer::new(user_store, &secret_bytes);
+    let tasks = app_state.tasks.clone();
+    let rate_limiter = RateLimiter::new(
+        CounterBackend::new(redis_pool.clone(), &config.redis.key_prefix),
+        config.rate_limit.clone(),
+    );
+    let request_stats = RequestStats::new();
+    let request_sampler = RequestSampler::from_settings(&config.logging);
+    let compression_layer =
+        middlewares::compression::build_compression_layer(&config.compression);
+    let mut session_layer = SessionLayer::new(session_store, &secret_bytes)
+        .with_cookie_name(SESSION_COOKIE_NAME)
+        .with_secure(config.cookie.secure)
+        .with_same_site_policy(config.cookie.same_site.into());
+    if let Some(domain) = &config.cookie.domain {
+        session_layer = session_layer.with_cookie_domain(domain);
+    }
+    let user_store: EdgeDbStore<models::User> = EdgeDbStore::new(client.clone());
+    let auth_layer = AuthLayer::new(user_store.clone(), &secret_bytes);
+    let secret_rotation = SecretRotation::new(secret_bytes, config.old_secret_bytes(), user_store);
 
     let home_router: Router<AppState> = front::routes::get_router();
-    let api_router: Router<AppState> = api::get_router().with_state(app_state.clone());
+    // Bearer tokens are only accepted by API. The layer must be inside the auth layer.
+    let api_router: Router<AppState> = api::get_router()
+        .with_state(app_state.clone())
+        .layer(middleware::from_fn_with_state(client.clone(), authenticate_bearer));
+    // Health checks are outside of session and auth layers, to not touch the session store.
+    let health_router: Router = health::get_router().with_state(app_state.clone());
 
     let app = Router::new()
         .merge(home_router)
@@ -106,17 +260,85 @@ async fn main() -> miette::Result<()> {
         .fallback(front::views::fallback_view)
         .with_state(app_state)
         .layer(auth_layer)
+        .layer(middleware::from_fn_with_state(secret_rotation.clone(), upgrade_session_auth))
         .layer(session_layer)
-        .layer(TraceLayer::new_for_http());
-
-    let port = conf::get_listening_port(&config);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!("Listening on http://{}", addr);
+        .layer(middleware::from_fn_with_state(secret_rotation, resign_session_cookie))
+        .nest("/_health", health_router)
+        .merge(monitoring::get_router(metrics_handle, config.metrics_token.clone()))
+        // Outside of session layer, so that rejected requests don't touch the session store.
+        .layer(middleware::from_fn_with_state(CsrfGuard::new(config.csrf.clone()), csrf_protect))
+        .layer(middleware::from_fn_with_state(rate_limiter, rate_limit))
+        .layer(compression_layer)
+        // Outside of CSRF and rate limit layers, so that their error responses get the headers too.
+        .layer(middleware::from_fn_with_state(SecurityHeaders::new(&config.security_headers), set_security_headers))
+        .layer(middleware::from_fn(scope_request_id))
+        .layer(PropagateRequestIdLayer::new(X_REQUEST_ID))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_sampler.clone())
+                .on_request(request_sampler.clone())
+                .on_response(request_sampler),
+        )
+        .layer(SetRequestIdLayer::new(X_REQUEST_ID, MakeRequestUuid))
+        .layer(middleware::from_fn_with_state(request_stats.clone(), track_requests));
 
-    // TODO: Support Unix domain socket with hyperlocal
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
-    Ok(())
+    let shutdown_timeout = config.shutdown_timeout;
+    let listener = AppListener::bind(&config).into_diagnostic()?;
+    tracing::info!("Listening on {}", listener);
+    let socket_file = listener.created_socket_file();
+    let service = app.into_make_service();
+    let shutdown_signal = shutdown::spawn_signal_listener();
+    let stop_accepting = shutdown_signal.token().cancelled_owned();
+    let served = match listener {
+        AppListener::Tcp(l) => {
+            let server = axum::Server::from_tcp(l)
+                .into_diagnostic()?
+                .serve(service)
+                .with_graceful_shutdown(stop_accepting);
+            shutdown::serve_with_deadline(server, shutdown_signal.clone(), shutdown_timeout).await
+        }
+        AppListener::Unix(l, _) => {
+            let server = axum::Server::builder(UnixAccept(l))
+                .serve(service)
+                .with_graceful_shutdown(stop_accepting);
+            shutdown::serve_with_deadline(server, shutdown_signal.clone(), shutdown_timeout).await
+        }
+    };
+    if let Some(path) = socket_file {
+        listener::remove_socket_file(&path);
+    }
+    let aborted_requests = request_stats.in_flight();
+    if served.as_ref().is_ok_and(|o| *o == DrainOutcome::TimedOut) {
+        tracing::warn!("Timed out waiting for {} in-flight requests", aborted_requests);
+    }
+    // Let background jobs finish, with what is left of the time budget.
+    tasks.close();
+    let deadline = shutdown_signal.deadline(shutdown_timeout);
+    let tasks_finished = tokio::time::timeout_at(deadline, tasks.wait()).await.is_ok();
+    if !tasks_finished {
+        tracing::warn!("Timed out waiting for {} background tasks", tasks.len());
+    }
+    // Handlers of timed-out requests keep running in their connection tasks, and may still use Redis.
+    // Their connections are closed anyway when the process exits.
+    let still_running = request_stats.in_flight() + tasks.len();
+    match redis_pool {
+        Some(pool) if still_running == 0 => {
+            pool.quit_pool().await;
+            tracing::debug!("Closed Redis connections");
+        }
+        Some(_pool) => tracing::debug!("Leave Redis connections to {} unfinished requests and tasks", still_running),
+        None => {}
+    }
+    // EdgeDB client has no explicit close, its connections are closed when the last clone is dropped.
+    drop(client);
+    tracing::info!(
+        "Server stopped after {:?}. Served {} requests, aborted {}, unfinished background tasks: {}",
+        request_stats.uptime(),
+        request_stats.total(),
+        aborted_requests,
+        tasks.len(),
+    );
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+    served.map(|_o| ()).into_diagnostic()
 }
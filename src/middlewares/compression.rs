@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use axum::body::HttpBody as Body;
+use http::header::CONTENT_TYPE;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+
+use crate::conf::CompressionSettings;
+
+// Server-sent events are flushed by event, which compression would hold back in its buffer.
+const STREAMING_CONTENT_TYPES: [&str; 1] = ["text/event-stream"];
+
+// Skip small responses, streams and the content types which are already compressed.
+// Note that CompressionLayer never touches a response which has "content-encoding" header,
+// so the pre-compressed static files are passed through as is.
+#[derive(Debug, Clone)]
+pub struct CompressionPredicate {
+    size_above: SizeAbove,
+    excluded_content_types: Arc<[String]>,
+}
+
+impl CompressionPredicate {
+    pub fn new(min_size: u16, excluded_content_types: Vec<String>) -> Self {
+        Self {
+            size_above: SizeAbove::new(min_size),
+            excluded_content_types: excluded_content_types.into(),
+        }
+    }
+}
+
+impl Predicate for CompressionPredicate {
+    fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+    where
+        B: Body,
+    {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let excluded = STREAMING_CONTENT_TYPES
+            .iter()
+            .copied()
+            .chain(self.excluded_content_types.iter().map(String::as_str))
+            .any(|t| content_type.starts_with(t));
+        !excluded && self.size_above.should_compress(response)
+    }
+}
+
+pub fn parse_compression_level(level: &str) -> CompressionLevel {
+    match level.trim().to_lowercase().as_str() {
+        "fastest" => CompressionLevel::Fastest,
+        "best" => CompressionLevel::Best,
+        "default" | "" => CompressionLevel::Default,
+        s => s.parse().map(CompressionLevel::Precise).unwrap_or_else(|_e| {
+            tracing::warn!("Unknown compression level {}, fallback to default", s);
+            CompressionLevel::Default
+        }),
+    }
+}
+
+pub fn build_compression_layer(settings: &CompressionSettings) -> CompressionLayer<CompressionPredicate> {
+    let algorithms: Vec<String> = settings.algorithms.iter().map(|a| a.to_lowercase()).collect();
+    let enabled = |name: &str| algorithms.iter().any(|a| a == name);
+    for unknown in algorithms.iter().filter(|a| !["br", "zstd", "gzip"].contains(&a.as_str())) {
+        tracing::warn!("Unsupported compression algorithm: {}", unknown);
+    }
+    let predicate = CompressionPredicate::new(settings.min_size, settings.excluded_content_types.clone());
+    CompressionLayer::new()
+        .br(enabled("br"))
+        .zstd(enabled("zstd"))
+        .gzip(enabled("gzip"))
+        .no_deflate()
+        .quality(parse_compression_level(&settings.level))
+        .compress_when(predicate)
+}
@@ -0,0 +1,84 @@
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, Request, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header::{AUTHORIZATION, HOST, ORIGIN, REFERER};
+
+use crate::api::errors::ApiError;
+use crate::conf::CsrfSettings;
+
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+// Cookies are sent by the browser even when the request is made by another site's page.
+// Browsers tell where the request comes from, by Sec-Fetch-Site, or at least Origin (Referer in older ones).
+// Those headers cannot be set by the page, so we don't need tokens in forms.
+#[derive(Debug, Clone)]
+pub struct CsrfGuard {
+    settings: CsrfSettings,
+}
+
+impl CsrfGuard {
+    pub fn new(settings: CsrfSettings) -> Self {
+        Self { settings }
+    }
+
+    fn is_trusted(&self, origin: &str, headers: &HeaderMap) -> bool {
+        if self.settings.trusted_origins.iter().any(|o| o == origin) {
+            return true;
+        }
+        // Origin is "scheme://host[:port]", and Host header is "host[:port]".
+        let host = headers.get(HOST).and_then(|v| v.to_str().ok());
+        let authority = origin.split_once("://").map(|(_scheme, rest)| rest);
+        host.is_some() && authority == host
+    }
+
+    // Return the reason to refuse the request, if any.
+    pub fn check(&self, method: &Method, headers: &HeaderMap) -> Result<(), String> {
+        if !self.settings.enabled || matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE) {
+            return Ok(());
+        }
+        // Pages of other sites cannot set this header without our CORS consent.
+        let has_bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .is_some_and(|(scheme, _token)| scheme.eq_ignore_ascii_case("bearer"));
+        if has_bearer {
+            return Ok(());
+        }
+        let origin = headers.get(ORIGIN).and_then(|v| v.to_str().ok()).map(|o| o.trim().to_string());
+        let fetch_site = headers.get(SEC_FETCH_SITE).and_then(|v| v.to_str().ok());
+        match fetch_site {
+            // "none" is when user opens the URL directly.
+            Some("same-origin" | "none") => return Ok(()),
+            // A sibling subdomain is still another site to us, unless trusted.
+            Some(site) => {
+                return match origin {
+                    Some(o) if self.is_trusted(&o, headers) => Ok(()),
+                    _ => Err(format!("request from {site} page")),
+                };
+            }
+            None => {}
+        }
+        // Older browsers, only Origin or Referer.
+        let origin = origin.filter(|o| o != "null").or_else(|| {
+            let referer: Uri = headers.get(REFERER)?.to_str().ok()?.parse().ok()?;
+            Some(format!("{}://{}", referer.scheme_str()?, referer.authority()?))
+        });
+        match origin {
+            Some(o) if !self.is_trusted(&o, headers) => Err(format!("origin {o} is not allowed")),
+            Some(_) => Ok(()),
+            // Not from a browser, like scripts, which don't carry cookies of a visited site.
+            None if headers.contains_key(ORIGIN) => Err("opaque origin".into()),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn csrf_protect<B>(State(guard): State<CsrfGuard>, request: Request<B>, next: Next<B>) -> Response {
+    if let Err(reason) = guard.check(request.method(), request.headers()) {
+        tracing::warn!("Refused {} {}: {reason}", request.method(), request.uri().path());
+        return ApiError::CsrfRejected(reason).into_response();
+    }
+    next.run(request).await
+}
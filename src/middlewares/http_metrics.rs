@@ -0,0 +1,27 @@
+use std::time::Instant;
+
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics::{histogram, increment_counter};
+
+use crate::monitoring::{HTTP_REQUESTS_TOTAL, HTTP_REQUEST_DURATION};
+
+// Must be added with "route_layer", so that MatchedPath is available. Labeling by the route pattern,
+// instead of the actual path, keeps the number of time series bounded.
+pub async fn track_http_metrics<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".into());
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("route", route), ("status", status)];
+    increment_counter!(HTTP_REQUESTS_TOTAL, &labels);
+    histogram!(HTTP_REQUEST_DURATION, started.elapsed(), &labels);
+    response
+}
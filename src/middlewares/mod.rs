@@ -0,0 +1,10 @@
+pub mod compression;
+pub mod csrf;
+pub mod http_metrics;
+pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
+pub mod stats;
+pub mod trace;
+#[cfg(test)]
+pub mod tests;
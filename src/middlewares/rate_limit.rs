@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hyper::body::HttpBody;
+use http::header::CONTENT_LENGTH;
+use serde::Deserialize;
+
+use crate::api::errors::ApiError;
+use crate::conf::{RateLimitRule, RateLimitSettings};
+use crate::db::CounterBackend;
+use crate::types::ApiErrorShape;
+
+const MAX_EMAIL_BODY_SIZE: usize = 16 * 1024;
+const X_REAL_IP: &str = "x-real-ip";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    counters: CounterBackend,
+    settings: RateLimitSettings,
+}
+
+#[derive(Deserialize)]
+struct EmailField {
+    email: Option<String>,
+}
+
+impl RateLimiter {
+    pub fn new(counters: CounterBackend, settings: RateLimitSettings) -> Self {
+        Self { counters, settings }
+    }
+
+    // Return the seconds to wait if the limit is exceeded. Counter errors let the request through.
+    async fn hit(&self, key: &str, rule: &RateLimitRule) -> Option<u64> {
+        match self.counters.increment(key, Duration::from_secs(rule.window)).await {
+            Ok((count, ttl)) => (count > rule.limit).then(|| ttl.as_secs_f64().ceil().max(1.0) as u64),
+            Err(e) => {
+                tracing::error!("Cannot count requests for {key}: {e}");
+                None
+            }
+        }
+    }
+}
+
+// With one reverse proxy in front, the last address in X-Forwarded-For is the one it saw,
+// the earlier ones are given by the client and can be forged.
+pub fn client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let forwarded = headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    forwarded.or_else(|| headers.get(X_REAL_IP)?.to_str().ok()?.trim().parse().ok())
+}
+
+pub async fn rate_limit(State(limiter): State<RateLimiter>, request: Request<Body>, next: Next<Body>) -> Response {
+    if !limiter.settings.enabled {
+        return next.run(request).await;
+    }
+    let path = request.uri().path().to_string();
+    let groups: Vec<_> = limiter
+        .settings
+        .groups
+        .iter()
+        .filter(|(_name, g)| g.paths.iter().any(|p| path.starts_with(p.as_str())))
+        .collect();
+    if groups.is_empty() {
+        return next.run(request).await;
+    }
+    let ip = limiter
+        .settings
+        .trust_forwarded_for
+        .then(|| client_ip(request.headers()))
+        .flatten()
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let needs_email = groups.iter().any(|(_name, g)| g.per_email.is_some());
+    let (request, email) = if needs_email {
+        match read_email(request).await {
+            Ok(r) => r,
+            Err(response) => return response,
+        }
+    } else {
+        (request, None)
+    };
+    for (name, group) in groups {
+        let mut checks = Vec::with_capacity(2);
+        if let Some(rule) = &group.per_ip {
+            checks.push((format!("ratelimit:{name}:ip:{ip}"), rule));
+        }
+        if let (Some(rule), Some(email)) = (&group.per_email, &email) {
+            checks.push((format!("ratelimit:{name}:email:{email}"), rule));
+        }
+        for (key, rule) in checks {
+            if let Some(retry_after) = limiter.hit(&key, rule).await {
+                tracing::info!("Rate limit of {name} exceeded for {key}");
+                return ApiError::TooManyRequests {
+                    message: "Too many requests, please slow down".into(),
+                    retry_after,
+                }
+                .into_response();
+            }
+        }
+    }
+    next.run(request).await
+}
+
+// Take the "email" field from JSON body, then put the body back for the handler.
+// Paths limited per email are small forms, so big bodies are refused, rather than let through uncounted.
+async fn read_email(request: Request<Body>) -> Result<(Request<Body>, Option<String>), Response> {
+    let too_large = || {
+        let resp = ApiErrorShape::from("Request body is too large".to_string());
+        (StatusCode::PAYLOAD_TOO_LARGE, Json(resp)).into_response()
+    };
+    let declared_size = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if declared_size.is_some_and(|s| s > MAX_EMAIL_BODY_SIZE) {
+        return Err(too_large());
+    }
+    let (parts, mut body) = request.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::Other(format!("Cannot read request body: {e}")).into_response())?;
+        if bytes.len() + chunk.len() > MAX_EMAIL_BODY_SIZE {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let email = serde_json::from_slice::<EmailField>(&bytes)
+        .ok()
+        .and_then(|f| f.email)
+        .map(|e| e.trim().to_lowercase())
+        .filter(|e| !e.is_empty());
+    Ok((Request::from_parts(parts, Body::from(bytes)), email))
+}
@@ -0,0 +1,32 @@
+use axum::http::{HeaderName, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_http::request_id::RequestId;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// ID of the request being handled by current task, to be included in error payloads.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+pub fn get_request_id<B>(request: &Request<B>) -> Option<&str> {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.header_value())
+        .or_else(|| request.headers().get(X_REQUEST_ID))
+        .and_then(|v| v.to_str().ok())
+}
+
+// Must be inside SetRequestIdLayer, which generates the ID if client (Nginx) doesn't send one.
+pub async fn scope_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    match get_request_id(&request).map(String::from) {
+        Some(id) => REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
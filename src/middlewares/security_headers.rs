@@ -0,0 +1,110 @@
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use http::header::{
+    CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY_REPORT_ONLY, CONTENT_TYPE, REFERRER_POLICY,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::conf::SecurityHeadersSettings;
+
+const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+const NONCE_BYTES: usize = 16;
+
+tokio::task_local! {
+    static CSP_NONCE: String;
+}
+
+// Nonce of the request being handled by current task, for templates to allow their inline scripts.
+pub fn current_csp_nonce() -> Option<String> {
+    CSP_NONCE.try_with(|nonce| nonce.clone()).ok()
+}
+
+pub fn generate_nonce() -> Option<String> {
+    let mut bytes = [0u8; NONCE_BYTES];
+    SystemRandom::new().fill(&mut bytes).ok()?;
+    Some(base64::encode(bytes))
+}
+
+// Header values are built once. Only the CSP changes per request, because of the nonce.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    static_headers: Vec<(HeaderName, HeaderValue)>,
+    csp_header: HeaderName,
+    csp_template: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub fn new(settings: &SecurityHeadersSettings) -> Self {
+        let mut static_headers = vec![];
+        if settings.enabled {
+            static_headers.push((X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")));
+            if settings.hsts_max_age > 0 {
+                let mut value = format!("max-age={}", settings.hsts_max_age);
+                if settings.hsts_include_subdomains {
+                    value.push_str("; includeSubDomains");
+                }
+                static_headers.extend(HeaderValue::from_str(&value).ok().map(|v| (STRICT_TRANSPORT_SECURITY, v)));
+            }
+            let policies = [
+                (REFERRER_POLICY, &settings.referrer_policy),
+                (PERMISSIONS_POLICY, &settings.permissions_policy),
+            ];
+            // Empty value is to not send the header.
+            for (name, value) in policies.into_iter().filter(|(_n, v)| !v.is_empty()) {
+                static_headers.extend(HeaderValue::from_str(value).ok().map(|v| (name, v)));
+            }
+        }
+        let csp_header = if settings.csp_report_only {
+            CONTENT_SECURITY_POLICY_REPORT_ONLY
+        } else {
+            CONTENT_SECURITY_POLICY
+        };
+        let csp_template = Some(settings.content_security_policy.clone()).filter(|p| settings.enabled && !p.is_empty());
+        Self {
+            static_headers,
+            csp_header,
+            csp_template,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, nonce: &str) {
+        // Handlers may set their own, like for a page embedding third-party widgets.
+        for (name, value) in &self.static_headers {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        let is_html = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/html"));
+        let Some(template) = self.csp_template.as_ref().filter(|_| is_html) else {
+            return;
+        };
+        if headers.contains_key(&self.csp_header) {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&template.replace("{nonce}", nonce)) {
+            headers.insert(self.csp_header.clone(), value);
+        }
+    }
+}
+
+// Must wrap the handlers, for templates to read the nonce when being rendered.
+pub async fn set_security_headers<B>(
+    State(security_headers): State<SecurityHeaders>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Without a nonce, the CSP still goes out, so inline scripts are blocked rather than allowed.
+    let nonce = generate_nonce().unwrap_or_else(|| {
+        tracing::error!("Failed to generate CSP nonce");
+        String::new()
+    });
+    let mut response = CSP_NONCE.scope(nonce.clone(), next.run(request)).await;
+    security_headers.apply(response.headers_mut(), &nonce);
+    response
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+
+// Counters of served requests, to report when the server shuts down.
+#[derive(Debug, Clone)]
+pub struct RequestStats(Arc<RequestStatsInner>);
+
+#[derive(Debug)]
+struct RequestStatsInner {
+    started_at: Instant,
+    total: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl RequestStats {
+    pub fn new() -> Self {
+        Self(Arc::new(RequestStatsInner {
+            started_at: Instant::now(),
+            total: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }))
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.0.started_at.elapsed()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.total.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RequestStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Decrease the in-flight count even if the request future is dropped midway.
+struct InFlightGuard(RequestStats);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0 .0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn track_requests<B>(
+    State(stats): State<RequestStats>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    stats.0.total.fetch_add(1, Ordering::Relaxed);
+    stats.0.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _guard = InFlightGuard(stats);
+    next.run(request).await
+}
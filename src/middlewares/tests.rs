@@ -0,0 +1,175 @@
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use axum::body::Body;
+use axum::response::Html;
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use indexmap::IndexMap;
+use tower::ServiceExt;
+use tower_http::compression::predicate::Predicate;
+use tower_http::compression::CompressionLevel;
+
+use super::compression::{parse_compression_level, CompressionPredicate};
+use super::csrf::CsrfGuard;
+use super::rate_limit::{client_ip, rate_limit, RateLimiter};
+use super::security_headers::{current_csp_nonce, set_security_headers, SecurityHeaders};
+use crate::conf::{CsrfSettings, RateLimitGroup, RateLimitRule, RateLimitSettings, SecurityHeadersSettings};
+use crate::db::CounterBackend;
+use super::trace::RequestSampler;
+
+fn make_response(content_type: &str, length: usize) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, length)
+        .body(Body::from("x".repeat(length)))
+        .unwrap()
+}
+
+#[test]
+fn compress_html_above_min_size() {
+    let predicate = CompressionPredicate::new(1024, vec!["image/png".into()]);
+    assert!(predicate.should_compress(&make_response("text/html; charset=utf-8", 2048)));
+    assert!(!predicate.should_compress(&make_response("text/html; charset=utf-8", 100)));
+}
+
+#[test]
+fn not_compress_excluded_content_types() {
+    let predicate = CompressionPredicate::new(10, vec!["image/png".into(), "font/woff".into()]);
+    assert!(!predicate.should_compress(&make_response("image/png", 2048)));
+    assert!(!predicate.should_compress(&make_response("font/woff2", 2048)));
+    assert!(predicate.should_compress(&make_response("image/svg+xml", 2048)));
+    // Always, because events must reach the client without waiting for more.
+    assert!(!predicate.should_compress(&make_response("text/event-stream", 2048)));
+}
+
+#[test]
+fn parse_compression_levels() {
+    assert!(matches!(parse_compression_level("Best"), CompressionLevel::Best));
+    assert!(matches!(parse_compression_level("fastest"), CompressionLevel::Fastest));
+    assert!(matches!(parse_compression_level("6"), CompressionLevel::Precise(6)));
+    assert!(matches!(parse_compression_level("foo"), CompressionLevel::Default));
+}
+
+#[test]
+fn sample_requests_to_noisy_paths() {
+    let sampler = RequestSampler::new(vec!["/_health/".into()], 3);
+    let logged: Vec<bool> = (0..6).map(|_| sampler.should_log("/_health/live")).collect();
+    assert_eq!(logged, vec![true, false, false, true, false, false]);
+    assert!((0..3).all(|_| sampler.should_log("/post/2023/07/hello")));
+}
+
+#[test]
+fn client_ip_from_proxy_headers() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(client_ip(&headers), None);
+    headers.insert("x-real-ip", "10.0.0.2".parse().unwrap());
+    assert_eq!(client_ip(&headers), Some("10.0.0.2".parse().unwrap()));
+    // The last hop, added by our proxy, wins over what the client sent.
+    headers.insert("x-forwarded-for", "1.2.3.4, 203.0.113.7".parse().unwrap());
+    assert_eq!(client_ip(&headers), Some("203.0.113.7".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn rate_limit_by_ip_and_email() {
+    let settings = RateLimitSettings {
+        groups: IndexMap::from([(
+            "login".into(),
+            RateLimitGroup {
+                paths: vec!["/login".into()],
+                per_ip: Some(RateLimitRule { limit: 3, window: 60 }),
+                per_email: Some(RateLimitRule { limit: 2, window: 60 }),
+            },
+        )]),
+        ..Default::default()
+    };
+    let limiter = RateLimiter::new(CounterBackend::new(None, "test:"), settings);
+    let app = Router::new()
+        .route("/login", post(|body: String| async move { body }))
+        .route("/other", post(|| async { "ok" }))
+        .layer(middleware::from_fn_with_state(limiter, rate_limit));
+    let send = |path: &'static str, ip: &'static str, email: &'static str| {
+        let request = Request::post(path)
+            .header("x-forwarded-for", ip)
+            .body(Body::from(format!(r#"{{"email": "{email}"}}"#)))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+    let response = send("/login", "1.1.1.1", "a@b.c").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // The handler still gets the body.
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&body[..], br#"{"email": "a@b.c"}"#);
+    assert_eq!(send("/login", "2.2.2.2", "A@b.c").await.unwrap().status(), StatusCode::OK);
+    let response = send("/login", "3.3.3.3", "a@b.c").await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(RETRY_AFTER));
+    assert_eq!(send("/login", "1.1.1.1", "x@b.c").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send("/login", "1.1.1.1", "y@b.c").await.unwrap().status(), StatusCode::OK);
+    assert_eq!(send("/login", "1.1.1.1", "z@b.c").await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(send("/other", "1.1.1.1", "z@b.c").await.unwrap().status(), StatusCode::OK);
+}
+
+#[test]
+fn csrf_refuses_cross_site_writes() {
+    let guard = CsrfGuard::new(CsrfSettings {
+        enabled: true,
+        trusted_origins: vec!["https://admin.example.com".into()],
+    });
+    let headers = |pairs: &[(&'static str, &str)]| {
+        let mut map = HeaderMap::new();
+        map.insert("host", "quan.hoabinh.vn".parse().unwrap());
+        for (k, v) in pairs {
+            map.insert(*k, v.parse().unwrap());
+        }
+        map
+    };
+    let evil = headers(&[("sec-fetch-site", "cross-site"), ("origin", "https://evil.com")]);
+    assert!(guard.check(&Method::POST, &evil).is_err());
+    // Reading is always allowed
+    assert!(guard.check(&Method::GET, &evil).is_ok());
+    // Cookies are not used with bearer token
+    let with_token = headers(&[("sec-fetch-site", "cross-site"), ("authorization", "Bearer qwt_abc")]);
+    assert!(guard.check(&Method::DELETE, &with_token).is_ok());
+    assert!(guard.check(&Method::POST, &headers(&[("sec-fetch-site", "same-origin")])).is_ok());
+    let trusted = headers(&[("sec-fetch-site", "same-site"), ("origin", "https://admin.example.com")]);
+    assert!(guard.check(&Method::PATCH, &trusted).is_ok());
+    // Browsers without Sec-Fetch-Site
+    assert!(guard.check(&Method::POST, &headers(&[("origin", "https://quan.hoabinh.vn")])).is_ok());
+    assert!(guard.check(&Method::POST, &headers(&[("origin", "https://evil.com")])).is_err());
+    assert!(guard.check(&Method::POST, &headers(&[("origin", "null")])).is_err());
+    assert!(guard.check(&Method::POST, &headers(&[("referer", "https://evil.com/page")])).is_err());
+    assert!(guard.check(&Method::POST, &headers(&[("referer", "https://quan.hoabinh.vn/_admin/")])).is_ok());
+    // Not a browser
+    assert!(guard.check(&Method::POST, &headers(&[])).is_ok());
+}
+
+#[tokio::test]
+async fn security_headers_with_csp_nonce() {
+    let security_headers = SecurityHeaders::new(&SecurityHeadersSettings::default());
+    let app = Router::new()
+        .route("/page", get(|| async { Html(current_csp_nonce().unwrap_or_default()) }))
+        .route("/api", get(|| async { "{}" }))
+        .layer(middleware::from_fn_with_state(security_headers, set_security_headers));
+    let fetch = |path: &'static str| app.clone().oneshot(Request::get(path).body(Body::empty()).unwrap());
+    let mut nonces = vec![];
+    for _ in 0..2 {
+        let response = fetch("/page").await.unwrap();
+        let headers = response.headers().clone();
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+        assert!(headers.contains_key("referrer-policy"));
+        assert!(headers.contains_key("permissions-policy"));
+        let csp = headers["content-security-policy"].to_str().unwrap().to_string();
+        let script_src = csp.split("; ").find(|d| d.starts_with("script-src")).unwrap();
+        assert!(!script_src.contains("'unsafe-inline'"));
+        // Template sees the same nonce as in the header.
+        let nonce = String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+        assert!(!nonce.is_empty());
+        assert!(csp.contains(&format!("'nonce-{nonce}'")));
+        nonces.push(nonce);
+    }
+    assert_ne!(nonces[0], nonces[1]);
+    let response = fetch("/api").await.unwrap();
+    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    assert!(!response.headers().contains_key("content-security-policy"));
+}
@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{Request, Response};
+use tower_http::trace::{MakeSpan, OnRequest, OnResponse};
+use tracing::Span;
+
+use super::request_id::get_request_id;
+use crate::conf::LoggingSettings;
+
+// Decide which requests to log. Requests to noisy paths (health check, static files)
+// are logged once for every "sample_every" requests. The skipped ones get a disabled span,
+// and OnRequest, OnResponse also stay silent for them.
+#[derive(Debug, Clone)]
+pub struct RequestSampler {
+    sampled_paths: Arc<[String]>,
+    sample_every: u32,
+    counter: Arc<AtomicU32>,
+}
+
+impl RequestSampler {
+    pub fn new(sampled_paths: Vec<String>, sample_every: u32) -> Self {
+        Self {
+            sampled_paths: sampled_paths.into(),
+            sample_every,
+            counter: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn from_settings(settings: &LoggingSettings) -> Self {
+        Self::new(settings.sampled_paths.clone(), settings.sample_every)
+    }
+
+    pub fn should_log(&self, path: &str) -> bool {
+        if self.sample_every <= 1 || !self.sampled_paths.iter().any(|p| path.starts_with(p.as_str())) {
+            return true;
+        }
+        self.counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_every)
+    }
+}
+
+impl<B> MakeSpan<B> for RequestSampler {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        if !self.should_log(request.uri().path()) {
+            return Span::none();
+        }
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            request_id = get_request_id(request).unwrap_or_default(),
+        )
+    }
+}
+
+impl<B> OnRequest<B> for RequestSampler {
+    fn on_request(&mut self, _request: &Request<B>, span: &Span) {
+        if !span.is_disabled() {
+            tracing::debug!(parent: span, "Started processing request");
+        }
+    }
+}
+
+impl<B> OnResponse<B> for RequestSampler {
+    fn on_response(self, response: &Response<B>, latency: Duration, span: &Span) {
+        if !span.is_disabled() {
+            tracing::info!(
+                parent: span,
+                status = response.status().as_u16(),
+                latency_ms = latency.as_millis() as u64,
+                "Finished processing request"
+            );
+        }
+    }
+}
@@ -49,6 +49,20 @@ impl From<DocFormat> for EValue {
     }
 }
 
+// One heading of a post, for the table of contents. "id" is the anchor of the heading in rendered HTML.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TocEntry {
+    pub level: u8,
+    pub id: String,
+    pub text: String,
+}
+
+// Just enough of a BlogPost to check who may change it.
+#[derive(Debug, Queryable)]
+pub struct BlogPostOwner {
+    pub author_id: Option<Uuid>,
+}
+
 // Struct to represent a BlogPost in the database, but with just enough fields to display in a list.
 #[serde_with::apply(
     EDatetime => #[serde(serialize_with = "serialize_edge_datetime")],
@@ -68,7 +82,7 @@ pub struct MediumBlogPost {
 }
 
 impl MediumBlogPost {
-    pub fn type_cast_for_field<'a>(name: &'a str) -> &'a str {
+    pub fn type_cast_for_field(name: &str) -> &str {
         match name {
             "title" => "str",
             "slug" => "str",
@@ -179,11 +193,15 @@ pub struct DetailedBlogPost {
     pub html: Option<String>,
     pub seo_description: Option<String>,
     pub og_image: Option<String>,
+    // Stored as JSON in database.
+    #[edgedb(json)]
+    pub toc: Vec<TocEntry>,
+    pub show_toc: bool,
 }
 
 #[allow(dead_code)]
 impl DetailedBlogPost {
-    pub fn type_cast_for_field<'a>(name: &'a str) -> &'a str {
+    pub fn type_cast_for_field(name: &str) -> &str {
         match name {
             "title" => "str",
             "slug" => "str",
@@ -195,6 +213,7 @@ impl DetailedBlogPost {
             "html" => "str",
             "seo_description" => "str",
             "og_image" => "str",
+            "show_toc" => "bool",
             _ => "str",
         }
     }
@@ -219,6 +238,8 @@ impl Default for DetailedBlogPost {
             html: None,
             seo_description: None,
             og_image: None,
+            toc: Vec::default(),
+            show_toc: true,
         }
     }
 }
@@ -233,7 +254,7 @@ impl StructObject for DetailedBlogPost {
             "published_at" => self.published_at.map(edge_datetime_to_jinja),
             "created_at" => Some(edge_datetime_to_jinja(self.created_at)),
             "updated_at" => self.updated_at.map(edge_datetime_to_jinja),
-            "categories" => Some(self.categories.clone().into_iter().map(|c| MJValue::from_struct_object(c)).collect()),
+            "categories" => Some(self.categories.clone().into_iter().map(MJValue::from_struct_object).collect()),
             "body" => self.body.clone().map(MJValue::from),
             "format" => Some(MJValue::from(self.format.to_string())),
             "locale" => self.locale.clone().map(MJValue::from),
@@ -241,6 +262,8 @@ impl StructObject for DetailedBlogPost {
             "html" => self.html.clone().map(MJValue::from),
             "seo_description" => self.seo_description.clone().map(MJValue::from),
             "og_image" => self.og_image.clone().map(MJValue::from),
+            "toc" => Some(MJValue::from_serializable(&self.toc)),
+            "show_toc" => Some(MJValue::from(self.show_toc)),
             _ => None,
         }
     }
@@ -262,12 +285,14 @@ impl StructObject for DetailedBlogPost {
                 "html",
                 "seo_description",
                 "og_image",
+                "toc",
+                "show_toc",
             ][..],
         )
     }
 
     fn field_count(&self) -> usize {
-        15
+        17
     }
 }
 
@@ -294,3 +319,12 @@ pub struct MiniBlogPost {
     pub slug: String,
     pub created_at: EDatetime,
 }
+
+// Just the fields needed to render a post again.
+#[derive(Debug, Queryable)]
+pub struct PostSource {
+    pub id: Uuid,
+    pub title: String,
+    pub body: Option<String>,
+    pub format: DocFormat,
+}
@@ -1,8 +1,8 @@
 pub mod users;
 pub mod blogs;
 
-pub use users::{User, Role};
-pub use blogs::{DocFormat, MediumBlogPost, DetailedBlogPost, BlogCategory};
+pub use users::{AccessToken, User, Role, Permission, TokenOwner, TwoFactorState, UserInfo};
+pub use blogs::{DocFormat, MediumBlogPost, DetailedBlogPost, BlogCategory, TocEntry};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, edgedb_derive::Queryable)]
 pub struct MinimalObject {
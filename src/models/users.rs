@@ -1,8 +1,12 @@
+use edgedb_protocol::model::Datetime as EDatetime;
 use uuid::Uuid;
 
 use axum_login::{AuthUser, secrecy::SecretVec};
 use edgedb_derive::Queryable;
 use serde::{Serialize, Deserialize};
+use strum_macros::{Display, EnumString, IntoStaticStr};
+
+use crate::types::conversions::{serialize_edge_datetime, serialize_optional_edge_datetime};
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, Queryable)]
 pub struct User {
@@ -13,13 +17,104 @@ pub struct User {
     pub password: String,
     pub is_active: bool,
     pub is_superuser: bool,
+    // Stored role. Superusers are always admins, regardless of it.
+    pub role: Role,
+}
+
+impl User {
+    pub fn effective_role(&self) -> Role {
+        if self.is_superuser {
+            Role::Admin
+        } else {
+            self.role.clone()
+        }
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.effective_role().permissions().contains(&permission)
+    }
+}
+
+// User as shown to administrators, with profile fields and without password hash.
+#[derive(Debug, Clone, Serialize, Queryable)]
+pub struct UserInfo {
+    pub id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub is_active: bool,
+    pub is_superuser: bool,
+    pub role: Role,
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+// Variants are ordered by privilege, so that role ranges (like `Role::Editor..`) make sense.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    EnumString,
+    Display,
+    IntoStaticStr,
+    Serialize,
+    Deserialize,
+    Queryable,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(ascii_case_insensitive)]
 pub enum Role {
+    Viewer,
+    #[default]
+    Author,
+    Editor,
     Admin,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Permission {
+    // Preview unpublished posts.
+    ViewDrafts,
+    CreatePost,
+    EditOwnPost,
+    EditAnyPost,
+    ManageCategories,
+    RerenderPosts,
+    ManageUsers,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Self::Viewer => &[ViewDrafts],
+            Self::Author => &[ViewDrafts, CreatePost, EditOwnPost],
+            Self::Editor => &[ViewDrafts, CreatePost, EditOwnPost, EditAnyPost, ManageCategories],
+            Self::Admin => &[
+                ViewDrafts,
+                CreatePost,
+                EditOwnPost,
+                EditAnyPost,
+                ManageCategories,
+                RerenderPosts,
+                ManageUsers,
+            ],
+        }
+    }
+}
+
+impl From<Role> for edgedb_protocol::value::Value {
+    fn from(role: Role) -> Self {
+        let v: &str = role.into();
+        Self::Enum(v.into())
+    }
+}
+
 impl AuthUser<Uuid, Role> for User {
     fn get_id(&self) -> Uuid {
         self.id
@@ -30,6 +125,39 @@ impl AuthUser<Uuid, Role> for User {
     }
 
     fn get_role(&self) -> Option<Role> {
-        Some(Role::Admin)
+        Some(self.effective_role())
     }
 }
+
+// Personal access token, without the secret part.
+#[serde_with::apply(
+    EDatetime => #[serde(serialize_with = "serialize_edge_datetime")],
+    Option<EDatetime> => #[serde(serialize_with = "serialize_optional_edge_datetime")],
+)]
+#[derive(Debug, Clone, Serialize, Queryable)]
+pub struct AccessToken {
+    pub id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: EDatetime,
+    pub expires_at: Option<EDatetime>,
+    pub last_used_at: Option<EDatetime>,
+}
+
+// The user who a bearer token belongs to, with what the token is allowed to do.
+#[derive(Debug, Clone, Queryable)]
+pub struct TokenOwner {
+    pub scopes: Vec<String>,
+    pub user: User,
+}
+
+// TOTP enrolment of a user. The secret is kept while enrolment is pending, but only checked once enabled.
+#[derive(Debug, Default, Clone, Queryable)]
+pub struct TwoFactorState {
+    pub secret: Option<String>,
+    pub enabled: bool,
+    // Hashes of unused recovery codes.
+    pub recovery_codes: Vec<String>,
+    pub last_step: Option<i64>,
+}
@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::Router;
+use axum_sessions::async_session::{Result as SessionResult, Session, SessionStore};
+use metrics::{describe_counter, describe_histogram, histogram, increment_counter};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+use redact::Secret;
+use ring::constant_time::verify_slices_are_equal;
+
+use crate::utils::routing::{get, RouteTable};
+
+pub const HTTP_REQUESTS_TOTAL: &str = "http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+pub const EDGEDB_QUERY_DURATION: &str = "edgedb_query_duration_seconds";
+pub const EDGEDB_QUERY_ERRORS_TOTAL: &str = "edgedb_query_errors_total";
+pub const SESSION_OPERATION_DURATION: &str = "redis_session_operation_duration_seconds";
+pub const SESSION_OPERATION_ERRORS_TOTAL: &str = "redis_session_operation_errors_total";
+pub const TEMPLATE_RENDER_DURATION: &str = "template_render_duration_seconds";
+pub const LOGIN_ATTEMPTS_TOTAL: &str = "login_attempts_total";
+
+// Latency buckets (in seconds), from 1ms to 10s.
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new().set_buckets(&BUCKETS)?.install_recorder()?;
+    describe_counter!(HTTP_REQUESTS_TOTAL, "Number of HTTP requests, by route and status");
+    describe_histogram!(HTTP_REQUEST_DURATION, "HTTP request latency, by route and status");
+    describe_histogram!(EDGEDB_QUERY_DURATION, "EdgeDB query duration, by store function");
+    describe_counter!(EDGEDB_QUERY_ERRORS_TOTAL, "Number of failed EdgeDB queries, by store function");
+    describe_histogram!(SESSION_OPERATION_DURATION, "Redis session store operation duration");
+    describe_counter!(SESSION_OPERATION_ERRORS_TOTAL, "Number of failed Redis session store operations");
+    describe_histogram!(TEMPLATE_RENDER_DURATION, "Template render time, by template name");
+    describe_counter!(LOGIN_ATTEMPTS_TOTAL, "Number of login attempts, by result");
+    Ok(handle)
+}
+
+#[derive(Clone)]
+pub struct MetricsState {
+    pub handle: PrometheusHandle,
+    pub token: Secret<String>,
+}
+
+pub fn has_metrics_token(headers: &HeaderMap, token: &Secret<String>) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _t)| scheme.eq_ignore_ascii_case("bearer"))
+        .is_some_and(|(_s, given)| verify_slices_are_equal(given.trim().as_bytes(), token.expose_secret().as_bytes()).is_ok())
+}
+
+pub async fn render_metrics(State(state): State<MetricsState>, headers: HeaderMap) -> impl IntoResponse {
+    if !has_metrics_token(&headers, &state.token) {
+        return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], state.handle.render()).into_response()
+}
+
+pub fn route_table() -> RouteTable<MetricsState> {
+    RouteTable::new().route("/metrics", get(render_metrics))
+}
+
+// Metrics tell about traffic and internals, so they are only served to who has the token.
+pub fn get_router(handle: PrometheusHandle, token: Option<Secret<String>>) -> Router {
+    let Some(token) = token else {
+        tracing::info!("No metrics token is set, /metrics is not served");
+        return Router::new();
+    };
+    route_table().into_router().with_state(MetricsState { handle, token })
+}
+
+// Measure a query run by functions in "stores" module.
+pub async fn timed_query<F, T, E>(name: &'static str, query: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let started = Instant::now();
+    let result = query.await;
+    histogram!(EDGEDB_QUERY_DURATION, started.elapsed(), "query" => name);
+    if result.is_err() {
+        increment_counter!(EDGEDB_QUERY_ERRORS_TOTAL, "query" => name);
+    }
+    result
+}
+
+pub fn record_render(template_name: &str, elapsed: Duration) {
+    histogram!(TEMPLATE_RENDER_DURATION, elapsed, "template" => template_name.to_string());
+}
+
+pub fn record_login(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    increment_counter!(LOGIN_ATTEMPTS_TOTAL, "result" => result);
+}
+
+// Wrap a session store to measure its operations.
+#[derive(Debug, Clone)]
+pub struct MeteredSessionStore<S>(pub S);
+
+impl<S> MeteredSessionStore<S> {
+    async fn observe<F, T>(operation: &'static str, fut: F) -> SessionResult<T>
+    where
+        F: Future<Output = SessionResult<T>>,
+    {
+        let started = Instant::now();
+        let result = fut.await;
+        histogram!(SESSION_OPERATION_DURATION, started.elapsed(), "operation" => operation);
+        if result.is_err() {
+            increment_counter!(SESSION_OPERATION_ERRORS_TOTAL, "operation" => operation);
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl<S: SessionStore> SessionStore for MeteredSessionStore<S> {
+    async fn load_session(&self, cookie_value: String) -> SessionResult<Option<Session>> {
+        Self::observe("load", self.0.load_session(cookie_value)).await
+    }
+
+    async fn store_session(&self, session: Session) -> SessionResult<Option<String>> {
+        Self::observe("store", self.0.store_session(session)).await
+    }
+
+    async fn destroy_session(&self, session: Session) -> SessionResult {
+        Self::observe("destroy", self.0.destroy_session(session)).await
+    }
+
+    async fn clear_store(&self) -> SessionResult {
+        Self::observe("clear", self.0.clear_store()).await
+    }
+}
+
+#[cfg(feature = "otlp")]
+pub fn otlp_layer<S>(
+    endpoint: &str,
+) -> Result<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry::sdk::trace::Tracer>, opentelemetry::trace::TraceError>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+
+    let exporter = opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint);
+    let resource = Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+
+use edgedb_tokio::Client;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::stores;
+use crate::utils::markdown::{render_post_blocking, RENDERER_VERSION};
+
+pub const BATCH_SIZE: i64 = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    #[default]
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RerenderFailure {
+    pub id: Uuid,
+    pub title: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RerenderProgress {
+    pub status: JobStatus,
+    pub renderer_version: i16,
+    // Number of posts to be re-rendered, counted when the job starts.
+    pub total: usize,
+    pub processed: usize,
+    pub updated: usize,
+    // Posts without body, or in a format we cannot render.
+    pub skipped: usize,
+    pub failures: Vec<RerenderFailure>,
+    // Set if the job stopped midway.
+    pub error: Option<String>,
+}
+
+// Progress of the latest job run from API, shared between the job and the status endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct RerenderTracker(Arc<Mutex<Option<RerenderProgress>>>);
+
+impl RerenderTracker {
+    pub fn get(&self) -> Option<RerenderProgress> {
+        self.0.lock().map(|p| p.clone()).unwrap_or_default()
+    }
+
+    // Return false if a job is already running.
+    pub fn try_start(&self) -> bool {
+        let Ok(mut progress) = self.0.lock() else {
+            return false;
+        };
+        if progress.as_ref().is_some_and(|p| p.status == JobStatus::Running) {
+            return false;
+        }
+        *progress = Some(RerenderProgress {
+            renderer_version: RENDERER_VERSION,
+            ..Default::default()
+        });
+        true
+    }
+
+    pub fn update(&self, latest: &RerenderProgress) {
+        if let Ok(mut progress) = self.0.lock() {
+            *progress = Some(latest.clone());
+        }
+    }
+}
+
+// Re-render posts whose HTML was generated by an older renderer, or all posts if "all" is true.
+// "on_batch" is called after each batch, to report progress.
+pub async fn rerender_posts<F>(client: &Client, all: bool, mut on_batch: F) -> RerenderProgress
+where
+    F: FnMut(&RerenderProgress),
+{
+    let below_version = if all { i16::MAX } else { RENDERER_VERSION };
+    let mut progress = RerenderProgress {
+        renderer_version: RENDERER_VERSION,
+        ..Default::default()
+    };
+    match stores::blog::count_posts_below_renderer_version(below_version, client).await {
+        Ok(total) => progress.total = total,
+        Err(e) => return fail(progress, e),
+    }
+    on_batch(&progress);
+    let mut after = Uuid::nil();
+    loop {
+        let posts = match stores::blog::get_post_sources_after(after, BATCH_SIZE, below_version, client).await {
+            Ok(posts) => posts,
+            Err(e) => return fail(progress, e),
+        };
+        let Some(last) = posts.last() else {
+            break;
+        };
+        after = last.id;
+        for post in posts {
+            progress.processed += 1;
+            let Some(body) = post.body else {
+                progress.skipped += 1;
+                continue;
+            };
+            let rendered = match render_post_blocking(body, post.format).await {
+                Ok(Some(rendered)) => rendered,
+                Ok(None) => {
+                    progress.skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to re-render post {}: {e}", post.id);
+                    progress.failures.push(RerenderFailure {
+                        id: post.id,
+                        title: post.title,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            match stores::blog::update_post_rendering(post.id, rendered, RENDERER_VERSION, client).await {
+                Ok(()) => progress.updated += 1,
+                Err(e) => {
+                    tracing::warn!("Failed to re-render post {}: {e}", post.id);
+                    progress.failures.push(RerenderFailure {
+                        id: post.id,
+                        title: post.title,
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        on_batch(&progress);
+    }
+    progress.status = JobStatus::Finished;
+    tracing::info!(
+        "Re-rendered {} posts, skipped {}, failed {}",
+        progress.updated,
+        progress.skipped,
+        progress.failures.len()
+    );
+    progress
+}
+
+fn fail(mut progress: RerenderProgress, error: edgedb_tokio::Error) -> RerenderProgress {
+    tracing::error!("Re-render job stopped: {error}");
+    progress.status = JobStatus::Failed;
+    progress.error = Some(error.to_string());
+    progress
+}
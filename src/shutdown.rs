@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainOutcome {
+    Completed,
+    TimedOut,
+}
+
+// Resolve when we receive SIGTERM (from systemd) or SIGINT (Ctrl-C).
+pub async fn wait_for_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => tracing::info!("Received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("Received SIGINT"),
+    }
+}
+
+// Shutdown request, with the time it was received, so that all steps of shutting down
+// share one time budget, counted from the signal.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSignal {
+    token: CancellationToken,
+    received_at: Arc<OnceLock<Instant>>,
+}
+
+impl ShutdownSignal {
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn trigger(&self) {
+        self.received_at.get_or_init(Instant::now);
+        self.token.cancel();
+    }
+
+    // When shutting down must be done, or `timeout` from now if no signal was received.
+    pub fn deadline(&self, timeout: Duration) -> Instant {
+        self.received_at.get().copied().unwrap_or_else(Instant::now) + timeout
+    }
+}
+
+pub fn spawn_signal_listener() -> ShutdownSignal {
+    let shutdown = ShutdownSignal::default();
+    let cloned = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        tracing::info!("Stop accepting new connections, waiting for in-flight requests");
+        cloned.trigger();
+    });
+    shutdown
+}
+
+// Run the server until it finishes draining after shutdown is requested,
+// but don't wait longer than `timeout` (from the signal) for the in-flight requests.
+pub async fn serve_with_deadline<F, E>(
+    server: F,
+    shutdown: ShutdownSignal,
+    timeout: Duration,
+) -> Result<DrainOutcome, E>
+where
+    F: Future<Output = Result<(), E>>,
+{
+    let deadline = async {
+        shutdown.token.cancelled().await;
+        tokio::time::sleep_until(shutdown.deadline(timeout)).await;
+    };
+    tokio::select! {
+        r = server => r.map(|_| DrainOutcome::Completed),
+        _ = deadline => Ok(DrainOutcome::TimedOut),
+    }
+}
@@ -5,25 +5,35 @@ use edgedb_protocol::value::Value as EValue;
 use edgedb_protocol::common::Cardinality as Cd;
 use indexmap::{indexmap, IndexMap};
 
-use crate::models::blogs::MiniBlogPost;
-use crate::models::{MediumBlogPost, DetailedBlogPost, BlogCategory};
+use crate::models::blogs::{BlogPostOwner, MiniBlogPost, PostSource};
+use crate::models::{MediumBlogPost, DetailedBlogPost, BlogCategory, MinimalObject};
+use crate::monitoring::timed_query;
+use crate::utils::markdown::RenderedPost;
 use crate::types::conversions::{edge_object_from_simple_pairs, edge_object_from_pairs};
 
 pub async fn get_all_posts_count(client: &Client) -> Result<usize, Error> {
     let q = "SELECT count(BlogPost)";
     tracing::debug!("To query: {}", q);
-    let count: i64 = client.query_required_single(q, &()).await?;
+    let count: i64 = timed_query("blog::get_all_posts_count", client.query_required_single(q, &())).await?;
     Ok(count.try_into().unwrap_or(0))
 }
 
 pub async fn count_all_published_posts(client: &Client) -> Result<usize, Error> {
     let q = "SELECT count((SELECT BlogPost FILTER .is_published = true))";
     tracing::debug!("To query: {}", q);
-    let count: i64 = client.query_required_single(q, &()).await?;
+    let count: i64 = timed_query("blog::count_all_published_posts", client.query_required_single(q, &())).await?;
     Ok(count.try_into().unwrap_or(0))
 }
 
-pub async fn get_post(post_id: Uuid, client: &Client) -> Result<Option<DetailedBlogPost>, Error> {
+pub async fn get_post_owner(post_id: Uuid, client: &Client) -> Result<Option<BlogPostOwner>, Error> {
+    let q = "SELECT BlogPost {author_id := .author.id} FILTER .id = <uuid>$0";
+    tracing::debug!("To query: {}", q);
+    let owner: Option<BlogPostOwner> = timed_query("blog::get_post_owner", client.query_single(q, &(post_id,))).await?;
+    Ok(owner)
+}
+
+// With "published_only", drafts are not found, for readers who cannot view them.
+pub async fn get_post(post_id: Uuid, published_only: bool, client: &Client) -> Result<Option<DetailedBlogPost>, Error> {
     // Note: For now, we cannot use EdgeDB splats syntax because the returned field order
     // does not match DetailedBlogPost.
     let q = "
@@ -43,10 +53,13 @@ pub async fn get_post(post_id: Uuid, client: &Client) -> Result<Option<DetailedB
         html,
         seo_description,
         og_image,
+        toc := .toc ?? <json>[],
+        show_toc := .show_toc ?? true,
     }
-    FILTER .id = <uuid>$0";
+    FILTER .id = <uuid>$0 AND ((.is_published ?? false) OR NOT <bool>$1)";
     tracing::debug!("To query: {}", q);
-    let post: Option<DetailedBlogPost> = client.query_single(q, &(post_id,)).await?;
+    let post: Option<DetailedBlogPost> =
+        timed_query("blog::get_post", client.query_single(q, &(post_id, published_only))).await?;
     Ok(post)
 }
 
@@ -70,14 +83,21 @@ pub async fn get_detailed_post_by_slug(slug: String, client: &Client) -> Result<
         html,
         seo_description,
         og_image,
+        toc := .toc ?? <json>[],
+        show_toc := .show_toc ?? true,
     }
     FILTER .slug = <str>$0";
     tracing::debug!("To query: {}", q);
-    let post: Option<DetailedBlogPost> = client.query_single(q, &(slug,)).await?;
+    let post: Option<DetailedBlogPost> = timed_query("blog::get_detailed_post_by_slug", client.query_single(q, &(slug,))).await?;
     Ok(post)
 }
 
-pub async fn get_blogposts(offset: Option<i64>, limit: Option<i64>, client: &Client) -> Result<Vec<MediumBlogPost>, Error> {
+pub async fn get_blogposts(
+    offset: Option<i64>,
+    limit: Option<i64>,
+    published_only: bool,
+    client: &Client,
+) -> Result<Vec<MediumBlogPost>, Error> {
     let q = "
     SELECT BlogPost {
         id,
@@ -94,8 +114,10 @@ pub async fn get_blogposts(offset: Option<i64>, limit: Option<i64>, client: &Cli
             slug,
         },
     }
+    FILTER (.is_published ?? false) OR NOT <bool>$2
     ORDER BY .created_at DESC EMPTY FIRST OFFSET <optional int64>$0 LIMIT <optional int64>$1";
-    let posts: Vec<MediumBlogPost> = client.query(q, &(offset, limit)).await?;
+    let posts: Vec<MediumBlogPost> =
+        timed_query("blog::get_blogposts", client.query(q, &(offset, limit, published_only))).await?;
     Ok(posts)
 }
 
@@ -104,11 +126,11 @@ pub async fn get_published_posts(offset: Option<i64>, limit: Option<i64>, client
     let mut paging_lines: Vec<String> = Vec::with_capacity(2);
     if let Some(offset) = offset {
         pairs.insert("offset", (Some(EValue::Int64(offset)), Cd::One));
-        paging_lines.push(format!("OFFSET <int64>$offset"));
+        paging_lines.push("OFFSET <int64>$offset".to_string());
     }
     if let Some(limit) = limit {
         pairs.insert("limit", (Some(EValue::Int64(limit)), Cd::One));
-        paging_lines.push(format!("LIMIT <int64>$limit"));
+        paging_lines.push("LIMIT <int64>$limit".to_string());
     }
     let paging_expr = paging_lines.join(" ");
     let args = edge_object_from_pairs(pairs);
@@ -129,7 +151,7 @@ pub async fn get_published_posts(offset: Option<i64>, limit: Option<i64>, client
         }},
     }}
     FILTER .is_published = true ORDER BY .created_at DESC EMPTY FIRST {paging_expr}");
-    let posts: Vec<MediumBlogPost> = client.query(&q, &args).await?;
+    let posts: Vec<MediumBlogPost> = timed_query("blog::get_published_posts", client.query(&q, &args)).await?;
     Ok(posts)
 }
 
@@ -145,11 +167,11 @@ pub async fn get_published_posts_under_category(cat_slug: Option<String>, offset
     }
     if let Some(offset) = offset {
         pairs.insert("offset", (Some(EValue::Int64(offset)), Cd::One));
-        paging_lines.push(format!("OFFSET <int64>$offset"));
+        paging_lines.push("OFFSET <int64>$offset".to_string());
     }
     if let Some(limit) = limit {
         pairs.insert("limit", (Some(EValue::Int64(limit)), Cd::One));
-        paging_lines.push(format!("LIMIT <int64>$limit"));
+        paging_lines.push("LIMIT <int64>$limit".to_string());
     }
     let filter_expr = filter_lines.join(" AND ");
     let paging_expr = paging_lines.join(" ");
@@ -174,7 +196,7 @@ pub async fn get_published_posts_under_category(cat_slug: Option<String>, offset
     FILTER {filter_expr} ORDER BY .created_at DESC EMPTY FIRST {paging_expr}");
     tracing::debug!("To query: {}", q);
     tracing::debug!("With args: {:#?}", args);
-    let posts: Vec<MediumBlogPost> = client.query(&q, &args).await?;
+    let posts: Vec<MediumBlogPost> = timed_query("blog::get_published_posts_under_category", client.query(&q, &args)).await?;
     Ok(posts)
 }
 
@@ -182,7 +204,7 @@ pub async fn count_blogposts_under_category(id: Uuid, client: &Client) -> Result
     let q = "
     SELECT count((SELECT BlogPost FILTER .categories.id = <uuid>$0))";
     tracing::debug!("To query: {}", q);
-    let count: i64 = client.query_required_single(q, &(id,)).await?;
+    let count: i64 = timed_query("blog::count_blogposts_under_category", client.query_required_single(q, &(id,))).await?;
     Ok(count.try_into().unwrap_or(0))
 }
 
@@ -191,11 +213,11 @@ pub async fn get_published_uncategorized_blogposts(offset: Option<i64>, limit: O
     let mut paging_lines: Vec<String> = Vec::with_capacity(2);
     if let Some(offset) = offset {
         pairs.insert("offset", (Some(EValue::Int64(offset)), Cd::One));
-        paging_lines.push(format!("OFFSET <int64>$offset"));
+        paging_lines.push("OFFSET <int64>$offset".to_string());
     }
     if let Some(limit) = limit {
         pairs.insert("limit", (Some(EValue::Int64(limit)), Cd::One));
-        paging_lines.push(format!("LIMIT <int64>$limit"));
+        paging_lines.push("LIMIT <int64>$limit".to_string());
     }
     let paging_expr = paging_lines.join(" ");
     let args = edge_object_from_pairs(pairs);
@@ -218,7 +240,7 @@ pub async fn get_published_uncategorized_blogposts(offset: Option<i64>, limit: O
     FILTER .is_published = true AND NOT EXISTS .categories ORDER BY .created_at DESC EMPTY FIRST {paging_expr}");
     tracing::debug!("To query: {}", q);
     tracing::debug!("With args: {:#?}", args);
-    let posts: Vec<MediumBlogPost> = client.query(&q, &args).await?;
+    let posts: Vec<MediumBlogPost> = timed_query("blog::get_published_uncategorized_blogposts", client.query(&q, &args)).await?;
     Ok(posts)
 }
 
@@ -226,7 +248,7 @@ pub async fn count_published_uncategorized_posts(client: &Client) -> Result<usiz
     let q = "
     SELECT count((SELECT BlogPost FILTER .is_published = true AND NOT EXISTS .categories))";
     tracing::debug!("To query: {}", q);
-    let count: i64 = client.query_required_single(q, &()).await?;
+    let count: i64 = timed_query("blog::count_published_uncategorized_posts", client.query_required_single(q, &())).await?;
     Ok(count.try_into().unwrap_or(0))
 }
 
@@ -237,7 +259,7 @@ pub async fn get_blog_categories(offset: Option<i64>, limit: Option<i64>, client
         title,
         slug
     } ORDER BY .title OFFSET <optional int64>$0 LIMIT <optional int64>$1";
-    let categories: Vec<BlogCategory> = client.query(q, &(offset, limit)).await?;
+    let categories: Vec<BlogCategory> = timed_query("blog::get_blog_categories", client.query(q, &(offset, limit))).await?;
     Ok(categories)
 }
 
@@ -245,7 +267,7 @@ pub async fn get_blog_categories(offset: Option<i64>, limit: Option<i64>, client
 pub async fn get_all_categories_count(client: &Client) -> Result<usize, Error> {
     let q = "SELECT count(BlogCategory)";
     tracing::debug!("To query: {}", q);
-    let count: i64 = client.query_required_single(q, &()).await?;
+    let count: i64 = timed_query("blog::get_all_categories_count", client.query_required_single(q, &())).await?;
     Ok(count.try_into().unwrap_or(0))
 }
 
@@ -257,7 +279,7 @@ pub async fn get_category(id: Uuid, client: &Client) -> Result<Option<BlogCatego
         slug
     } FILTER .id = <uuid>$0";
     tracing::debug!("To query: {}", q);
-    let cat: Option<BlogCategory> = client.query_single(q, &(id,)).await?;
+    let cat: Option<BlogCategory> = timed_query("blog::get_category", client.query_single(q, &(id,))).await?;
     Ok(cat)
 }
 
@@ -269,7 +291,7 @@ pub async fn get_category_by_slug(slug: &str, client: &Client) -> Result<Option<
         slug
     } FILTER .slug = <str>$0";
     tracing::debug!("To query: {}", q);
-    let cat: Option<BlogCategory> = client.query_single(q, &(slug,)).await?;
+    let cat: Option<BlogCategory> = timed_query("blog::get_category_by_slug", client.query_single(q, &(slug,))).await?;
     Ok(cat)
 }
 
@@ -308,7 +330,7 @@ pub async fn get_previous_post(created_at: EDatetime, cat_slug: Option<String>,
     FILTER {filter_expr} ORDER BY .created_at DESC LIMIT 1");
     tracing::debug!("To query: {}", q);
     tracing::debug!("With args: {:#?}", args);
-    let post: Option<MediumBlogPost> = client.query_single(&q, &args).await?;
+    let post: Option<MediumBlogPost> = timed_query("blog::get_previous_post", client.query_single(&q, &args)).await?;
     Ok(post)
 }
 
@@ -347,13 +369,42 @@ pub async fn get_next_post(created_at: EDatetime, cat_slug: Option<String>, clie
     FILTER {filter_expr} ORDER BY .created_at ASC LIMIT 1");
     tracing::debug!("To query: {}", q);
     tracing::debug!("With args: {:#?}", args);
-    let post: Option<MediumBlogPost> = client.query_single(&q, &args).await?;
+    let post: Option<MediumBlogPost> = timed_query("blog::get_next_post", client.query_single(&q, &args)).await?;
     Ok(post)
 }
 
 pub async fn get_mini_post_by_old_id(old_id: u32, client: &Client) -> Result<Option<MiniBlogPost>, Error> {
     let q = "SELECT BlogPost {id, title, slug, created_at} FILTER .old_id = <int32>$0";
     tracing::debug!("To query: {}", q);
-    let post: Option<MiniBlogPost> = client.query_single(q, &(old_id as i32,)).await?;
+    let post: Option<MiniBlogPost> = timed_query("blog::get_mini_post_by_old_id", client.query_single(q, &(old_id as i32,))).await?;
     Ok(post)
 }
+
+// Posts are iterated by ID, so that updating them doesn't shift the next batches.
+pub async fn get_post_sources_after(after: Uuid, limit: i64, below_version: i16, client: &Client) -> Result<Vec<PostSource>, Error> {
+    let q = "
+    SELECT BlogPost {id, title, body, format}
+    FILTER .id > <uuid>$0 AND (.renderer_version ?? 0) < <int16>$2
+    ORDER BY .id LIMIT <int64>$1";
+    tracing::debug!("To query: {}", q);
+    let posts: Vec<PostSource> = timed_query("blog::get_post_sources_after", client.query(q, &(after, limit, below_version))).await?;
+    Ok(posts)
+}
+
+pub async fn count_posts_below_renderer_version(below_version: i16, client: &Client) -> Result<usize, Error> {
+    let q = "SELECT count((SELECT BlogPost FILTER (.renderer_version ?? 0) < <int16>$0))";
+    tracing::debug!("To query: {}", q);
+    let count: i64 = timed_query("blog::count_posts_below_renderer_version", client.query_required_single(q, &(below_version,))).await?;
+    Ok(count.try_into().unwrap_or(0))
+}
+
+pub async fn update_post_rendering(id: Uuid, rendered: RenderedPost, renderer_version: i16, client: &Client) -> Result<(), Error> {
+    let q = "UPDATE BlogPost FILTER .id = <uuid>$0 SET {
+        html := <str>$1, excerpt := <str>$2, toc := to_json(<str>$3), renderer_version := <int16>$4
+    }";
+    tracing::debug!("To query: {}", q);
+    let RenderedPost { html, excerpt, toc } = rendered;
+    let toc = serde_json::to_string(&toc).unwrap_or_else(|_| "[]".into());
+    timed_query("blog::update_post_rendering", client.query_required_single::<MinimalObject, _>(q, &(id, html, excerpt, toc, renderer_version))).await?;
+    Ok(())
+}
@@ -1,2 +1,5 @@
 pub mod user;
 pub mod blog;
+pub mod token;
+#[cfg(test)]
+pub mod tests;
@@ -0,0 +1,39 @@
+use edgedb_tokio::Client;
+use uuid::Uuid;
+
+use super::user;
+use crate::auth::totp::hash_recovery_code;
+use crate::conf::AppConfig;
+use crate::db::get_edgedb_client;
+use crate::models::Role;
+
+// These tests write to the database configured in settings. Run them against a migrated
+// development instance with "cargo test -- --ignored".
+async fn connect() -> Client {
+    let config = AppConfig::load().expect("Settings must be valid");
+    get_edgedb_client(&config).await.expect("EdgeDB must be reachable")
+}
+
+#[tokio::test]
+#[ignore = "needs a migrated EdgeDB instance"]
+async fn recovery_code_works_only_once() {
+    let client = connect().await;
+    let name = format!("test-{}", Uuid::now_v1(&[0; 6]).simple());
+    let user = user::create_user(&name, &format!("{name}@example.com"), "x", false, Role::Viewer, &client)
+        .await
+        .unwrap();
+    let codes: Vec<String> = ["aaaaa-aaaaa", "bbbbb-bbbbb"].iter().map(|c| hash_recovery_code(c)).collect();
+    user::set_pending_totp_secret(user.id, "JBSWY3DPEHPK3PXP", &client).await.unwrap();
+    user::enable_two_factor(user.id, codes.clone(), 0, &client).await.unwrap();
+
+    let first_use = user::use_recovery_code(user.id, &codes[0], &client).await;
+    let second_use = user::use_recovery_code(user.id, &codes[0], &client).await;
+    let other_code = user::use_recovery_code(user.id, &codes[1], &client).await;
+    let state = user::get_two_factor(user.id, &client).await;
+    user::delete_user(user.id, &client).await.unwrap();
+
+    assert!(first_use.unwrap());
+    assert!(!second_use.unwrap());
+    assert!(other_code.unwrap());
+    assert!(state.unwrap().unwrap().recovery_codes.is_empty());
+}
@@ -0,0 +1,69 @@
+use edgedb_protocol::model::Datetime as EDatetime;
+use edgedb_tokio::{Client, Error};
+use uuid::Uuid;
+
+use crate::models::{AccessToken, MinimalObject, TokenOwner};
+use crate::monitoring::timed_query;
+
+const TOKEN_FIELDS: &str = "id, name, prefix, scopes, created_at, expires_at, last_used_at";
+
+pub async fn create_token(
+    user_id: Uuid,
+    name: &str,
+    token_hash: &str,
+    prefix: &str,
+    scopes: Vec<String>,
+    expires_at: Option<EDatetime>,
+    client: &Client,
+) -> Result<AccessToken, Error> {
+    let q = format!(
+        "SELECT (
+            INSERT AccessToken {{
+                user := (SELECT User FILTER .id = <uuid>$0),
+                name := <str>$1,
+                token_hash := <str>$2,
+                prefix := <str>$3,
+                scopes := <array<str>>$4,
+                expires_at := <optional datetime>$5,
+            }}
+        ) {{{TOKEN_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    let args = (user_id, name, token_hash, prefix, scopes, expires_at);
+    let token: AccessToken = timed_query("token::create_token", client.query_required_single(&q, &args)).await?;
+    Ok(token)
+}
+
+pub async fn list_tokens(user_id: Uuid, client: &Client) -> Result<Vec<AccessToken>, Error> {
+    let q = format!("SELECT AccessToken {{{TOKEN_FIELDS}}} FILTER .user.id = <uuid>$0 ORDER BY .created_at DESC");
+    tracing::debug!("To query: {}", q);
+    let tokens: Vec<AccessToken> = timed_query("token::list_tokens", client.query(&q, &(user_id,))).await?;
+    Ok(tokens)
+}
+
+// Only the owner can revoke the token, so the user is part of the filter.
+pub async fn delete_token(user_id: Uuid, token_id: Uuid, client: &Client) -> Result<Option<MinimalObject>, Error> {
+    let q = "DELETE AccessToken FILTER .id = <uuid>$0 AND .user.id = <uuid>$1";
+    tracing::debug!("To query: {}", q);
+    let deleted: Option<MinimalObject> =
+        timed_query("token::delete_token", client.query_single(q, &(token_id, user_id))).await?;
+    Ok(deleted)
+}
+
+// Find the owner of a valid token, and mark the token as used.
+pub async fn use_token(token_hash: &str, client: &Client) -> Result<Option<TokenOwner>, Error> {
+    let q = "SELECT (
+        UPDATE AccessToken
+        FILTER .token_hash = <str>$0
+            AND ((.expires_at > datetime_current()) ?? true)
+            AND (.user.is_active ?? true)
+        SET {last_used_at := datetime_current()}
+    ) {
+        scopes,
+        user: {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author},
+    }
+    LIMIT 1";
+    tracing::debug!("To query: {}", q);
+    let owner: Option<TokenOwner> = timed_query("token::use_token", client.query_single(q, &(token_hash,))).await?;
+    Ok(owner)
+}
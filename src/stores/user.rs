@@ -1,9 +1,146 @@
+use uuid::Uuid;
 use edgedb_tokio::{Client, Error};
-use crate::models::User;
+use crate::models::{MinimalObject, Role, TwoFactorState, User, UserInfo};
+use crate::monitoring::timed_query;
 
 pub async fn get_user_by_email(email: &str, client: &Client) -> Result<Option<User>, Error> {
-    let q = "SELECT User {id, username, email, password, is_active, is_superuser} FILTER .email = <str>$0 LIMIT 1";
+    let q = "SELECT User {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author} FILTER .email = <str>$0 LIMIT 1";
     tracing::debug!("To query: {}", q);
-    let user: Option<User> = client.query_single(q, &(email,)).await?;
+    let user: Option<User> = timed_query("user::get_user_by_email", client.query_single(q, &(email,))).await?;
     Ok(user)
 }
+
+pub async fn get_user(id: Uuid, client: &Client) -> Result<Option<User>, Error> {
+    let q = "SELECT User {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author} FILTER .id = <uuid>$0";
+    tracing::debug!("To query: {}", q);
+    let user: Option<User> = timed_query("user::get_user", client.query_single(q, &(id,))).await?;
+    Ok(user)
+}
+
+pub const USER_INFO_FIELDS: &str =
+    "id, username, email, first_name, last_name, is_active, is_superuser, role := .role ?? UserRole.Author";
+
+pub async fn list_users(offset: Option<i64>, limit: Option<i64>, client: &Client) -> Result<Vec<UserInfo>, Error> {
+    let q = format!("SELECT User {{{USER_INFO_FIELDS}}} ORDER BY .username OFFSET <optional int64>$0 LIMIT <optional int64>$1");
+    tracing::debug!("To query: {}", q);
+    let users: Vec<UserInfo> = timed_query("user::list_users", client.query(&q, &(offset, limit))).await?;
+    Ok(users)
+}
+
+pub async fn get_all_users_count(client: &Client) -> Result<usize, Error> {
+    let q = "SELECT count(User)";
+    tracing::debug!("To query: {}", q);
+    let count: i64 = timed_query("user::get_all_users_count", client.query_required_single(q, &())).await?;
+    Ok(count.try_into().unwrap_or(0))
+}
+
+pub async fn get_user_info(id: Uuid, client: &Client) -> Result<Option<UserInfo>, Error> {
+    let q = format!("SELECT User {{{USER_INFO_FIELDS}}} FILTER .id = <uuid>$0");
+    tracing::debug!("To query: {}", q);
+    let user: Option<UserInfo> = timed_query("user::get_user_info", client.query_single(&q, &(id,))).await?;
+    Ok(user)
+}
+
+pub async fn delete_user(id: Uuid, client: &Client) -> Result<Option<MinimalObject>, Error> {
+    let q = "DELETE User FILTER .id = <uuid>$0";
+    tracing::debug!("To query: {}", q);
+    let deleted: Option<MinimalObject> = timed_query("user::delete_user", client.query_single(q, &(id,))).await?;
+    Ok(deleted)
+}
+
+pub async fn create_user(username: &str, email: &str, password_hash: &str, is_superuser: bool, role: Role, client: &Client) -> Result<User, Error> {
+    let role: &str = role.into();
+    let q = "SELECT (
+        INSERT User {username := <str>$0, email := <str>$1, password := <str>$2, is_superuser := <bool>$3, role := <UserRole><str>$4}
+    ) {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author}";
+    tracing::debug!("To query: {}", q);
+    let user: User = timed_query("user::create_user", client.query_required_single(q, &(username, email, password_hash, is_superuser, role))).await?;
+    Ok(user)
+}
+
+// Someone's username may be another one's email, so the caller must check that only one user matches.
+pub async fn get_users_by_login(login: &str, client: &Client) -> Result<Vec<UserInfo>, Error> {
+    let q = format!("SELECT User {{{USER_INFO_FIELDS}}} FILTER .username = <str>$0 OR .email = <str>$0");
+    tracing::debug!("To query: {}", q);
+    let users: Vec<UserInfo> = timed_query("user::get_users_by_login", client.query(&q, &(login,))).await?;
+    Ok(users)
+}
+
+pub async fn set_password_by_id(user_id: Uuid, password_hash: &str, client: &Client) -> Result<Option<User>, Error> {
+    let q = "SELECT (
+        UPDATE User FILTER .id = <uuid>$0
+        SET {password := <str>$1}
+    ) {id, username, email, password, is_active, is_superuser, role := .role ?? UserRole.Author}";
+    tracing::debug!("To query: {}", q);
+    let user: Option<User> = timed_query("user::set_password_by_id", client.query_single(q, &(user_id, password_hash))).await?;
+    Ok(user)
+}
+
+const TWO_FACTOR_FIELDS: &str = "secret := .totp_secret, enabled := .totp_enabled ?? false, recovery_codes := .totp_recovery_codes ?? <array<str>>[], last_step := .totp_last_step";
+
+pub async fn get_two_factor(user_id: Uuid, client: &Client) -> Result<Option<TwoFactorState>, Error> {
+    let q = format!("SELECT User {{{TWO_FACTOR_FIELDS}}} FILTER .id = <uuid>$0");
+    tracing::debug!("To query: {}", q);
+    let state: Option<TwoFactorState> = timed_query("user::get_two_factor", client.query_single(&q, &(user_id,))).await?;
+    Ok(state)
+}
+
+// Start enrolment with a new secret. It is not used at login until confirmed by `enable_two_factor`.
+pub async fn set_pending_totp_secret(user_id: Uuid, secret: &str, client: &Client) -> Result<Option<TwoFactorState>, Error> {
+    let q = format!(
+        "SELECT (
+            UPDATE User FILTER .id = <uuid>$0 AND NOT (.totp_enabled ?? false)
+            SET {{totp_secret := <str>$1, totp_last_step := <int64>{{}}}}
+        ) {{{TWO_FACTOR_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    let state: Option<TwoFactorState> = timed_query("user::set_pending_totp_secret", client.query_single(&q, &(user_id, secret))).await?;
+    Ok(state)
+}
+
+pub async fn enable_two_factor(user_id: Uuid, recovery_code_hashes: Vec<String>, last_step: i64, client: &Client) -> Result<Option<TwoFactorState>, Error> {
+    let q = format!(
+        "SELECT (
+            UPDATE User FILTER .id = <uuid>$0 AND EXISTS .totp_secret
+            SET {{totp_enabled := true, totp_recovery_codes := <array<str>>$1, totp_last_step := <int64>$2}}
+        ) {{{TWO_FACTOR_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    let args = (user_id, recovery_code_hashes, last_step);
+    let state: Option<TwoFactorState> = timed_query("user::enable_two_factor", client.query_single(&q, &args)).await?;
+    Ok(state)
+}
+
+pub async fn disable_two_factor(user_id: Uuid, client: &Client) -> Result<Option<TwoFactorState>, Error> {
+    let q = format!(
+        "SELECT (
+            UPDATE User FILTER .id = <uuid>$0
+            SET {{totp_enabled := false, totp_secret := <str>{{}}, totp_recovery_codes := <array<str>>{{}}, totp_last_step := <int64>{{}}}}
+        ) {{{TWO_FACTOR_FIELDS}}}"
+    );
+    tracing::debug!("To query: {}", q);
+    let state: Option<TwoFactorState> = timed_query("user::disable_two_factor", client.query_single(&q, &(user_id,))).await?;
+    Ok(state)
+}
+
+// Remember the step of the accepted code. The filter makes it fail if another request used the same code first.
+pub async fn mark_totp_step_used(user_id: Uuid, step: i64, client: &Client) -> Result<bool, Error> {
+    let q = "SELECT (
+        UPDATE User FILTER .id = <uuid>$0 AND ((.totp_last_step < <int64>$1) ?? true)
+        SET {totp_last_step := <int64>$1}
+    ) {id}";
+    tracing::debug!("To query: {}", q);
+    let updated: Option<MinimalObject> = timed_query("user::mark_totp_step_used", client.query_single(q, &(user_id, step))).await?;
+    Ok(updated.is_some())
+}
+
+// Remove a recovery code, so it works only once. Return false if it was not there (or was just used).
+pub async fn use_recovery_code(user_id: Uuid, code_hash: &str, client: &Client) -> Result<bool, Error> {
+    let q = "SELECT (
+        UPDATE User FILTER .id = <uuid>$0 AND <str>$1 IN array_unpack(.totp_recovery_codes)
+        SET {totp_recovery_codes := array_agg((WITH codes := array_unpack(.totp_recovery_codes) SELECT codes FILTER codes != <str>$1))}
+    ) {id}";
+    tracing::debug!("To query: {}", q);
+    let updated: Option<MinimalObject> = timed_query("user::use_recovery_code", client.query_single(q, &(user_id, code_hash))).await?;
+    Ok(updated.is_some())
+}
@@ -7,17 +7,24 @@ use std::num::NonZeroU16;
 
 use http::Uri;
 use axum::extract::FromRef;
-use axum::http::header::CONTENT_TYPE;
+use axum::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, VARY};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use edgedb_protocol::codec::ShapeElement;
 use edgedb_protocol::common::Cardinality;
 use edgedb_tokio::Client;
+use fred::pool::RedisPool;
 use minijinja::Environment;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
 use smart_default::SmartDefault;
+use tokio_util::task::TaskTracker;
 
+use crate::auth::lockout::LoginLockout;
+use crate::auth::passwords::ResetTokens;
+use crate::mail::MailSender;
+use crate::middlewares::request_id::current_request_id;
+use crate::rerender::RerenderTracker;
 use crate::utils::urls::update_entry_in_query;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,6 +32,7 @@ pub struct ApiErrorShape {
     pub message: String,
     pub fields: Option<HashMap<String, String>>,
     pub code: Option<String>,
+    pub request_id: Option<String>,
 }
 
 impl Default for ApiErrorShape {
@@ -33,6 +41,7 @@ impl Default for ApiErrorShape {
             message: "Some error".to_string(),
             fields: None,
             code: None,
+            request_id: current_request_id(),
         }
     }
 }
@@ -58,7 +67,16 @@ impl From<HashMap<String, String>> for ApiErrorShape {
 #[derive(Debug, Clone, FromRef)]
 pub struct AppState {
     pub db: Client,
+    // Absent when Redis is not configured.
+    pub redis: Option<RedisPool>,
     pub jinja: Environment<'static>,
+    // Jobs which outlive the request spawning them. They are awaited on shutdown.
+    pub tasks: TaskTracker,
+    pub rerender: RerenderTracker,
+    pub lockout: LoginLockout,
+    // Absent when mail or the reset page is not configured, then password reset is disabled.
+    pub mailer: Option<MailSender>,
+    pub reset_tokens: Option<ResetTokens>,
 }
 
 #[derive(RustEmbed)]
@@ -67,20 +85,53 @@ pub struct AppState {
 #[exclude = "fonts/*"]
 pub struct Assets;
 
-pub struct StaticFile<T>(pub T);
+// The second field is the value of "Accept-Encoding" request header. If the client accepts it,
+// we serve the pre-compressed variant of the file ("style.css.br", "style.css.gz"), if any.
+pub struct StaticFile<T>(pub T, pub Option<HeaderValue>);
+
+impl<T> StaticFile<T> {
+    const PRECOMPRESSED_VARIANTS: [(&'static str, &'static str); 2] = [("br", "br"), ("gzip", "gz")];
+
+    fn accepted_variants(&self) -> Vec<(&'static str, &'static str)> {
+        let accepted = self
+            .1
+            .as_ref()
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let accepted: Vec<&str> = accepted
+            .split(',')
+            .filter_map(|s| s.split(';').next())
+            .map(str::trim)
+            .collect();
+        Self::PRECOMPRESSED_VARIANTS
+            .into_iter()
+            .filter(|(encoding, _ext)| accepted.contains(encoding))
+            .collect()
+    }
+}
 
 impl<T> IntoResponse for StaticFile<T>
 where
     T: Into<String>,
 {
     fn into_response(self) -> Response {
+        let variants = self.accepted_variants();
         let path = self.0.into();
-
+        let mime = mime_guess::from_path(&path).first_or_octet_stream();
+        let precompressed = variants.into_iter().find_map(|(encoding, ext)| {
+            Assets::get(&format!("{path}.{ext}")).map(|content| (encoding, content))
+        });
+        if let Some((encoding, content)) = precompressed {
+            let headers = [
+                (CONTENT_TYPE, mime.as_ref()),
+                (CONTENT_ENCODING, encoding),
+                (VARY, "accept-encoding"),
+            ];
+            return (headers, content.data).into_response();
+        }
         match Assets::get(path.as_str()) {
-            Some(content) => {
-                let mime = mime_guess::from_path(path).first_or_octet_stream();
-                ([(CONTENT_TYPE, mime.as_ref())], content.data).into_response()
-            }
+            // Caches must not give this to clients which accept a pre-compressed variant.
+            Some(content) => ([(CONTENT_TYPE, mime.as_ref()), (VARY, "accept-encoding")], content.data).into_response(),
             None => (StatusCode::NOT_FOUND, "File Not Found").into_response(),
         }
     }
@@ -0,0 +1,170 @@
+// Rendering of diagrams in fenced code blocks (```dot, ```mermaid...) to inline SVG,
+// by external programs. The result is cached by content hash, so re-rendering posts
+// doesn't run the programs again.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use comrak::arena_tree::Node;
+use comrak::nodes::{Ast, AstNode, NodeValue};
+use comrak::Arena;
+use ring::digest;
+use thiserror::Error;
+
+use super::placeholder::Placeholders;
+use super::sanitize::sanitize_svg;
+use crate::conf::DiagramSettings;
+
+#[derive(Debug, Error)]
+pub enum DiagramError {
+    #[error("cannot run {0}: {1}")]
+    Spawn(String, io::Error),
+    #[error("{0} did not finish in {1} seconds")]
+    Timeout(String, u64),
+    #[error("{0} failed: {1}")]
+    Failed(String, String),
+    #[error("{0} did not produce SVG")]
+    InvalidOutput(String),
+}
+
+pub struct DiagramRenderer<'a> {
+    pub settings: &'a DiagramSettings,
+    // Where rendered SVG is cached. None to always render.
+    pub cache_dir: Option<&'a Path>,
+}
+
+impl DiagramRenderer<'_> {
+    // Replace the diagram code blocks which can be rendered, wherever they are nested, with paragraphs
+    // of placeholders, and return the SVGs. Blocks which fail to render are left as is, to be shown as
+    // highlighted source.
+    pub fn extract<'a>(
+        &self,
+        root: &'a AstNode<'a>,
+        arena: &'a Arena<AstNode<'a>>,
+        placeholders: &Placeholders,
+    ) -> Vec<String> {
+        let mut diagrams = Vec::new();
+        for node in root.descendants() {
+            let (lang, source) = match &node.data.borrow().value {
+                NodeValue::CodeBlock(block) if block.fenced => {
+                    let lang = block.info.split_whitespace().next().unwrap_or_default().to_lowercase();
+                    (lang, block.literal.clone())
+                }
+                _ => continue,
+            };
+            let svg = self
+                .settings
+                .renderers
+                .get(&lang)
+                .filter(|_| self.settings.enabled)
+                .and_then(|command| match self.render(command, &source) {
+                    Ok(svg) => Some(svg),
+                    Err(e) => {
+                        tracing::warn!("Cannot render {lang} diagram: {e}");
+                        None
+                    }
+                });
+            let Some(svg) = svg else {
+                continue;
+            };
+            let mut ast = node.data.borrow_mut();
+            ast.value = NodeValue::Paragraph;
+            let text = Ast::new(NodeValue::Text(placeholders.get(diagrams.len())), ast.sourcepos.start);
+            node.append(arena.alloc(Node::new(RefCell::new(text))));
+            diagrams.push(svg);
+        }
+        diagrams
+    }
+
+    // The cache keeps SVG as the renderer made it. It is sanitized every time,
+    // so that the cached files follow the current sanitizer policy.
+    fn render(&self, command: &[String], source: &str) -> Result<String, DiagramError> {
+        let cache_path = self.cache_dir.map(|dir| cache_path(dir, command, source));
+        if let Some(svg) = cache_path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            return Ok(sanitize_svg(&svg));
+        }
+        let svg = run_renderer(command, source, self.settings.timeout)?;
+        if let Some(path) = cache_path {
+            if let Err(e) = write_cache(&path, &svg) {
+                tracing::warn!("Cannot write diagram cache {}: {e}", path.display());
+            }
+        }
+        Ok(sanitize_svg(&svg))
+    }
+}
+
+// The command is part of the key, so that changing renderer options invalidates the cache.
+fn cache_path(dir: &Path, command: &[String], source: &str) -> PathBuf {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for arg in command {
+        context.update(arg.as_bytes());
+        context.update(b"\0");
+    }
+    context.update(source.as_bytes());
+    let hash: String = context.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect();
+    dir.join(format!("{hash}.svg"))
+}
+
+fn write_cache(path: &Path, svg: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, svg)?;
+    fs::rename(&tmp_path, path)
+}
+
+// Blocks the thread until the program finishes or times out, see `render_post_blocking` for async code.
+fn run_renderer(command: &[String], source: &str, timeout: u64) -> Result<String, DiagramError> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| DiagramError::Failed("renderer".into(), "empty command".into()))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| DiagramError::Spawn(program.clone(), e))?;
+    let pid = child.id();
+    let stdin = child.stdin.take();
+    let source = source.to_string();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            // The error, if any, will show up as failed exit status.
+            stdin.write_all(source.as_bytes()).ok();
+        }
+        sender.send(child.wait_with_output()).ok();
+    });
+    let output = match receiver.recv_timeout(Duration::from_secs(timeout)) {
+        Ok(output) => output.map_err(|e| DiagramError::Spawn(program.clone(), e))?,
+        Err(_) => {
+            // SAFETY: Just sending signal to the process we spawned, which is not reaped yet.
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            return Err(DiagramError::Timeout(program.clone(), timeout));
+        }
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(DiagramError::Failed(program.clone(), stderr.trim().to_string()));
+    }
+    let svg = String::from_utf8_lossy(&output.stdout);
+    let start = svg.find("<svg").ok_or_else(|| DiagramError::InvalidOutput(program.clone()))?;
+    Ok(svg[start..].trim().to_string())
+}
+
+// Put rendered SVG in place of the placeholder paragraphs of sanitized HTML.
+pub fn restore_diagrams(html: &str, diagrams: &[String], placeholders: &Placeholders) -> String {
+    placeholders.restore(
+        &placeholders.unwrap_paragraphs(html),
+        |index| diagrams.get(index).map(|svg| format!("<figure class=\"diagram\">{svg}</figure>")),
+        |_index| Some(String::new()),
+    )
+}
@@ -0,0 +1,185 @@
+// Syntax highlighting of code blocks with CSS classes instead of inline styles, so that
+// the colors come from a stylesheet, which can be switched (light/dark) without re-rendering posts.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use comrak::adapters::SyntaxHighlighterAdapter;
+use once_cell::sync::Lazy;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{css_for_theme_with_class_style, line_tokens_to_classed_spans, ClassStyle};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::consts::HIGHLIGHT_CSS_URL;
+
+pub const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: CLASS_PREFIX };
+
+static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+static THEME_SET: Lazy<ThemeSet> = Lazy::new(ThemeSet::load_defaults);
+
+// Bundled theme names, like "base16-ocean.dark", "InspiredGitHub", "Solarized (light)".
+pub fn theme_names() -> Vec<&'static str> {
+    THEME_SET.themes.keys().map(String::as_str).collect()
+}
+
+// Themes are addressed in URL by a slug of their name, like "solarized-light".
+pub fn theme_slug(name: &str) -> String {
+    slug::slugify(name)
+}
+
+fn find_theme(name_or_slug: &str) -> Option<&'static Theme> {
+    THEME_SET.themes.get(name_or_slug).or_else(|| {
+        THEME_SET
+            .themes
+            .iter()
+            .find(|(name, _)| theme_slug(name) == name_or_slug)
+            .map(|(_, theme)| theme)
+    })
+}
+
+pub fn stylesheet_url(theme: &str) -> String {
+    format!("{HIGHLIGHT_CSS_URL}/{}.css", theme_slug(theme))
+}
+
+pub fn theme_exists(name_or_slug: &str) -> bool {
+    find_theme(name_or_slug).is_some()
+}
+
+fn css_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}{:02x}", color.r, color.g, color.b, color.a)
+}
+
+// Stylesheet for the classes emitted by `ClassedHighlighter`, including line numbers
+// and highlighted lines. None if the theme doesn't exist.
+pub fn theme_css(name_or_slug: &str) -> Option<String> {
+    let theme = find_theme(name_or_slug)?;
+    let mut css = css_for_theme_with_class_style(theme, CLASS_STYLE).ok()?;
+    let settings = &theme.settings;
+    let gutter = settings.gutter_foreground.or(settings.foreground).map(css_color);
+    // Themes don't always define it, fall back to a translucent gray which works on both light and dark.
+    let line_highlight = settings.line_highlight.map_or("rgba(128, 128, 128, 0.25)".into(), css_color);
+    css.push_str(&format!(
+        "\n.{CLASS_PREFIX}numbered {{\n counter-reset: {CLASS_PREFIX}line;\n}}\n\
+         .{CLASS_PREFIX}numbered .{CLASS_PREFIX}line::before {{\n counter-increment: {CLASS_PREFIX}line;\n \
+         content: counter({CLASS_PREFIX}line);\n display: inline-block;\n width: 2.5em;\n margin-right: 1em;\n \
+         text-align: right;\n opacity: 0.6;\n user-select: none;\n{}}}\n\
+         .{CLASS_PREFIX}line.{CLASS_PREFIX}highlighted {{\n display: inline-block;\n min-width: 100%;\n \
+         background-color: {line_highlight};\n}}\n",
+        gutter.map(|c| format!(" color: {c};\n")).unwrap_or_default()
+    ));
+    Some(css)
+}
+
+// Options given in fence info after the language, like "```rust {3-5} linenos".
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FenceOptions {
+    pub line_numbers: bool,
+    pub highlighted: Vec<RangeInclusive<usize>>,
+}
+
+impl FenceOptions {
+    pub fn parse(meta: &str) -> Self {
+        let mut options = Self::default();
+        for token in meta.split_whitespace() {
+            if let Some(ranges) = token.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+                options.highlighted.extend(ranges.split(',').filter_map(parse_range));
+            } else if matches!(token, "linenos" | "showLineNumbers" | "line-numbers") {
+                options.line_numbers = true;
+            }
+        }
+        options
+    }
+
+    fn is_highlighted(&self, line: usize) -> bool {
+        self.highlighted.iter().any(|r| r.contains(&line))
+    }
+}
+
+fn parse_range(range: &str) -> Option<RangeInclusive<usize>> {
+    let range = range.trim();
+    match range.split_once('-') {
+        Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+        None => range.parse().ok().map(|n| n..=n),
+    }
+}
+
+// Used as comrak's code block highlighter. comrak calls `write_pre_tag`, `write_code_tag`
+// and `write_highlighted` in turn for each block, the fence options are passed along in between.
+#[derive(Default)]
+pub struct ClassedHighlighter {
+    meta: RefCell<Option<String>>,
+}
+
+impl SyntaxHighlighterAdapter for ClassedHighlighter {
+    fn write_highlighted(&self, output: &mut dyn Write, lang: Option<&str>, code: &str) -> io::Result<()> {
+        let options = FenceOptions::parse(self.meta.take().as_deref().unwrap_or_default());
+        // "```rust{3-5}" without space.
+        let lang = lang.and_then(|l| l.split('{').next()).unwrap_or_default();
+        let html = highlight_lines(code, lang, &options).map_err(io::Error::other)?;
+        output.write_all(html.as_bytes())
+    }
+
+    fn write_pre_tag(&self, output: &mut dyn Write, _attributes: HashMap<String, String>) -> io::Result<()> {
+        // Only know about line numbers after seeing the code tag, so the class is added to <code>.
+        write!(output, "<pre class=\"{CLASS_PREFIX}code\">")
+    }
+
+    fn write_code_tag(&self, output: &mut dyn Write, mut attributes: HashMap<String, String>) -> io::Result<()> {
+        let meta = attributes.remove("data-meta");
+        let mut classes: Vec<String> = attributes.remove("class").into_iter().collect();
+        if meta.as_deref().is_some_and(|m| FenceOptions::parse(m).line_numbers) {
+            classes.push(format!("{CLASS_PREFIX}numbered"));
+        }
+        *self.meta.borrow_mut() = meta;
+        if classes.is_empty() {
+            return output.write_all(b"<code>");
+        }
+        let classes = html_escape(&classes.join(" "));
+        write!(output, "<code class=\"{classes}\">")
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Each line is wrapped in its own span, to attach line number and highlight.
+// Spans of scopes which continue across lines are closed at the end of the line and reopened on the next one.
+pub fn highlight_lines(code: &str, lang: &str, options: &FenceOptions) -> Result<String, syntect::Error> {
+    let syntax = SYNTAX_SET
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| SYNTAX_SET.find_syntax_plain_text());
+    let mut parse_state = ParseState::new(syntax);
+    let mut scope_stack = ScopeStack::new();
+    let mut html = String::with_capacity(code.len() * 2);
+    for (index, line) in LinesWithEndings::from(code).enumerate() {
+        let ops = parse_state.parse_line(line, &SYNTAX_SET)?;
+        let mut line_class = format!("{CLASS_PREFIX}line");
+        if options.is_highlighted(index + 1) {
+            line_class.push_str(&format!(" {CLASS_PREFIX}highlighted"));
+        }
+        html.push_str(&format!("<span class=\"{line_class}\">"));
+        for scope in scope_stack.as_slice() {
+            let classes: Vec<String> = scope
+                .build_string()
+                .split('.')
+                .map(|atom| format!("{CLASS_PREFIX}{atom}"))
+                .collect();
+            html.push_str(&format!("<span class=\"{}\">", classes.join(" ")));
+        }
+        let (mut spans, _) = line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut scope_stack)?;
+        // Keep the newline out of the line span.
+        let newline = spans.rfind('\n').map(|i| spans.remove(i)).is_some();
+        html.push_str(&spans);
+        html.push_str(&"</span>".repeat(scope_stack.len()));
+        html.push_str("</span>");
+        if newline {
+            html.push('\n');
+        }
+    }
+    Ok(html)
+}
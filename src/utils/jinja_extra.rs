@@ -6,6 +6,7 @@ use regex::Regex;
 use chrono::DateTime;
 use minijinja::value::Value as MJValue;
 
+use crate::middlewares::security_headers::current_csp_nonce;
 use crate::utils::urls::update_entry_in_query;
 
 pub fn debug_value(value: MJValue) -> &'static str {
@@ -26,6 +27,11 @@ pub fn post_detail_url(slug: String, created_at: String) -> String {
     }
 }
 
+// For <script nonce='...'>, to be allowed by Content-Security-Policy.
+pub fn csp_nonce() -> String {
+    current_csp_nonce().unwrap_or_default()
+}
+
 pub fn gen_element_attr(name: &str, value: MJValue) -> String {
     match value.as_str() {
         Some(value) => format!("{}=\"{}\"", name, value),
@@ -1,15 +1,225 @@
-use comrak::{markdown_to_html_with_plugins, ComrakPlugins, ComrakOptions};
-use comrak::plugins::syntect::SyntectAdapter;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use comrak::adapters::{HeadingAdapter, HeadingMeta};
+use comrak::nodes::{NodeValue, Sourcepos};
+use comrak::{format_html_with_plugins, parse_document, Anchorizer, Arena, ComrakOptions, ComrakPlugins};
+use once_cell::sync::OnceCell;
+use tokio::task::{spawn_blocking, JoinError};
+
+use super::diagram::{restore_diagrams, DiagramRenderer};
+use super::highlight::ClassedHighlighter;
+use super::math::{extract_math, restore_math, restore_math_source, MathSpan};
+use super::placeholder::Placeholders;
+use super::sanitize::sanitize_html;
+use crate::conf::{AppConfig, MarkdownSettings};
+use crate::models::{DocFormat, TocEntry};
+
+// Bump it whenever the generated HTML changes (comrak options, syntect theme...),
+// so that "rerender" job knows which posts are outdated.
+pub const RENDERER_VERSION: i16 = 10;
+pub const FRONT_MATTER_DELIMITER: &str = "---";
+
+static SETTINGS: OnceCell<MarkdownSettings> = OnceCell::new();
+static DIAGRAM_CACHE_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+// Called once at startup. Without it, default settings are used and diagrams are not cached (like in tests).
+pub fn configure(config: &AppConfig) {
+    let already_set = SETTINGS.set(config.markdown.clone()).is_err()
+        || DIAGRAM_CACHE_DIR.set(config.cache_dir.join("diagrams")).is_err();
+    if already_set {
+        tracing::warn!("Markdown settings are already configured");
+    }
+}
+
+fn settings() -> &'static MarkdownSettings {
+    SETTINGS.get_or_init(MarkdownSettings::default)
+}
+
+fn build_options(settings: &MarkdownSettings) -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    options.extension.table = settings.table;
+    options.extension.strikethrough = settings.strikethrough;
+    options.extension.footnotes = settings.footnotes;
+    options.extension.tasklist = settings.tasklist;
+    options.extension.autolink = settings.autolink;
+    if settings.front_matter {
+        options.extension.front_matter_delimiter = Some(FRONT_MATTER_DELIMITER.into());
+    }
+    // Let the text after language in fence info ("```rust {3-5}") reach the highlighter.
+    options.render.full_info_string = true;
+    // Our sanitizer is responsible for dropping unsafe HTML.
+    options.render.unsafe_ = settings.raw_html;
+    options
+}
+
+pub struct RenderedPost {
+    pub html: String,
+    pub excerpt: String,
+    pub toc: Vec<TocEntry>,
+}
+
+// Write headings with "id" derived from their text. Unlike comrak's "header_ids" extension,
+// non-ASCII text (Vietnamese) is transliterated, so "Giới thiệu" becomes "gioi-thieu".
+// Duplicated slugs get "-1", "-2" suffixes. The headings are collected for table of contents.
+pub struct SlugHeadingAdapter {
+    anchorizer: RefCell<Anchorizer>,
+    toc: RefCell<Vec<TocEntry>>,
+    // Math taken out of the document, to put back TeX source in place of placeholders.
+    math: Vec<MathSpan>,
+    math_placeholders: Placeholders,
+}
+
+impl SlugHeadingAdapter {
+    pub fn new(math: Vec<MathSpan>, math_placeholders: Placeholders) -> Self {
+        Self {
+            anchorizer: Default::default(),
+            toc: Default::default(),
+            math,
+            math_placeholders,
+        }
+    }
+
+    pub fn heading_id(&self, text: &str) -> String {
+        let mut slug = slug::slugify(text);
+        if slug.is_empty() {
+            slug.push_str("section");
+        }
+        self.anchorizer.borrow_mut().anchorize(slug)
+    }
+}
+
+impl HeadingAdapter for SlugHeadingAdapter {
+    fn enter(&self, output: &mut dyn Write, heading: &HeadingMeta, _sourcepos: Option<Sourcepos>) -> io::Result<()> {
+        let text = restore_math_source(heading.content.trim(), &self.math, &self.math_placeholders);
+        let id = self.heading_id(&text);
+        write!(output, "<h{} id=\"{id}\">", heading.level)?;
+        self.toc.borrow_mut().push(TocEntry {
+            level: heading.level,
+            id,
+            text,
+        });
+        Ok(())
+    }
+
+    fn exit(&self, output: &mut dyn Write, heading: &HeadingMeta) -> io::Result<()> {
+        write!(output, "</h{}>", heading.level)
+    }
+}
 
 pub fn markdown_to_html(markdown: &str) -> String {
-    let options = ComrakOptions::default();
+    markdown_to_html_with_toc(markdown).0
+}
+
+// The table of contents is empty if headings don't have IDs to link to.
+pub fn markdown_to_html_with_toc(markdown: &str) -> (String, Vec<TocEntry>) {
+    let settings = settings();
+    let options = build_options(settings);
     let mut plugins = ComrakPlugins::default();
-    let adapter = SyntectAdapter::new("base16-ocean.dark");
+    let adapter = ClassedHighlighter::default();
     plugins.render.codefence_syntax_highlighter = Some(&adapter);
-    markdown_to_html_with_plugins(markdown, &options, &plugins)
+    // Math is taken out before parsing, so that TeX is not mangled by Markdown syntax, and diagram code blocks
+    // are taken out of the parsed document. They are put back after sanitizing, because their markup
+    // is generated by us or cleaned separately.
+    let math_placeholders = Placeholders::new("MATH");
+    let (markdown, math) = if settings.math {
+        extract_math(markdown, &math_placeholders)
+    } else {
+        (markdown.to_string(), Vec::new())
+    };
+    let heading_adapter = SlugHeadingAdapter::new(math, math_placeholders);
+    if settings.heading_ids {
+        plugins.render.heading_adapter = Some(&heading_adapter);
+    }
+    let arena = Arena::new();
+    let root = parse_document(&arena, &markdown, &options);
+    let diagram_renderer = DiagramRenderer {
+        settings: &settings.diagrams,
+        cache_dir: DIAGRAM_CACHE_DIR.get().map(PathBuf::as_path),
+    };
+    let diagram_placeholders = Placeholders::new("DIAGRAM");
+    let diagrams = diagram_renderer.extract(root, &arena, &diagram_placeholders);
+    let mut html = Vec::new();
+    format_html_with_plugins(root, &options, &mut html, &plugins)
+        .unwrap_or_else(|e| tracing::error!("Cannot write HTML: {e}"));
+    let html = String::from_utf8_lossy(&html);
+    let html = restore_math(&sanitize_html(&html), &heading_adapter.math, &heading_adapter.math_placeholders);
+    let html = restore_diagrams(&html, &diagrams, &diagram_placeholders);
+    (html, heading_adapter.toc.into_inner())
+}
+
+// Return None if we don't have renderer for the format.
+pub fn render_post(body: &str, format: &DocFormat) -> Option<RenderedPost> {
+    match format {
+        DocFormat::Md => {
+            let (html, toc) = markdown_to_html_with_toc(body);
+            Some(RenderedPost {
+                html,
+                excerpt: make_excerpt(body),
+                toc,
+            })
+        }
+        // The old posts in reStructuredText were rendered by Python docutils.
+        DocFormat::Rst => None,
+    }
+}
+
+// For async code. Rendering may wait for diagram programs, so it runs on the thread pool for blocking work,
+// not to hold up the runtime's workers which serve other requests.
+pub async fn render_post_blocking(body: String, format: DocFormat) -> Result<Option<RenderedPost>, JoinError> {
+    spawn_blocking(move || render_post(&body, &format)).await
+}
+
+// Byte ranges of the lines with code blocks and <pre> blocks, wherever they are nested,
+// for the text processing which must leave code alone.
+pub fn code_ranges(markdown: &str) -> Vec<Range<usize>> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, markdown, &build_options(settings()));
+    let line_starts: Vec<usize> = iter::once(0).chain(markdown.match_indices('\n').map(|(i, _)| i + 1)).collect();
+    let line_start = |line: usize| line_starts.get(line.saturating_sub(1)).copied().unwrap_or(markdown.len());
+    root.descendants()
+        .filter_map(|node| {
+            let ast = node.data.borrow();
+            let is_code = match &ast.value {
+                NodeValue::CodeBlock(_) => true,
+                NodeValue::HtmlBlock(block) => {
+                    block.literal.trim_start().get(..4).is_some_and(|tag| tag.eq_ignore_ascii_case("<pre"))
+                }
+                _ => false,
+            };
+            let Sourcepos { start, end } = ast.sourcepos;
+            is_code.then(|| line_start(start.line)..line_start(end.line + 1))
+        })
+        .collect()
+}
+
+// Return the Markdown content after the front matter block, if any.
+pub fn strip_front_matter(markdown: &str) -> &str {
+    let Some(rest) = markdown.strip_prefix(FRONT_MATTER_DELIMITER) else {
+        return markdown;
+    };
+    let Some(rest) = rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n")) else {
+        return markdown;
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        if line.trim_end() == FRONT_MATTER_DELIMITER {
+            return &rest[offset..];
+        }
+    }
+    markdown
 }
 
 pub fn make_excerpt(markdown: &str) -> String {
+    let markdown = if settings().front_matter {
+        strip_front_matter(markdown).trim_start()
+    } else {
+        markdown
+    };
     let mut lines: Vec<&str> = markdown.lines().take(7).collect();
     // Count "code block" marker (```)
     let count: u8 = lines.iter().map(|ln| ln.starts_with("```") as u8).sum();
@@ -0,0 +1,847 @@
+// Server-side rendering of TeX math to MathML, so that formulas are displayed without client JS.
+// Only the commonly used subset of LaTeX is supported. When a formula uses something else,
+// it is shown as source code instead.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use super::markdown::code_ranges;
+use super::placeholder::Placeholders;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MathError {
+    #[error("unsupported command \\{0}")]
+    UnknownCommand(String),
+    #[error("unsupported environment {0}")]
+    UnknownEnvironment(String),
+    #[error("missing argument for {0}")]
+    MissingArgument(String),
+    #[error("unbalanced braces or delimiters")]
+    Unbalanced,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MathSpan {
+    pub tex: String,
+    pub display: bool,
+}
+
+impl MathSpan {
+    // As written in Markdown, with delimiters.
+    pub fn source(&self) -> String {
+        let delimiter = if self.display { "$$" } else { "$" };
+        format!("{delimiter}{}{delimiter}", self.tex)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Normal,
+    Bold,
+    DoubleStruck,
+    Script,
+    Fraktur,
+    SansSerif,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Command(String),
+    // Command whose argument is taken as is, like \text{...} and \begin{...}.
+    RawCommand(String, String),
+    Letter(char),
+    Digit(char),
+    Symbol(char),
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Ampersand,
+    Prime,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Ident(String, Option<Variant>),
+    Number(String),
+    Operator(String, OperatorKind),
+    Text(String),
+    Space(&'static str),
+    Row(Vec<Node>),
+    Frac(Box<Node>, Box<Node>, bool),
+    Sqrt(Box<Node>, Option<Box<Node>>),
+    Scripts {
+        base: Box<Node>,
+        sub: Option<Box<Node>>,
+        sup: Option<Box<Node>>,
+    },
+    Accent(Box<Node>, &'static str, bool),
+    Fenced(String, Vec<Node>, String),
+    Table {
+        rows: Vec<Vec<Node>>,
+        open: &'static str,
+        close: &'static str,
+        align: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperatorKind {
+    Normal,
+    // Delimiter written without \left, \right: doesn't stretch, like in TeX.
+    Delimiter,
+    // Like \sum, \lim: sub/superscript go under/over in display mode.
+    Limits,
+}
+
+const RAW_ARGUMENT_COMMANDS: &[&str] = &["text", "textrm", "textit", "textbf", "mbox", "operatorname", "begin", "end"];
+
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "α"), ("beta", "β"), ("gamma", "γ"), ("delta", "δ"), ("epsilon", "ϵ"), ("varepsilon", "ε"),
+    ("zeta", "ζ"), ("eta", "η"), ("theta", "θ"), ("vartheta", "ϑ"), ("iota", "ι"), ("kappa", "κ"),
+    ("lambda", "λ"), ("mu", "μ"), ("nu", "ν"), ("xi", "ξ"), ("pi", "π"), ("varpi", "ϖ"), ("rho", "ρ"),
+    ("varrho", "ϱ"), ("sigma", "σ"), ("varsigma", "ς"), ("tau", "τ"), ("upsilon", "υ"), ("phi", "ϕ"),
+    ("varphi", "φ"), ("chi", "χ"), ("psi", "ψ"), ("omega", "ω"), ("Gamma", "Γ"), ("Delta", "Δ"),
+    ("Theta", "Θ"), ("Lambda", "Λ"), ("Xi", "Ξ"), ("Pi", "Π"), ("Sigma", "Σ"), ("Upsilon", "Υ"),
+    ("Phi", "Φ"), ("Psi", "Ψ"), ("Omega", "Ω"), ("infty", "∞"), ("partial", "∂"), ("nabla", "∇"),
+    ("ell", "ℓ"), ("hbar", "ℏ"), ("emptyset", "∅"), ("varnothing", "∅"), ("aleph", "ℵ"), ("Re", "ℜ"),
+    ("Im", "ℑ"),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("pm", "±"), ("mp", "∓"), ("times", "×"), ("div", "÷"), ("cdot", "⋅"), ("ast", "∗"), ("star", "⋆"),
+    ("circ", "∘"), ("bullet", "∙"), ("cap", "∩"), ("cup", "∪"), ("wedge", "∧"), ("land", "∧"), ("vee", "∨"),
+    ("lor", "∨"), ("oplus", "⊕"), ("otimes", "⊗"), ("setminus", "∖"), ("le", "≤"), ("leq", "≤"), ("ge", "≥"),
+    ("geq", "≥"), ("ne", "≠"), ("neq", "≠"), ("equiv", "≡"), ("approx", "≈"), ("sim", "∼"), ("simeq", "≃"),
+    ("cong", "≅"), ("propto", "∝"), ("ll", "≪"), ("gg", "≫"), ("subset", "⊂"), ("supset", "⊃"),
+    ("subseteq", "⊆"), ("supseteq", "⊇"), ("in", "∈"), ("notin", "∉"), ("ni", "∋"), ("to", "→"),
+    ("rightarrow", "→"), ("leftarrow", "←"), ("gets", "←"), ("Rightarrow", "⇒"), ("Leftarrow", "⇐"),
+    ("leftrightarrow", "↔"), ("Leftrightarrow", "⇔"), ("mapsto", "↦"), ("implies", "⟹"), ("iff", "⟺"),
+    ("forall", "∀"), ("exists", "∃"), ("neg", "¬"), ("lnot", "¬"), ("ldots", "…"), ("dots", "…"),
+    ("cdots", "⋯"), ("vdots", "⋮"), ("ddots", "⋱"), ("mid", "∣"), ("parallel", "∥"), ("perp", "⊥"),
+    ("angle", "∠"), ("colon", ":"), ("prime", "′"),
+];
+
+const LARGE_OPERATORS: &[(&str, &str, bool)] = &[
+    ("sum", "∑", true), ("prod", "∏", true), ("coprod", "∐", true), ("bigcup", "⋃", true), ("bigcap", "⋂", true),
+    ("bigoplus", "⨁", true), ("bigotimes", "⨂", true), ("int", "∫", false), ("iint", "∬", false),
+    ("iiint", "∭", false), ("oint", "∮", false),
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh", "coth", "log",
+    "ln", "lg", "exp", "deg", "dim", "ker", "arg", "hom",
+];
+
+const LIMIT_FUNCTIONS: &[&str] = &["lim", "limsup", "liminf", "max", "min", "sup", "inf", "det", "gcd", "Pr"];
+
+const DELIMITERS: &[(&str, &str)] = &[
+    ("{", "{"), ("}", "}"), ("|", "‖"), ("lbrace", "{"), ("rbrace", "}"), ("langle", "⟨"), ("rangle", "⟩"),
+    ("lfloor", "⌊"), ("rfloor", "⌋"), ("lceil", "⌈"), ("rceil", "⌉"), ("vert", "|"), ("Vert", "‖"),
+];
+
+// (command, mark, is accent), stretchy marks are drawn over the whole argument.
+const ACCENTS: &[(&str, &str, bool)] = &[
+    ("hat", "^", true), ("widehat", "^", true), ("bar", "‾", true), ("overline", "‾", true), ("vec", "→", true),
+    ("overrightarrow", "→", true), ("dot", "˙", true), ("ddot", "¨", true), ("tilde", "~", true),
+    ("widetilde", "~", true), ("overbrace", "⏞", true), ("underline", "_", false), ("underbrace", "⏟", false),
+];
+
+const SPACES: &[(&str, &str)] = &[
+    (",", "0.1667em"), (":", "0.2222em"), (">", "0.2222em"), (";", "0.2778em"), (" ", "0.25em"),
+    ("quad", "1em"), ("qquad", "2em"), ("!", "-0.1667em"),
+];
+
+const VARIANTS: &[(&str, Variant)] = &[
+    ("mathbf", Variant::Bold), ("boldsymbol", Variant::Bold), ("bm", Variant::Bold),
+    ("mathbb", Variant::DoubleStruck), ("mathcal", Variant::Script), ("mathscr", Variant::Script),
+    ("mathfrak", Variant::Fraktur), ("mathsf", Variant::SansSerif), ("mathrm", Variant::Normal),
+];
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+fn tokenize(tex: &str) -> Result<Vec<Token>, MathError> {
+    let mut tokens = Vec::new();
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\\' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                    name.push(c);
+                    chars.next();
+                }
+                if name.is_empty() {
+                    name.extend(chars.next());
+                }
+                if RAW_ARGUMENT_COMMANDS.contains(&name.as_str()) {
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    if chars.next() != Some('{') {
+                        return Err(MathError::MissingArgument(format!("\\{name}")));
+                    }
+                    let mut argument = String::new();
+                    let mut depth = 0;
+                    loop {
+                        let c = chars.next().ok_or(MathError::Unbalanced)?;
+                        match c {
+                            '}' if depth == 0 => break,
+                            '}' => depth -= 1,
+                            '{' => depth += 1,
+                            _ => {}
+                        }
+                        argument.push(c);
+                    }
+                    Token::RawCommand(name, argument)
+                } else {
+                    Token::Command(name)
+                }
+            }
+            '{' => Token::Open,
+            '}' => Token::Close,
+            '^' => Token::Sup,
+            '_' => Token::Sub,
+            '&' => Token::Ampersand,
+            '\'' => Token::Prime,
+            '~' => Token::Command(" ".into()),
+            c if c.is_whitespace() => continue,
+            c if c.is_ascii_digit() || c == '.' => Token::Digit(c),
+            c if c.is_alphabetic() => Token::Letter(c),
+            c => Token::Symbol(c),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    variant: Option<Variant>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_row_end(token: Option<&Token>) -> bool {
+        match token {
+            None | Some(Token::Close | Token::Ampersand) => true,
+            Some(Token::Command(name)) => name == "\\" || name == "right",
+            Some(Token::RawCommand(name, _)) => name == "end",
+            _ => false,
+        }
+    }
+
+    // Parse until "}", "&", "\\", "\right", "\end" or end of input, which are left unconsumed.
+    fn parse_row(&mut self) -> Result<Vec<Node>, MathError> {
+        let mut nodes = Vec::new();
+        while !Self::is_row_end(self.peek()) {
+            let node = self.parse_scripts()?;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn parse_scripts(&mut self) -> Result<Node, MathError> {
+        let base = match self.peek() {
+            Some(Token::Sup | Token::Sub) => Node::Row(Vec::new()),
+            _ => self.parse_atom()?,
+        };
+        let (mut sub, mut sup) = (None, None);
+        loop {
+            match self.peek() {
+                Some(Token::Sub) if sub.is_none() => {
+                    self.next();
+                    sub = Some(Box::new(self.parse_argument("_")?));
+                }
+                Some(Token::Sup) if sup.is_none() => {
+                    self.next();
+                    sup = Some(Box::new(self.parse_argument("^")?));
+                }
+                Some(Token::Prime) if sup.is_none() => {
+                    let mut primes = String::new();
+                    while self.peek() == Some(&Token::Prime) {
+                        self.next();
+                        primes.push('′');
+                    }
+                    sup = Some(Box::new(Node::Operator(primes, OperatorKind::Normal)));
+                }
+                _ => break,
+            }
+        }
+        if sub.is_none() && sup.is_none() {
+            return Ok(base);
+        }
+        Ok(Node::Scripts {
+            base: Box::new(base),
+            sub,
+            sup,
+        })
+    }
+
+    // A group in braces, or a single token.
+    fn parse_argument(&mut self, command: &str) -> Result<Node, MathError> {
+        match self.peek() {
+            Some(Token::Open) => {
+                self.next();
+                let nodes = self.parse_row()?;
+                if self.next() != Some(Token::Close) {
+                    return Err(MathError::Unbalanced);
+                }
+                Ok(Node::Row(nodes))
+            }
+            Some(&Token::Digit(d)) => {
+                self.next();
+                Ok(self.number(d.to_string()))
+            }
+            t if Self::is_row_end(t) => Err(MathError::MissingArgument(command.into())),
+            _ => self.parse_atom(),
+        }
+    }
+
+    fn number(&self, digits: String) -> Node {
+        match self.variant {
+            Some(v) if v != Variant::Normal => Node::Number(digits.chars().map(|c| styled_char(c, v)).collect()),
+            _ => Node::Number(digits),
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, MathError> {
+        let token = self.next().ok_or(MathError::Unbalanced)?;
+        let node = match token {
+            Token::Letter(c) => match self.variant {
+                Some(Variant::Normal) => Node::Ident(c.into(), Some(Variant::Normal)),
+                Some(v) => Node::Ident(styled_char(c, v).into(), None),
+                None => Node::Ident(c.into(), None),
+            },
+            Token::Digit(d) => {
+                let mut digits = d.to_string();
+                while let Some(Token::Digit(d)) = self.peek() {
+                    digits.push(*d);
+                    self.next();
+                }
+                self.number(digits)
+            }
+            Token::Symbol(c) => {
+                let kind = if "()[]|".contains(c) {
+                    OperatorKind::Delimiter
+                } else {
+                    OperatorKind::Normal
+                };
+                let op = match c {
+                    '-' => '−',
+                    '*' => '∗',
+                    c => c,
+                };
+                Node::Operator(op.into(), kind)
+            }
+            Token::Open => {
+                let nodes = self.parse_row()?;
+                if self.next() != Some(Token::Close) {
+                    return Err(MathError::Unbalanced);
+                }
+                Node::Row(nodes)
+            }
+            Token::Prime => Node::Operator("′".into(), OperatorKind::Normal),
+            Token::Command(name) => self.parse_command(name)?,
+            Token::RawCommand(name, argument) => self.parse_raw_command(name, argument)?,
+            Token::Close | Token::Sup | Token::Sub | Token::Ampersand => return Err(MathError::Unbalanced),
+        };
+        Ok(node)
+    }
+
+    fn parse_command(&mut self, name: String) -> Result<Node, MathError> {
+        if let Some(s) = lookup(GREEK, &name) {
+            return Ok(Node::Ident(s.into(), None));
+        }
+        if let Some(s) = lookup(OPERATORS, &name) {
+            return Ok(Node::Operator(s.into(), OperatorKind::Normal));
+        }
+        if let Some(s) = lookup(DELIMITERS, &name) {
+            return Ok(Node::Operator(s.into(), OperatorKind::Delimiter));
+        }
+        if let Some(width) = lookup(SPACES, &name) {
+            return Ok(Node::Space(width));
+        }
+        if let Some((_, s, limits)) = LARGE_OPERATORS.iter().find(|(n, _, _)| *n == name) {
+            let kind = if *limits {
+                OperatorKind::Limits
+            } else {
+                OperatorKind::Normal
+            };
+            return Ok(Node::Operator((*s).into(), kind));
+        }
+        if FUNCTIONS.contains(&name.as_str()) {
+            return Ok(Node::Ident(name, Some(Variant::Normal)));
+        }
+        if LIMIT_FUNCTIONS.contains(&name.as_str()) {
+            let text = match name.as_str() {
+                "limsup" => "lim sup".into(),
+                "liminf" => "lim inf".into(),
+                _ => name,
+            };
+            return Ok(Node::Operator(text, OperatorKind::Limits));
+        }
+        if let Some((_, mark, over)) = ACCENTS.iter().find(|(n, _, _)| *n == name) {
+            let base = self.parse_argument(&name)?;
+            return Ok(Node::Accent(Box::new(base), mark, *over));
+        }
+        if name == "mathit" || name == "mathnormal" {
+            let outer = self.variant.take();
+            let argument = self.parse_argument(&name);
+            self.variant = outer;
+            return argument;
+        }
+        if let Some(variant) = lookup(VARIANTS, &name) {
+            let outer = self.variant.replace(variant);
+            let argument = self.parse_argument(&name);
+            self.variant = outer;
+            return argument;
+        }
+        match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "binom" => {
+                let numerator = self.parse_argument(&name)?;
+                let denominator = self.parse_argument(&name)?;
+                let binom = name == "binom";
+                let frac = Node::Frac(Box::new(numerator), Box::new(denominator), binom);
+                if binom {
+                    return Ok(Node::Fenced("(".into(), vec![frac], ")".into()));
+                }
+                Ok(frac)
+            }
+            "sqrt" => {
+                let index = if self.peek() == Some(&Token::Symbol('[')) {
+                    self.next();
+                    let mut nodes = Vec::new();
+                    while self.peek() != Some(&Token::Symbol(']')) {
+                        if Self::is_row_end(self.peek()) {
+                            return Err(MathError::Unbalanced);
+                        }
+                        nodes.push(self.parse_scripts()?);
+                    }
+                    self.next();
+                    Some(Box::new(Node::Row(nodes)))
+                } else {
+                    None
+                };
+                let base = self.parse_argument(&name)?;
+                Ok(Node::Sqrt(Box::new(base), index))
+            }
+            "left" => {
+                let open = self.parse_delimiter(&name)?;
+                let nodes = self.parse_row()?;
+                if self.next() != Some(Token::Command("right".into())) {
+                    return Err(MathError::Unbalanced);
+                }
+                let close = self.parse_delimiter("right")?;
+                Ok(Node::Fenced(open, nodes, close))
+            }
+            "%" | "$" | "#" | "&" | "_" => Ok(Node::Ident(name, Some(Variant::Normal))),
+            // Sizing commands: MathML stretches the delimiters by itself.
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "displaystyle" | "textstyle"
+            | "limits" | "nolimits" => Ok(Node::Row(Vec::new())),
+            _ => Err(MathError::UnknownCommand(name)),
+        }
+    }
+
+    fn parse_delimiter(&mut self, command: &str) -> Result<String, MathError> {
+        match self.next() {
+            Some(Token::Symbol('.')) | Some(Token::Digit('.')) => Ok(String::new()),
+            Some(Token::Symbol(c)) => Ok(c.into()),
+            Some(Token::Command(name)) => lookup(DELIMITERS, &name)
+                .map(String::from)
+                .ok_or(MathError::UnknownCommand(name)),
+            _ => Err(MathError::MissingArgument(format!("\\{command}"))),
+        }
+    }
+
+    fn parse_raw_command(&mut self, name: String, argument: String) -> Result<Node, MathError> {
+        match name.as_str() {
+            "operatorname" => Ok(Node::Ident(argument, Some(Variant::Normal))),
+            "begin" => self.parse_environment(argument),
+            "end" => Err(MathError::Unbalanced),
+            _ => Ok(Node::Text(argument)),
+        }
+    }
+
+    fn parse_environment(&mut self, env: String) -> Result<Node, MathError> {
+        let (open, close, align) = match env.as_str() {
+            "matrix" | "smallmatrix" => ("", "", "center"),
+            "pmatrix" => ("(", ")", "center"),
+            "bmatrix" => ("[", "]", "center"),
+            "Bmatrix" => ("{", "}", "center"),
+            "vmatrix" => ("|", "|", "center"),
+            "Vmatrix" => ("‖", "‖", "center"),
+            "cases" => ("{", "", "left"),
+            "aligned" | "align" | "align*" | "split" => ("", "", "right left"),
+            _ => return Err(MathError::UnknownEnvironment(env)),
+        };
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        loop {
+            row.push(Node::Row(self.parse_row()?));
+            match self.next() {
+                Some(Token::Ampersand) => {}
+                Some(Token::Command(name)) if name == "\\" => rows.push(std::mem::take(&mut row)),
+                Some(Token::RawCommand(name, end)) if name == "end" && end == env => {
+                    rows.push(row);
+                    break;
+                }
+                _ => return Err(MathError::Unbalanced),
+            }
+        }
+        Ok(Node::Table {
+            rows,
+            open,
+            close,
+            align,
+        })
+    }
+}
+
+// Letters in styles like bold and double-struck are distinct Unicode characters.
+fn styled_char(c: char, variant: Variant) -> char {
+    let exception = match (variant, c) {
+        (Variant::DoubleStruck, 'C') => Some('ℂ'),
+        (Variant::DoubleStruck, 'H') => Some('ℍ'),
+        (Variant::DoubleStruck, 'N') => Some('ℕ'),
+        (Variant::DoubleStruck, 'P') => Some('ℙ'),
+        (Variant::DoubleStruck, 'Q') => Some('ℚ'),
+        (Variant::DoubleStruck, 'R') => Some('ℝ'),
+        (Variant::DoubleStruck, 'Z') => Some('ℤ'),
+        (Variant::Script, 'B') => Some('ℬ'),
+        (Variant::Script, 'E') => Some('ℰ'),
+        (Variant::Script, 'F') => Some('ℱ'),
+        (Variant::Script, 'H') => Some('ℋ'),
+        (Variant::Script, 'I') => Some('ℐ'),
+        (Variant::Script, 'L') => Some('ℒ'),
+        (Variant::Script, 'M') => Some('ℳ'),
+        (Variant::Script, 'R') => Some('ℛ'),
+        (Variant::Script, 'e') => Some('ℯ'),
+        (Variant::Script, 'g') => Some('ℊ'),
+        (Variant::Script, 'o') => Some('ℴ'),
+        (Variant::Fraktur, 'C') => Some('ℭ'),
+        (Variant::Fraktur, 'H') => Some('ℌ'),
+        (Variant::Fraktur, 'I') => Some('ℑ'),
+        (Variant::Fraktur, 'R') => Some('ℜ'),
+        (Variant::Fraktur, 'Z') => Some('ℨ'),
+        _ => None,
+    };
+    if let Some(c) = exception {
+        return c;
+    }
+    // Start of A-Z, a-z and 0-9 in Mathematical Alphanumeric Symbols block.
+    let (upper, lower, digit) = match variant {
+        Variant::Bold => (0x1D400, 0x1D41A, Some(0x1D7CE)),
+        Variant::DoubleStruck => (0x1D538, 0x1D552, Some(0x1D7D8)),
+        Variant::Script => (0x1D49C, 0x1D4B6, None),
+        Variant::Fraktur => (0x1D504, 0x1D51E, None),
+        Variant::SansSerif => (0x1D5A0, 0x1D5BA, Some(0x1D7E2)),
+        Variant::Normal => return c,
+    };
+    let code = match c {
+        'A'..='Z' => upper + (c as u32 - 'A' as u32),
+        'a'..='z' => lower + (c as u32 - 'a' as u32),
+        '0'..='9' => match digit {
+            Some(d) => d + (c as u32 - '0' as u32),
+            None => return c,
+        },
+        _ => return c,
+    };
+    char::from_u32(code).unwrap_or(c)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn write_node(out: &mut String, node: &Node) {
+    match node {
+        Node::Ident(name, Some(Variant::Normal)) => {
+            let _ = write!(out, "<mi mathvariant=\"normal\">{}</mi>", escape(name));
+            // Function application, so that "sin x" is spaced properly.
+            if name.chars().count() > 1 {
+                out.push_str("<mo>&#x2061;</mo>");
+            }
+        }
+        Node::Ident(name, _) => {
+            let _ = write!(out, "<mi>{}</mi>", escape(name));
+        }
+        Node::Number(n) => {
+            let _ = write!(out, "<mn>{}</mn>", escape(n));
+        }
+        Node::Operator(op, OperatorKind::Delimiter) => {
+            let _ = write!(out, "<mo stretchy=\"false\">{}</mo>", escape(op));
+        }
+        Node::Operator(op, OperatorKind::Limits) if op.chars().all(char::is_alphabetic) || op.contains(' ') => {
+            let _ = write!(out, "<mo form=\"prefix\" movablelimits=\"true\">{}</mo>", escape(op));
+        }
+        Node::Operator(op, _) => {
+            let _ = write!(out, "<mo>{}</mo>", escape(op));
+        }
+        Node::Text(text) => {
+            let _ = write!(out, "<mtext>{}</mtext>", escape(text));
+        }
+        Node::Space(width) => {
+            let _ = write!(out, "<mspace width=\"{width}\"></mspace>");
+        }
+        Node::Row(nodes) => {
+            out.push_str("<mrow>");
+            for n in nodes {
+                write_node(out, n);
+            }
+            out.push_str("</mrow>");
+        }
+        Node::Frac(numerator, denominator, binom) => {
+            out.push_str(if *binom { "<mfrac linethickness=\"0\">" } else { "<mfrac>" });
+            write_node(out, numerator);
+            write_node(out, denominator);
+            out.push_str("</mfrac>");
+        }
+        Node::Sqrt(base, None) => {
+            out.push_str("<msqrt>");
+            write_node(out, base);
+            out.push_str("</msqrt>");
+        }
+        Node::Sqrt(base, Some(index)) => {
+            out.push_str("<mroot>");
+            write_node(out, base);
+            write_node(out, index);
+            out.push_str("</mroot>");
+        }
+        Node::Scripts { base, sub, sup } => {
+            let limits = matches!(**base, Node::Operator(_, OperatorKind::Limits));
+            let tag = match (sub, sup, limits) {
+                (Some(_), Some(_), true) => "munderover",
+                (Some(_), None, true) => "munder",
+                (None, Some(_), true) => "mover",
+                (Some(_), Some(_), false) => "msubsup",
+                (Some(_), None, false) => "msub",
+                _ => "msup",
+            };
+            let _ = write!(out, "<{tag}>");
+            write_node(out, base);
+            for script in [sub, sup].into_iter().flatten() {
+                write_node(out, script);
+            }
+            let _ = write!(out, "</{tag}>");
+        }
+        Node::Accent(base, mark, over) => {
+            let tag = if *over { "mover" } else { "munder" };
+            let attribute = if *over { "accent" } else { "accentunder" };
+            let _ = write!(out, "<{tag} {attribute}=\"true\">");
+            write_node(out, base);
+            let _ = write!(out, "<mo stretchy=\"true\">{}</mo></{tag}>", escape(mark));
+        }
+        Node::Fenced(open, nodes, close) => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\" form=\"prefix\">{}</mo>", escape(open));
+            }
+            for n in nodes {
+                write_node(out, n);
+            }
+            if !close.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\" form=\"postfix\">{}</mo>", escape(close));
+            }
+            out.push_str("</mrow>");
+        }
+        Node::Table {
+            rows,
+            open,
+            close,
+            align,
+        } => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\" form=\"prefix\">{}</mo>", escape(open));
+            }
+            let _ = write!(out, "<mtable columnalign=\"{align}\">");
+            for row in rows {
+                out.push_str("<mtr>");
+                for cell in row {
+                    out.push_str("<mtd>");
+                    write_node(out, cell);
+                    out.push_str("</mtd>");
+                }
+                out.push_str("</mtr>");
+            }
+            out.push_str("</mtable>");
+            if !close.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\" form=\"postfix\">{}</mo>", escape(close));
+            }
+            out.push_str("</mrow>");
+        }
+    }
+}
+
+pub fn tex_to_mathml(tex: &str, display: bool) -> Result<String, MathError> {
+    let mut parser = Parser {
+        tokens: tokenize(tex)?,
+        pos: 0,
+        variant: None,
+    };
+    let nodes = parser.parse_row()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(match parser.peek() {
+            Some(Token::Command(name)) if name == "\\" => MathError::UnknownCommand(name.clone()),
+            _ => MathError::Unbalanced,
+        });
+    }
+    let mut out = String::new();
+    let display_attr = if display { " display=\"block\"" } else { "" };
+    let _ = write!(out, "<math{display_attr}><semantics>");
+    write_node(&mut out, &Node::Row(nodes));
+    let _ = write!(out, "<annotation encoding=\"application/x-tex\">{}</annotation></semantics></math>", escape(tex));
+    Ok(out)
+}
+
+// Render to MathML, or fall back to showing the source if the formula is not supported.
+pub fn render_math(span: &MathSpan) -> String {
+    match tex_to_mathml(&span.tex, span.display) {
+        Ok(mathml) => mathml,
+        Err(e) => {
+            tracing::debug!("Cannot convert math {:?} to MathML: {e}", span.tex);
+            let source = escape(&span.source());
+            let title = escape(&e.to_string());
+            if span.display {
+                format!("<pre class=\"math-fallback\" title=\"{title}\"><code>{source}</code></pre>")
+            } else {
+                format!("<code class=\"math-fallback\" title=\"{title}\">{source}</code>")
+            }
+        }
+    }
+}
+
+// Replace "$...$" (inline) and "$$...$$" (display) math outside of code with placeholders,
+// so that TeX syntax (like "_" and "\\") is not interpreted as Markdown.
+// Code is code blocks (also nested in lists and quotes), <pre> blocks, code spans and raw <code>, <pre> elements.
+// Like Pandoc, the opening "$" must not be followed by a space, and the closing "$"
+// must not be preceded by a space or followed by a digit, so that "$5 and $10" stay as is.
+pub fn extract_math(markdown: &str, placeholders: &Placeholders) -> (String, Vec<MathSpan>) {
+    let mut out = String::with_capacity(markdown.len());
+    let mut spans = Vec::new();
+    let code_blocks = code_ranges(markdown);
+    let mut code_blocks = code_blocks.iter().peekable();
+    let mut pos = 0;
+    while pos < markdown.len() {
+        while code_blocks.next_if(|r| r.end <= pos).is_some() {}
+        if let Some(block) = code_blocks.next_if(|r| r.start <= pos) {
+            // Copy the code block as is.
+            out.push_str(&markdown[pos..block.end]);
+            pos = block.end;
+            continue;
+        }
+        // Neither math nor code span runs into the next code block.
+        let text = &markdown[pos..code_blocks.peek().map_or(markdown.len(), |r| r.start)];
+        let c = text.chars().next().unwrap_or_default();
+        let consumed = match c {
+            '\\' => text.chars().nth(1).map_or(1, |n| 1 + n.len_utf8()),
+            '`' => {
+                let run = text.len() - text.trim_start_matches('`').len();
+                let ticks = &text[..run];
+                let mut search = run;
+                let mut end = None;
+                while let Some(i) = text[search..].find(ticks) {
+                    let start = search + i;
+                    let close_run = text[start..].len() - text[start..].trim_start_matches('`').len();
+                    if close_run == run {
+                        end = Some(start + run);
+                        break;
+                    }
+                    search = start + close_run;
+                }
+                end.unwrap_or(run)
+            }
+            '<' => raw_code_element(text).unwrap_or(1),
+            '$' => match find_math(text) {
+                Some((span, len)) => {
+                    out.push_str(&placeholders.get(spans.len()));
+                    spans.push(span);
+                    pos += len;
+                    continue;
+                }
+                None => 1,
+            },
+            _ => c.len_utf8(),
+        };
+        out.push_str(&text[..consumed]);
+        pos += consumed;
+    }
+    (out, spans)
+}
+
+// Length of the <code> or <pre> element written as HTML at the start of "text", up to its end tag.
+fn raw_code_element(text: &str) -> Option<usize> {
+    let after = text.strip_prefix('<')?;
+    ["code", "pre"].into_iter().find_map(|tag| {
+        let is_start_tag = after.get(..tag.len()).is_some_and(|name| name.eq_ignore_ascii_case(tag))
+            && after[tag.len()..].starts_with(|c: char| c == '>' || c.is_ascii_whitespace());
+        if !is_start_tag {
+            return None;
+        }
+        let close = format!("</{tag}>");
+        text.to_ascii_lowercase().find(&close).map(|i| i + close.len())
+    })
+}
+
+// Return the math at the start of "text" and the number of bytes it spans.
+fn find_math(text: &str) -> Option<(MathSpan, usize)> {
+    if let Some(inner) = text.strip_prefix("$$") {
+        let end = inner.find("$$")?;
+        let tex = inner[..end].trim();
+        if tex.is_empty() {
+            return None;
+        }
+        let span = MathSpan {
+            tex: tex.into(),
+            display: true,
+        };
+        return Some((span, end + 4));
+    }
+    let inner = &text[1..];
+    if inner.starts_with(char::is_whitespace) {
+        return None;
+    }
+    // Inline math doesn't span paragraphs, or run into code span.
+    let limit = [inner.find("\n\n"), inner.find('`')].into_iter().flatten().min().unwrap_or(inner.len());
+    let bytes = inner.as_bytes();
+    let end = inner[..limit].match_indices('$').map(|(i, _)| i).find(|&i| {
+        i > 0
+            && !bytes[i - 1].is_ascii_whitespace()
+            && bytes[i - 1] != b'\\'
+            && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit)
+    })?;
+    let span = MathSpan {
+        tex: inner[..end].into(),
+        display: false,
+    };
+    Some((span, end + 2))
+}
+
+// Put rendered math in place of the placeholders in sanitized HTML.
+// Those in attributes, like <a title="$x$">, get back the TeX source.
+pub fn restore_math(html: &str, spans: &[MathSpan], placeholders: &Placeholders) -> String {
+    placeholders.restore(
+        html,
+        |index| spans.get(index).map(render_math),
+        |index| spans.get(index).map(|span| escape(&span.source())),
+    )
+}
+
+// For plain text, like heading in table of contents.
+pub fn restore_math_source(text: &str, spans: &[MathSpan], placeholders: &Placeholders) -> String {
+    placeholders
+        .replace(text, |index| spans.get(index).map(|span| span.tex.clone()))
+        .into_owned()
+}
@@ -1,4 +1,12 @@
 pub mod urls;
+pub mod diagram;
+pub mod highlight;
 pub mod markdown;
+pub mod math;
+pub mod placeholder;
+pub mod sanitize;
 pub mod validation;
 pub mod jinja_extra;
+pub mod routing;
+#[cfg(test)]
+pub mod tests;
@@ -0,0 +1,145 @@
+// Content which must not go through Markdown parser (math, diagrams) is replaced with placeholders
+// like "%%MATH3f9a0c1d5e7b2468-0%%" beforehand, and put back into the sanitized HTML.
+// The placeholders have a random part, so that post authors cannot type them to inject our markup,
+// and they are only put back in text, not inside tag attributes where markup would break out of quotes.
+
+use std::borrow::Cow;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+const SUFFIX: &str = "%%";
+const NONCE_BYTES: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholders {
+    // Like "%%MATH3f9a0c1d5e7b2468-", followed by index and suffix.
+    prefix: String,
+}
+
+enum Segment<'a> {
+    Tag(&'a str),
+    Text(&'a str),
+}
+
+impl Placeholders {
+    pub fn new(name: &str) -> Self {
+        let mut bytes = [0u8; NONCE_BYTES];
+        if SystemRandom::new().fill(&mut bytes).is_err() {
+            // Still safe, because placeholders in attributes are not replaced with markup.
+            tracing::error!("Failed to generate random part of placeholders");
+        }
+        let nonce: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        Self {
+            prefix: format!("%%{name}{nonce}-"),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> String {
+        format!("{}{index}{SUFFIX}", self.prefix)
+    }
+
+    // Replace the placeholders in plain text (not HTML), with the result of "replacement" for their index.
+    // Those which "replacement" doesn't know are left as is.
+    pub fn replace<'t>(&self, text: &'t str, mut replacement: impl FnMut(usize) -> Option<String>) -> Cow<'t, str> {
+        if !text.contains(&self.prefix) {
+            return Cow::Borrowed(text);
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(pos) = rest.find(&self.prefix) {
+            out.push_str(&rest[..pos]);
+            let after = &rest[pos + self.prefix.len()..];
+            let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let index = after[..digits].parse().ok().filter(|_| after[digits..].starts_with(SUFFIX));
+            let found = index.and_then(&mut replacement);
+            match found {
+                Some(value) => {
+                    out.push_str(&value);
+                    rest = &after[digits + SUFFIX.len()..];
+                }
+                None => {
+                    out.push_str(&rest[pos..pos + self.prefix.len()]);
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        Cow::Owned(out)
+    }
+
+    // Put content back into sanitized HTML. In text, placeholders are replaced with "markup".
+    // In tag attributes, they are replaced with "attribute", which must be escaped already.
+    pub fn restore(
+        &self,
+        html: &str,
+        mut markup: impl FnMut(usize) -> Option<String>,
+        mut attribute: impl FnMut(usize) -> Option<String>,
+    ) -> String {
+        let mut out = String::with_capacity(html.len());
+        for segment in split_tags(html) {
+            match segment {
+                Segment::Tag(tag) => out.push_str(&self.replace(tag, &mut attribute)),
+                Segment::Text(text) => out.push_str(&self.replace(text, &mut markup)),
+            }
+        }
+        out
+    }
+
+    // Remove the <p> around placeholders which make a paragraph of their own,
+    // for content which is a block itself, like <figure>.
+    pub fn unwrap_paragraphs(&self, html: &str) -> String {
+        let segments: Vec<Segment> = split_tags(html).collect();
+        let mut out = String::with_capacity(html.len());
+        let mut i = 0;
+        while i < segments.len() {
+            if let [Segment::Tag("<p>"), Segment::Text(text), Segment::Tag("</p>"), ..] = &segments[i..] {
+                if self.is_placeholder(text) {
+                    out.push_str(text);
+                    i += 3;
+                    continue;
+                }
+            }
+            match segments[i] {
+                Segment::Tag(s) | Segment::Text(s) => out.push_str(s),
+            }
+            i += 1;
+        }
+        out
+    }
+
+    fn is_placeholder(&self, text: &str) -> bool {
+        text.strip_prefix(&self.prefix)
+            .and_then(|rest| rest.strip_suffix(SUFFIX))
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+    }
+}
+
+// Split HTML serialized by the sanitizer into tags and text. Text there never contains "<",
+// and attribute values are always double-quoted, so a full parser is not needed.
+fn split_tags(html: &str) -> impl Iterator<Item = Segment<'_>> {
+    let mut rest = html;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let (text, after) = rest.split_at(end);
+            rest = after;
+            return Some(Segment::Text(text));
+        }
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c == '>' && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i + 1);
+        let (tag, after) = rest.split_at(end);
+        rest = after;
+        Some(Segment::Tag(tag))
+    })
+}
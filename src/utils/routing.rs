@@ -0,0 +1,89 @@
+use axum::handler::Handler;
+use axum::http::Method;
+use axum::routing::{self, MethodRouter, Router};
+
+// Thin wrappers over axum routing functions, which also remember paths and methods,
+// so that the route table can be printed by "routes" command.
+#[derive(Debug, Clone)]
+pub struct RouteInfo {
+    pub path: String,
+    pub methods: Vec<Method>,
+}
+
+pub struct Endpoint<S> {
+    methods: Vec<Method>,
+    inner: MethodRouter<S>,
+}
+
+macro_rules! endpoint_method {
+    ($name:ident, $method:ident) => {
+        #[allow(dead_code)]
+        pub fn $name<H, T, S>(handler: H) -> Endpoint<S>
+        where
+            H: Handler<T, S>,
+            T: 'static,
+            S: Clone + Send + Sync + 'static,
+        {
+            Endpoint {
+                methods: vec![Method::$method],
+                inner: routing::$name(handler),
+            }
+        }
+
+        impl<S: Clone + Send + Sync + 'static> Endpoint<S> {
+            #[allow(dead_code)]
+            pub fn $name<H, T>(mut self, handler: H) -> Self
+            where
+                H: Handler<T, S>,
+                T: 'static,
+            {
+                self.methods.push(Method::$method);
+                self.inner = self.inner.$name(handler);
+                self
+            }
+        }
+    };
+}
+
+endpoint_method!(get, GET);
+endpoint_method!(post, POST);
+endpoint_method!(put, PUT);
+endpoint_method!(patch, PATCH);
+endpoint_method!(delete, DELETE);
+
+pub struct RouteTable<S> {
+    router: Router<S>,
+    routes: Vec<RouteInfo>,
+}
+
+impl<S: Clone + Send + Sync + 'static> RouteTable<S> {
+    pub fn new() -> Self {
+        Self {
+            router: Router::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn route(mut self, path: &str, endpoint: Endpoint<S>) -> Self {
+        self.routes.push(RouteInfo {
+            path: path.into(),
+            methods: endpoint.methods,
+        });
+        self.router = self.router.route(path, endpoint.inner);
+        self
+    }
+
+    pub fn routes(&self) -> &[RouteInfo] {
+        &self.routes
+    }
+
+    pub fn into_router(self) -> Router<S> {
+        self.router
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> Default for RouteTable<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
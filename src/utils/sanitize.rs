@@ -0,0 +1,184 @@
+// Allowlist-based HTML sanitizer for rendered post content. Markdown may contain raw HTML,
+// so anything outside the allowlist is removed before the HTML reaches the browser:
+// - Unknown tags are dropped, but their text is kept.
+// - Tags like <script>, <style> are dropped together with their content.
+// - Only listed attributes are kept, and URLs must be relative or use a safe scheme.
+// - Only the classes used by our stylesheets are kept, so posts cannot borrow the site's UI classes.
+// Parsing and serializing is done by ammonia (html5ever), like a browser would do.
+// SVG generated by diagram renderers is cleaned with its own allowlist, see `sanitize_svg`.
+
+use std::borrow::Cow;
+
+use ammonia::Builder;
+use once_cell::sync::Lazy;
+
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "abbr", "b", "blockquote", "br", "caption", "cite", "code", "dd", "del", "details", "div", "dl", "dt", "em",
+    "figcaption", "figure", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "input", "ins", "kbd", "li", "mark",
+    "ol", "p", "pre", "q", "s", "samp", "section", "small", "span", "strong", "sub", "summary", "sup", "table",
+    "tbody", "td", "tfoot", "th", "thead", "tr", "u", "ul", "var",
+];
+
+// Their content is not meant to be displayed as text, or is dangerous.
+const DROPPED_WITH_CONTENT: &[&str] = &[
+    "script", "style", "iframe", "object", "embed", "template", "noscript", "textarea", "title", "xmp", "noembed",
+    "noframes", "frameset", "select", "svg", "math",
+];
+
+const GLOBAL_ATTRIBUTES: &[&str] = &["class", "title", "lang", "aria-label", "aria-hidden"];
+
+// Attributes allowed on specific tags.
+const TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("a", &["href", "id", "data-footnote-ref", "data-footnote-backref"]),
+    ("img", &["src", "alt", "width", "height"]),
+    ("input", &["type", "checked", "disabled"]),
+    ("ol", &["start"]),
+    ("li", &["id", "value"]),
+    ("td", &["align", "colspan", "rowspan"]),
+    ("th", &["align", "colspan", "rowspan"]),
+    ("details", &["open"]),
+    ("h1", &["id"]),
+    ("h2", &["id"]),
+    ("h3", &["id"]),
+    ("h4", &["id"]),
+    ("h5", &["id"]),
+    ("h6", &["id"]),
+];
+
+// Classes from syntax highlighter ("hl-"), from comrak for code language and footnotes,
+// and the ones we put on math and diagrams.
+const CLASS_PREFIXES: &[&str] = &["hl-", "language-"];
+const CLASSES: &[&str] = &["math-fallback", "diagram", "footnotes", "footnote-ref", "footnote-backref"];
+
+const SAFE_URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+static HTML_SANITIZER: Lazy<Builder<'static>> = Lazy::new(|| {
+    let mut builder = Builder::empty();
+    builder
+        .tags(ALLOWED_TAGS.iter().copied().collect())
+        .clean_content_tags(DROPPED_WITH_CONTENT.iter().copied().collect())
+        .generic_attributes(GLOBAL_ATTRIBUTES.iter().copied().collect())
+        .tag_attributes(
+            TAG_ATTRIBUTES
+                .iter()
+                .map(|(tag, attributes)| (*tag, attributes.iter().copied().collect()))
+                .collect(),
+        )
+        .url_schemes(SAFE_URL_SCHEMES.iter().copied().collect())
+        .link_rel(None)
+        .attribute_filter(filter_html_attribute);
+    builder
+});
+
+pub fn sanitize_html(html: &str) -> String {
+    HTML_SANITIZER.clean(html).to_string()
+}
+
+fn is_allowed_class(class: &str) -> bool {
+    CLASSES.contains(&class) || CLASS_PREFIXES.iter().any(|prefix| class.starts_with(prefix))
+}
+
+// Runs on the attributes which passed the allowlist, to check their values.
+fn filter_html_attribute<'u>(tag: &str, attribute: &str, value: &'u str) -> Option<Cow<'u, str>> {
+    match (tag, attribute) {
+        (_, "class") => {
+            let classes: Vec<&str> = value.split_ascii_whitespace().filter(|c| is_allowed_class(c)).collect();
+            (!classes.is_empty()).then(|| classes.join(" ").into())
+        }
+        // Only the IDs which comrak gives to footnotes, headings' IDs are ours.
+        ("a", "id") => value.starts_with("fnref-").then_some(value.into()),
+        ("li", "id") => value.starts_with("fn-").then_some(value.into()),
+        ("input", "type") => (value == "checkbox").then_some(value.into()),
+        _ => Some(value.into()),
+    }
+}
+
+// Drawing elements, as written by Graphviz, Mermaid, PlantUML. Anything else is dropped, including
+// <style> and <foreignObject> (HTML inside SVG), so Mermaid needs "htmlLabels" turned off.
+const SVG_TAGS: &[&str] = &[
+    "svg", "g", "defs", "symbol", "use", "title", "desc", "a", "path", "rect", "circle", "ellipse", "line",
+    "polyline", "polygon", "text", "tspan", "textPath", "marker", "linearGradient", "radialGradient", "stop",
+    "clipPath", "mask", "pattern",
+];
+
+// Animation elements can set "href" to a "javascript:" URL.
+const SVG_DROPPED_WITH_CONTENT: &[&str] = &[
+    "script", "style", "foreignObject", "iframe", "object", "embed", "template", "noscript", "animate",
+    "animateMotion", "animateTransform", "set", "handler", "listener",
+];
+
+// Presentation attributes. Names are case-sensitive, as html5ever gives them ("viewBox").
+const SVG_GLOBAL_ATTRIBUTES: &[&str] = &[
+    "id", "transform", "style", "x", "y", "width", "height", "fill", "fill-opacity", "fill-rule", "stroke",
+    "stroke-width", "stroke-opacity", "stroke-dasharray", "stroke-dashoffset", "stroke-linecap", "stroke-linejoin",
+    "stroke-miterlimit", "opacity", "clip-path", "clip-rule", "mask", "marker-start", "marker-mid", "marker-end",
+    "font-family", "font-size", "font-weight", "font-style", "text-anchor", "dominant-baseline",
+    "alignment-baseline", "text-decoration", "letter-spacing", "visibility", "display",
+];
+
+const SVG_TAG_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("svg", &["viewBox", "preserveAspectRatio"]),
+    ("symbol", &["viewBox", "preserveAspectRatio"]),
+    ("use", &["href"]),
+    ("a", &["href"]),
+    ("path", &["d", "pathLength"]),
+    ("rect", &["rx", "ry"]),
+    ("circle", &["cx", "cy", "r"]),
+    ("ellipse", &["cx", "cy", "rx", "ry"]),
+    ("line", &["x1", "y1", "x2", "y2"]),
+    ("polyline", &["points"]),
+    ("polygon", &["points"]),
+    ("text", &["dx", "dy", "rotate", "textLength", "lengthAdjust"]),
+    ("tspan", &["dx", "dy", "rotate", "textLength", "lengthAdjust"]),
+    ("textPath", &["href", "startOffset"]),
+    (
+        "marker",
+        &["viewBox", "preserveAspectRatio", "refX", "refY", "markerWidth", "markerHeight", "markerUnits", "orient"],
+    ),
+    ("linearGradient", &["href", "x1", "y1", "x2", "y2", "gradientUnits", "gradientTransform", "spreadMethod"]),
+    ("radialGradient", &["href", "cx", "cy", "r", "fx", "fy", "gradientUnits", "gradientTransform", "spreadMethod"]),
+    ("stop", &["offset", "stop-color", "stop-opacity"]),
+    ("clipPath", &["clipPathUnits"]),
+    ("mask", &["maskUnits", "maskContentUnits"]),
+    ("pattern", &["viewBox", "patternUnits", "patternContentUnits", "patternTransform"]),
+];
+
+// In "style" attribute. Not "position" and the like, which could cover the page.
+const SVG_STYLE_PROPERTIES: &[&str] = &[
+    "fill", "fill-opacity", "stroke", "stroke-width", "stroke-opacity", "stroke-dasharray", "stroke-linecap",
+    "stroke-linejoin", "opacity", "font-family", "font-size", "font-weight", "font-style", "text-anchor",
+];
+
+static SVG_SANITIZER: Lazy<Builder<'static>> = Lazy::new(|| {
+    let mut builder = Builder::empty();
+    builder
+        .tags(SVG_TAGS.iter().copied().collect())
+        .clean_content_tags(SVG_DROPPED_WITH_CONTENT.iter().copied().collect())
+        .generic_attributes(SVG_GLOBAL_ATTRIBUTES.iter().copied().collect())
+        .tag_attributes(
+            SVG_TAG_ATTRIBUTES
+                .iter()
+                .map(|(tag, attributes)| (*tag, attributes.iter().copied().collect()))
+                .collect(),
+        )
+        .filter_style_properties(SVG_STYLE_PROPERTIES.iter().copied().collect())
+        .url_schemes(SAFE_URL_SCHEMES.iter().copied().collect())
+        .link_rel(None)
+        .attribute_filter(filter_svg_attribute);
+    builder
+});
+
+// For SVG made by diagram renderers from post content: keep the drawing,
+// but drop scripts, styles, event handlers and unsafe links.
+pub fn sanitize_svg(svg: &str) -> String {
+    SVG_SANITIZER.clean(svg).to_string()
+}
+
+// Except for links, "href" can only point to elements in the same SVG, like "#arrow".
+fn filter_svg_attribute<'u>(tag: &str, attribute: &str, value: &'u str) -> Option<Cow<'u, str>> {
+    match (tag, attribute) {
+        ("a", "href") => Some(value.into()),
+        (_, "href") => value.starts_with('#').then_some(value.into()),
+        _ => Some(value.into()),
+    }
+}
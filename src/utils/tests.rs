@@ -0,0 +1,194 @@
+use comrak::{format_html, parse_document, Arena, ComrakOptions};
+
+use super::diagram::{restore_diagrams, DiagramRenderer};
+use super::highlight::{theme_css, FenceOptions};
+use super::markdown::{markdown_to_html, markdown_to_html_with_toc, strip_front_matter};
+use crate::conf::DiagramSettings;
+use crate::models::TocEntry;
+use super::math::{extract_math, tex_to_mathml, MathSpan};
+use super::placeholder::Placeholders;
+use super::sanitize::{sanitize_html, sanitize_svg};
+use crate::config_jinja;
+
+#[test]
+fn sanitizer_drops_scripts_and_unsafe_urls() {
+    let html = r#"<p onclick="alert(1)">Hi<script>alert("x")</script></p><a href="java&#x09;script:alert(1)">link</a><a href="/blog/">ok</a>"#;
+    let cleaned = sanitize_html(html);
+    assert_eq!(cleaned, r#"<p>Hi</p><a>link</a><a href="/blog/">ok</a>"#);
+}
+
+#[test]
+fn sanitizer_closes_unbalanced_tags() {
+    // Like browsers do, a stray "</p>" opens an empty paragraph, and <em> is reopened for the rest.
+    assert_eq!(
+        sanitize_html("<div><em>text</div></p> 1 < 2"),
+        "<div><em>text</em></div><p></p><em> 1 &lt; 2</em>"
+    );
+}
+
+#[test]
+fn sanitizer_keeps_only_known_classes_and_ids() {
+    let html = r#"<h2 id="intro" class="btn hl-x">A</h2><p id="login" class="modal">B</p><li id="fn-1"><a id="x" class="footnote-backref">C</a></li>"#;
+    assert_eq!(
+        sanitize_html(html),
+        r#"<h2 id="intro" class="hl-x">A</h2><p>B</p><li id="fn-1"><a class="footnote-backref">C</a></li>"#
+    );
+}
+
+#[test]
+fn markdown_headings_get_stable_ids() {
+    let html = markdown_to_html("---\ntitle: Test\n---\n# Giới thiệu\n\n## Giới thiệu\n\n~~old~~ <iframe src=\"x\"></iframe>");
+    assert!(html.contains(r#"<h1 id="gioi-thieu">Giới thiệu</h1>"#));
+    assert!(html.contains(r#"<h2 id="gioi-thieu-1">Giới thiệu</h2>"#));
+    assert!(html.contains("<del>old</del>"));
+    assert!(!html.contains("iframe"));
+    assert!(!html.contains("title: Test"));
+    assert_eq!(strip_front_matter("---\na: b\n---\nBody"), "Body");
+}
+
+#[test]
+fn markdown_toc_follows_headings() {
+    let (_html, toc) = markdown_to_html_with_toc("# Intro\n\ntext\n\n## Cài đặt *nhanh*\n");
+    let entry = |level, id: &str, text: &str| TocEntry {
+        level,
+        id: id.into(),
+        text: text.into(),
+    };
+    assert_eq!(toc, vec![entry(1, "intro", "Intro"), entry(2, "cai-dat-nhanh", "Cài đặt nhanh")]);
+}
+
+#[test]
+fn toc_text_is_escaped_in_page() {
+    let (_html, toc) = markdown_to_html_with_toc("# 1 < 2\n\n## Tag `<script>`\n");
+    assert_eq!(toc[1].text, "Tag <script>");
+    let jinja = config_jinja().unwrap();
+    let template = jinja.get_template("blog/block_toc.jinja").unwrap();
+    let html = template.render(minijinja::context!(post => minijinja::context!(toc => toc))).unwrap();
+    assert!(html.contains(">1 &lt; 2</a>"), "{html}");
+    assert!(html.contains(">Tag &lt;script&gt;</a>"), "{html}");
+    assert!(!html.contains("<script>"));
+}
+
+#[test]
+fn math_is_extracted_outside_code_only() {
+    let placeholders = Placeholders::new("MATH");
+    let (markdown, spans) = extract_math("Cost $5 and $10, `$x$`, $a_1$ and\n\n$$\\frac{1}{2}$$", &placeholders);
+    assert_eq!(
+        markdown,
+        format!("Cost $5 and $10, `$x$`, {} and\n\n{}", placeholders.get(0), placeholders.get(1))
+    );
+    assert_eq!(
+        spans,
+        vec![
+            MathSpan {
+                tex: "a_1".into(),
+                display: false
+            },
+            MathSpan {
+                tex: "\\frac{1}{2}".into(),
+                display: true
+            },
+        ]
+    );
+}
+
+#[test]
+fn math_is_not_extracted_from_nested_code() {
+    let placeholders = Placeholders::new("MATH");
+    let markdown = "- Run:\n\n      echo $HOME/$USER\n\n- Then:\n\n  ```\n  $x$\n  ```\n\n<pre>\n$p$\n</pre>\n\n<code>$y$</code> and $z$\n";
+    let (out, spans) = extract_math(markdown, &placeholders);
+    assert_eq!(out, markdown.replace("$z$", &placeholders.get(0)));
+    assert_eq!(spans, vec![MathSpan { tex: "z".into(), display: false }]);
+}
+
+#[test]
+fn math_renders_to_mathml_or_falls_back() {
+    let mathml = tex_to_mathml("x^2", false).unwrap();
+    assert!(mathml.starts_with("<math><semantics><mrow><msup><mi>x</mi><mn>2</mn></msup></mrow>"));
+    let html = markdown_to_html("Known $\\sqrt{x}$, unknown $\\foo$");
+    assert!(html.contains("<msqrt>"));
+    assert!(html.contains(r#"<code class="math-fallback" title="unsupported command \foo">$\foo$</code>"#));
+}
+
+#[test]
+fn placeholders_are_restored_in_text_only() {
+    let html = markdown_to_html(r#"<a title="$x<1$">$y$</a> and %%MATH0%%"#);
+    assert!(html.starts_with(r#"<p><a title="$x&lt;1$"><math>"#), "{html}");
+    assert!(html.ends_with(" and %%MATH0%%</p>\n"), "{html}");
+}
+
+#[test]
+fn diagrams_are_rendered_cached_or_left_as_code() {
+    let cache_dir = tempfile::tempdir().unwrap();
+    // Fails once the marker file exists, to tell whether the result came from cache.
+    let marker = cache_dir.path().join("broken");
+    let dot_script = format!(
+        r#"cat > /dev/null; [ -e {} ] && exit 1; echo '<?xml version="1.0"?><svg viewBox="0 0 1 1" onload="alert(1)"><text>hi</text></svg>'"#,
+        marker.display()
+    );
+    let shell = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+    let settings = DiagramSettings {
+        renderers: [("dot".into(), shell(&dot_script)), ("mermaid".into(), shell("echo oops >&2; exit 1"))].into(),
+        ..Default::default()
+    };
+    let renderer = DiagramRenderer {
+        settings: &settings,
+        cache_dir: Some(cache_dir.path()),
+    };
+    let placeholders = Placeholders::new("DIAGRAM");
+    let extract = |markdown: &str| {
+        let arena = Arena::new();
+        let root = parse_document(&arena, markdown, &ComrakOptions::default());
+        let diagrams = renderer.extract(root, &arena, &placeholders);
+        let mut html = Vec::new();
+        format_html(root, &ComrakOptions::default(), &mut html).unwrap();
+        (String::from_utf8(html).unwrap(), diagrams)
+    };
+    // Also nested in list
+    let markdown = "Text\n\n- Item\n\n  ```dot\n  digraph { a -> b }\n  ```\n\n```mermaid\ngraph TD\n```\n";
+    let (html, diagrams) = extract(markdown);
+    assert_eq!(
+        html,
+        format!(
+            "<p>Text</p>\n<ul>\n<li>\n<p>Item</p>\n<p>{}</p>\n</li>\n</ul>\n<pre><code class=\"language-mermaid\">graph TD\n</code></pre>\n",
+            placeholders.get(0)
+        )
+    );
+    assert_eq!(diagrams, vec![r#"<svg viewBox="0 0 1 1"><text>hi</text></svg>"#.to_string()]);
+    assert_eq!(
+        restore_diagrams(&format!("<p>Text</p>\n<p>{}</p>", placeholders.get(0)), &diagrams, &placeholders),
+        "<p>Text</p>\n<figure class=\"diagram\"><svg viewBox=\"0 0 1 1\"><text>hi</text></svg></figure>"
+    );
+    std::fs::write(&marker, "").unwrap();
+    assert_eq!(extract(markdown).1, diagrams);
+}
+
+#[test]
+fn svg_sanitizer_keeps_only_drawing() {
+    let svg = r#"<svg viewBox="0 0 10 10" xmlns="http://www.w3.org/2000/svg"><style>body{display:none}</style><foreignObject><form action="https://evil.example/"><button formaction="javascript:alert(1)">Go</button></form></foreignObject><a href="javascript:alert(1)"><path d="M0 0" style="fill:red;position:fixed" /></a><use href="https://evil.example/x.svg#a" /></svg>"#;
+    assert_eq!(
+        sanitize_svg(svg),
+        r#"<svg viewBox="0 0 10 10"><a><path d="M0 0" style="fill:red"></path></a><use></use></svg>"#
+    );
+    let svg = sanitize_svg(r#"<svg><base href="https://evil.example/"><meta http-equiv="refresh" content="0"></svg>"#);
+    assert!(!svg.contains("base") && !svg.contains("meta"), "{svg}");
+}
+
+#[test]
+fn code_blocks_get_classes_line_numbers_and_highlighted_lines() {
+    let options = FenceOptions::parse("{1,3-4} linenos");
+    assert!(options.line_numbers);
+    assert_eq!(options.highlighted, vec![1..=1, 3..=4]);
+    let html = markdown_to_html("```rust {2} linenos\nfn main() {\n    let s = \"x\";\n}\n```\n");
+    assert!(html.starts_with("<pre class=\"hl-code\"><code class=\"language-rust hl-numbered\">"), "{html}");
+    assert!(!html.contains("style="));
+    assert!(!html.contains("data-meta"));
+    let lines: Vec<&str> = html.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3], "</code></pre>");
+    assert!(lines[0].contains("<span class=\"hl-line\">"));
+    assert!(lines[1].contains("<span class=\"hl-line hl-highlighted\">"));
+    assert!(lines[1].contains("hl-string"));
+    assert!(theme_css("base16-ocean-dark").is_some_and(|css| css.contains(".hl-code")));
+    assert!(theme_css("no-such-theme").is_none());
+}
@@ -5,9 +5,8 @@ use std::fmt::Display;
 pub fn update_entry_in_query<T: Display>(name: &str, value: T, original_uri: &Uri) -> Uri {
     let mut query = original_uri
         .query()
-        .map(|s| QueryString::decode(s.as_bytes()).ok())
-        .flatten()
-        .unwrap_or(QueryString::new());
+        .and_then(|s| QueryString::decode(s.as_bytes()).ok())
+        .unwrap_or_default();
     query.set(name, format!("{value}"));
     let path = original_uri.path();
     let path_and_query = match String::from_utf8(query.encode()).ok() {
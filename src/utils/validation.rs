@@ -1,9 +1,56 @@
 
+use garde::rules::{email, length};
 use redact::Secret;
 
 pub fn has_some_chars(value: &Secret<String>, _ctx: &()) -> garde::Result {
-    if value.expose_secret().len() >= 1 {
+    if !value.expose_secret().is_empty() {
         return Ok(());
     }
     Err(garde::Error::new("Too short"))
 }
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 200;
+
+pub fn valid_new_password(value: &Secret<String>, _ctx: &()) -> garde::Result {
+    let length = value.expose_secret().chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(garde::Error::new(format!("Must be at least {MIN_PASSWORD_LENGTH} characters")));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(garde::Error::new(format!("Must be at most {MAX_PASSWORD_LENGTH} characters")));
+    }
+    if value.expose_secret().trim().is_empty() {
+        return Err(garde::Error::new("Must not be only spaces"));
+    }
+    Ok(())
+}
+
+pub fn valid_token_lifetime(value: &Option<u16>, _ctx: &()) -> garde::Result {
+    match value {
+        Some(days) if !(1..=3650).contains(days) => Err(garde::Error::new("Must be between 1 and 3650 days")),
+        _ => Ok(()),
+    }
+}
+
+// Limits from the database schema.
+pub const MAX_USERNAME_LENGTH: usize = 150;
+pub const MAX_EMAIL_LENGTH: usize = 200;
+pub const MAX_NAME_LENGTH: usize = 40;
+
+// garde has no rules for Option, so these check the value only when it is given.
+pub fn optional_username(value: &Option<String>, _ctx: &()) -> garde::Result {
+    value.as_ref().map_or(Ok(()), |v| length::apply(v, (1, MAX_USERNAME_LENGTH)))
+}
+
+pub fn optional_email(value: &Option<String>, _ctx: &()) -> garde::Result {
+    let Some(v) = value else {
+        return Ok(());
+    };
+    email::apply(v, ())?;
+    length::apply(v, (1, MAX_EMAIL_LENGTH))
+}
+
+pub fn optional_person_name(value: &Option<String>, _ctx: &()) -> garde::Result {
+    value.as_ref().map_or(Ok(()), |v| length::apply(v, (0, MAX_NAME_LENGTH)))
+}